@@ -1,17 +1,39 @@
-use o_torrent::error::{Error, Result};
-use o_torrent::meta_info;
-use o_torrent::peer;
-use o_torrent::torrent_instance;
-use o_torrent::tracker;
+use o_torrent::error::Result;
+use o_torrent::session::{Session, SessionConfig};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    /*let sample = meta_info::TorrentInfo::from_file("big-buck-bunny.torrent")?;
-    let mut tracker = tracker::Tracker::from_metainfo(&sample).await?;
-    tracker.connect().await?;
-    tracker.annouce_request(-1, 0).await?;
-    */
-    let mut instance = torrent_instance::TorrentInstance::new("test.torrent").await?;
-    instance.update_announce(-1i32, 0u32).await?;
+    // Every argument is a .torrent file or a magnet link.
+    let inputs = std::env::args().skip(1).collect::<Vec<String>>();
+    if inputs.is_empty() {
+        println!("Usage: o_torrent <torrent file or magnet link>...");
+        return Ok(());
+    }
+    let mut session = Session::new(SessionConfig::default()).await?;
+    let mut events = session.subscribe();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            println!("{:?}", event);
+        }
+    });
+    for input in inputs {
+        let added = if input.starts_with("magnet:") {
+            session.add_magnet(&input).await
+        } else {
+            session.add_torrent_file(&input).await
+        };
+        if let Err(err) = added {
+            println!("Cannot add {}: {}", input, err);
+        }
+    }
+    // Ctrl-C stops the torrents cleanly, trackers are told and resume data is saved.
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        println!("Cannot wait for Ctrl-C: {}", err);
+        futures::future::pending::<()>().await;
+    }
+    println!("Shutting down...");
+    if !session.shutdown().await {
+        println!("Some torrents did not stop in time");
+    }
     Ok(())
 }
@@ -0,0 +1,247 @@
+/*
+ * checker.rs
+ * Hash check of the data already on disk, when there is no resume data to trust or when a
+ * recheck is forced. Pieces are read one at a time under the storage lock and hashed on several
+ * threads. A check can be paused, resumed or cancelled, pieces it has not reached yet are then
+ * downloaded again.
+ */
+use crate::downloader::Downloader;
+use crate::error::Result;
+use crate::meta_info::TorrentInfo;
+use crate::signal::Signal;
+
+use sha1::Sha1;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Progress of a hash check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CheckProgress {
+    pub checked: usize,
+    pub total: usize,
+    pub found: usize, //pieces whose hash matched
+    pub bytes_per_sec: u64,
+}
+
+/// Time spent checking, pauses excluded.
+struct ActiveTime {
+    before: Duration,
+    since: Option<Instant>, //None while paused
+}
+
+impl ActiveTime {
+    fn elapsed(&self) -> Duration {
+        self.before + self.since.map(|since| since.elapsed()).unwrap_or_default()
+    }
+}
+
+struct CheckState {
+    next: AtomicUsize, //next piece to hand to a worker
+    checked: AtomicUsize,
+    found: AtomicUsize,
+    bytes: AtomicU64,
+    total: usize,
+    running: AtomicUsize, //workers still running
+    cancelled: AtomicBool,
+    paused: Mutex<bool>,
+    resumed: Condvar,
+    time: Mutex<ActiveTime>,
+}
+
+/// Handle on a running hash check, cheap to clone.
+#[derive(Clone)]
+pub struct HashCheck {
+    state: Arc<CheckState>,
+}
+
+impl HashCheck {
+    /// Check every piece of the torrent on `workers` threads (one per CPU core if 0).
+    /// The downloader stops downloading until the check is over, the torrent then gets
+    /// a Signal::CheckFinished through `signal_slot`.
+    pub fn start(downloader: Arc<Mutex<Downloader>>, workers: usize, signal_slot: UnboundedSender<Signal>) -> Result<Self> {
+        let torrent_info = {
+            let mut guard = downloader.lock().unwrap();
+            guard.begin_check()?;
+            guard.get_torrent_info()
+        };
+        let workers = match workers {
+            0 => thread::available_parallelism().map(|count| count.get()).unwrap_or(1),
+            count => count,
+        };
+        let total = torrent_info.get_number_of_pieces();
+        let workers = workers.min(total).max(1);
+        let state = Arc::new(CheckState {
+            next: AtomicUsize::new(0),
+            checked: AtomicUsize::new(0),
+            found: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            total,
+            running: AtomicUsize::new(workers),
+            cancelled: AtomicBool::new(false),
+            paused: Mutex::new(false),
+            resumed: Condvar::new(),
+            time: Mutex::new(ActiveTime {
+                before: Duration::default(),
+                since: Some(Instant::now()),
+            }),
+        });
+        for _ in 0..workers {
+            let worker = CheckWorker {
+                state: state.clone(),
+                downloader: downloader.clone(),
+                torrent_info: torrent_info.clone(),
+                signal_slot: signal_slot.clone(),
+            };
+            thread::spawn(move || worker.run());
+        }
+        Ok(Self { state })
+    }
+
+    pub fn get_progress(&self) -> CheckProgress {
+        let elapsed = self.state.time.lock().unwrap().elapsed().as_secs_f64();
+        let bytes = self.state.bytes.load(Ordering::Relaxed);
+        CheckProgress {
+            checked: self.state.checked.load(Ordering::Relaxed),
+            total: self.state.total,
+            found: self.state.found.load(Ordering::Relaxed),
+            bytes_per_sec: if elapsed > 0.0 { (bytes as f64 / elapsed) as u64 } else { 0 },
+        }
+    }
+
+    /// Return true once every worker has stopped, because all pieces are checked or the
+    /// check was cancelled.
+    pub fn is_finished(&self) -> bool {
+        self.state.running.load(Ordering::Acquire) == 0
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.state.paused.lock().unwrap()
+    }
+
+    /// Workers stop after the pieces they are hashing.
+    pub fn pause(&self) {
+        let mut paused = self.state.paused.lock().unwrap();
+        if !*paused {
+            *paused = true;
+            let mut time = self.state.time.lock().unwrap();
+            time.before = time.elapsed();
+            time.since = None;
+        }
+    }
+
+    /// Go on from the first piece not checked yet.
+    pub fn resume(&self) {
+        let mut paused = self.state.paused.lock().unwrap();
+        if *paused {
+            *paused = false;
+            self.state.time.lock().unwrap().since = Some(Instant::now());
+            self.state.resumed.notify_all();
+        }
+    }
+
+    /// Stop checking, the pieces not checked yet will be downloaded.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Release);
+        self.resume();
+    }
+}
+
+struct CheckWorker {
+    state: Arc<CheckState>,
+    downloader: Arc<Mutex<Downloader>>,
+    torrent_info: TorrentInfo,
+    signal_slot: UnboundedSender<Signal>,
+}
+
+impl CheckWorker {
+    fn run(self) {
+        let disk = self.downloader.lock().unwrap().get_disk();
+        loop {
+            {
+                let mut paused = self.state.paused.lock().unwrap();
+                while *paused {
+                    paused = self.state.resumed.wait(paused).unwrap();
+                }
+            }
+            if self.state.cancelled.load(Ordering::Acquire) {
+                break;
+            }
+            let piece_idx = self.state.next.fetch_add(1, Ordering::Relaxed);
+            if piece_idx >= self.state.total {
+                break;
+            }
+
+            // Only the read holds the storage, hashing runs in parallel.
+            let length = self.torrent_info.get_piece_length(piece_idx) as usize;
+            let data = disk.storage().read_block(piece_idx, 0, length);
+            // A piece that cannot be read is missing, it will be downloaded again.
+            let matched = match data {
+                Ok(data) => Sha1::from(&data).digest().bytes() == self.torrent_info.get_piece_hash(piece_idx),
+                Err(_) => false,
+            };
+            if matched {
+                self.downloader.lock().unwrap().set_piece_checked(piece_idx);
+                self.state.found.fetch_add(1, Ordering::Relaxed);
+            }
+            self.state.bytes.fetch_add(length as u64, Ordering::Relaxed);
+            self.state.checked.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // The last worker out ends the check.
+        if self.state.running.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.downloader.lock().unwrap().end_check();
+            let mut time = self.state.time.lock().unwrap();
+            time.before = time.elapsed();
+            time.since = None;
+            let _ = self.signal_slot.send(Signal::CheckFinished);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{sample_torrent, TempDir};
+    use bit_vec::BitVec;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn check_finds_pieces_on_disk_and_can_be_paused() {
+        let (torrent_info, data) = sample_torrent(&[("data.bin", 40000)], 16384);
+        let dir = TempDir::new();
+        std::fs::create_dir_all(&dir).unwrap();
+        // The first two pieces are right, the last one is corrupted.
+        let mut on_disk = data.clone();
+        on_disk[39999] ^= 1;
+        std::fs::write(dir.join("data.bin"), &on_disk).unwrap();
+
+        let downloader = Arc::new(Mutex::new(Downloader::with_directory(&torrent_info, &dir).unwrap()));
+        assert!(downloader.lock().unwrap().needs_check());
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let check = HashCheck::start(downloader.clone(), 2, tx.clone()).unwrap();
+        check.pause();
+        assert!(downloader.lock().unwrap().pick_next_block("a", &BitVec::from_elem(3, true)).is_none());
+        check.resume();
+        assert!(matches!(rx.recv().await, Some(Signal::CheckFinished)));
+        assert!(check.is_finished());
+        let progress = check.get_progress();
+        assert_eq!((progress.checked, progress.total, progress.found), (3, 3, 2));
+        {
+            let mut downloader = downloader.lock().unwrap();
+            assert!(!downloader.needs_check() && !downloader.is_checking());
+            assert!(downloader.has_piece(0) && downloader.has_piece(1) && !downloader.has_piece(2));
+            assert_eq!(downloader.pick_next_block("a", &BitVec::from_elem(3, true)), Some((2, 0, 7232)));
+        }
+
+        // A forced recheck that is cancelled right away leaves the pieces it did not reach.
+        let check = HashCheck::start(downloader.clone(), 1, tx).unwrap();
+        check.pause();
+        check.cancel();
+        assert!(matches!(rx.recv().await, Some(Signal::CheckFinished)));
+        assert!(check.is_finished());
+        assert!(!downloader.lock().unwrap().is_checking());
+    }
+}
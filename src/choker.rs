@@ -0,0 +1,176 @@
+/*
+ * choker.rs
+ * Decide which peers we upload to (tit-for-tat). Every round (10 seconds) the peers that gave
+ * us the most data are unchoked, while seeding it is the peers we sent the most data to.
+ * Every third round (30 seconds) one more choked peer is picked randomly: the optimistic
+ * unchoke, so new peers get a chance to show what they can do.
+ */
+use crate::peer::{PeerCommand, PeerHandle};
+
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// Optimistic unchoke rotates every OPTIMISTIC_ROUNDS rounds.
+const OPTIMISTIC_ROUNDS: u32 = 3;
+/// Number of peers unchoked for their rate, the optimistic one is not counted.
+pub const UNCHOKE_SLOTS: usize = 4;
+
+struct ChokerEntry {
+    handle: PeerHandle,
+    last_downloaded: u64,
+    last_uploaded: u64,
+    rate: u64, //bytes per round
+    unchoked: bool,
+}
+
+pub struct Choker {
+    peers: HashMap<String, ChokerEntry>,
+    optimistic: Option<String>,
+    round: u32,
+    slots: usize,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            peers: HashMap::new(),
+            optimistic: None,
+            round: 0,
+            slots,
+        }
+    }
+
+    pub fn add_peer(&mut self, handle: PeerHandle) {
+        self.peers.insert(
+            handle.addr.clone(),
+            ChokerEntry {
+                handle,
+                last_downloaded: 0,
+                last_uploaded: 0,
+                rate: 0,
+                unchoked: false,
+            },
+        );
+    }
+
+    pub fn remove_peer(&mut self, addr: &str) {
+        self.peers.remove(addr);
+        if self.optimistic.as_deref() == Some(addr) {
+            self.optimistic = None;
+        }
+    }
+
+    pub fn get_handle(&self, addr: &str) -> Option<&PeerHandle> {
+        self.peers.get(addr).map(|entry| &entry.handle)
+    }
+
+    pub fn get_handles(&self) -> impl Iterator<Item = &PeerHandle> {
+        self.peers.values().map(|entry| &entry.handle)
+    }
+
+    /// Run one choke round, `seeding` tells whether we rank peers by upload rate.
+    /// Return the addresses of unchoked peers.
+    pub fn run_round(&mut self, seeding: bool) -> Vec<String> {
+        for entry in self.peers.values_mut() {
+            let downloaded = entry.handle.stats.downloaded.load(Ordering::Relaxed);
+            let uploaded = entry.handle.stats.uploaded.load(Ordering::Relaxed);
+            entry.rate = if seeding {
+                uploaded - entry.last_uploaded
+            } else {
+                downloaded - entry.last_downloaded
+            };
+            entry.last_downloaded = downloaded;
+            entry.last_uploaded = uploaded;
+        }
+
+        // Only interested peers compete for the slots, the best rates win.
+        let mut candidates = self
+            .peers
+            .iter()
+            .filter(|(_, entry)| entry.handle.stats.peer_interested.load(Ordering::Relaxed))
+            .map(|(addr, entry)| (addr.clone(), entry.rate))
+            .collect::<Vec<(String, u64)>>();
+        candidates.sort_by_key(|c| std::cmp::Reverse(c.1));
+        let mut unchoked = candidates
+            .iter()
+            .take(self.slots)
+            .map(|(addr, _)| addr.clone())
+            .collect::<Vec<String>>();
+
+        let optimistic_gone = match &self.optimistic {
+            Some(addr) => !self.peers.contains_key(addr),
+            None => true,
+        };
+        if self.round.is_multiple_of(OPTIMISTIC_ROUNDS) || optimistic_gone {
+            let others = candidates
+                .iter()
+                .map(|(addr, _)| addr)
+                .filter(|addr| !unchoked.contains(addr))
+                .cloned()
+                .collect::<Vec<String>>();
+            self.optimistic = others.choose(&mut rand::thread_rng()).cloned();
+        }
+        if let Some(addr) = &self.optimistic {
+            if !unchoked.contains(addr) {
+                unchoked.push(addr.clone());
+            }
+        }
+        self.round += 1;
+
+        for (addr, entry) in self.peers.iter_mut() {
+            let unchoke = unchoked.contains(addr);
+            if unchoke != entry.unchoked {
+                entry.unchoked = unchoke;
+                let command = if unchoke { PeerCommand::UnChoke } else { PeerCommand::Choke };
+                let _ = entry.handle.command_slot.send(command);
+            }
+        }
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerStats;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    fn add_peer(choker: &mut Choker, addr: &str, downloaded: u64, interested: bool) -> mpsc::UnboundedReceiver<PeerCommand> {
+        let (command_slot, command_rx) = mpsc::unbounded_channel();
+        let stats = Arc::new(PeerStats::default());
+        stats.downloaded.store(downloaded, Ordering::Relaxed);
+        stats.peer_interested.store(interested, Ordering::Relaxed);
+        choker.add_peer(PeerHandle {
+            addr: addr.to_string(),
+            command_slot,
+            stats,
+        });
+        command_rx
+    }
+
+    #[test]
+    fn fastest_peers_and_one_optimistic_are_unchoked() {
+        let mut choker = Choker::new(2);
+        let mut fast = add_peer(&mut choker, "fast", 300, true);
+        let _medium = add_peer(&mut choker, "medium", 200, true);
+        let _slow = add_peer(&mut choker, "slow", 100, true);
+        let _slower = add_peer(&mut choker, "slower", 50, true);
+        let mut not_interested = add_peer(&mut choker, "not_interested", 1000, false);
+
+        let unchoked = choker.run_round(false);
+        assert_eq!(unchoked.len(), 3);
+        assert_eq!(&unchoked[..2], &["fast".to_string(), "medium".to_string()]);
+        assert!(unchoked[2] == "slow" || unchoked[2] == "slower");
+        assert!(matches!(fast.try_recv(), Ok(PeerCommand::UnChoke)));
+        assert!(not_interested.try_recv().is_err());
+
+        // Optimistic unchoke stays until the third round.
+        let optimistic = unchoked[2].clone();
+        assert!(choker.run_round(false).contains(&optimistic));
+        assert!(choker.run_round(false).contains(&optimistic));
+    }
+}
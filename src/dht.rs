@@ -0,0 +1,756 @@
+/*
+ * dht.rs
+ * Mainline DHT (BEP 5): find peers of a torrent without any tracker.
+ * Nodes are kept in a Kademlia routing table (160 buckets of 8 nodes, by XOR distance to our
+ * id) and talk KRPC, bencoded dictionaries over UDP: ping, find_node, get_peers and
+ * announce_peer. A peer can only be announced with a token we gave to its IP address in a
+ * get_peers response, tokens are valid for 10 minutes.
+ */
+use crate::error::{Error, Result};
+use crate::utils::{compact_v4, parse_compact_v4};
+
+use futures::future::join_all;
+use rand::Rng;
+use serde_bytes::ByteBuf;
+use sha1::Sha1;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::udp::SendHalf;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::time::{interval, timeout};
+
+pub type NodeId = [u8; 20];
+
+/// Bucket size.
+pub const K: usize = 8;
+/// Number of queries in flight during a lookup.
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// A node that has not been heard from for this long can be replaced.
+const NODE_STALE: Duration = Duration::from_secs(15 * 60);
+/// Nodes that failed to answer this many queries in a row are dropped.
+const MAX_FAILURES: u32 = 2;
+/// Token secret changes this often, tokens of the previous secret are still accepted.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Announced peers are forgotten after this long.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Expired peers are looked for this often.
+const PEER_EXPIRE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Torrents we keep announced peers for, announces of other torrents are ignored.
+const MAX_TORRENTS: usize = 2000;
+/// Peers kept for each torrent, the oldest one goes when a new one comes.
+const MAX_PEERS: usize = 100;
+/// Last bit of the reserved bytes tells the remote peer we run a DHT node.
+pub const DHT_BYTE: usize = 7;
+pub const DHT_FLAG: u8 = 0x01;
+/// Well known nodes used when the torrent does not list any.
+pub const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/*-----------------------------------Routing table----------------------------------------------*/
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut dist = [0u8; 20];
+    for (idx, byte) in dist.iter_mut().enumerate() {
+        *byte = a[idx] ^ b[idx];
+    }
+    dist
+}
+
+/// Bucket of `id`: number of leading bits shared with our id, None for our own id.
+fn bucket_index(own_id: &NodeId, id: &NodeId) -> Option<usize> {
+    let dist = distance(own_id, id);
+    dist.iter()
+        .position(|&byte| byte != 0)
+        .map(|idx| idx * 8 + dist[idx].leading_zeros() as usize)
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeEntry {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    last_seen: Instant,
+    failed: u32, //queries without answer since the last one it answered.
+}
+
+impl NodeEntry {
+    fn is_questionable(&self) -> bool {
+        self.failed > 0 || self.last_seen.elapsed() > NODE_STALE
+    }
+}
+
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<NodeEntry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    /// Add a node we heard from, return false if its bucket is full of good nodes.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr) -> bool {
+        let bucket = match bucket_index(&self.own_id, &id) {
+            Some(idx) => &mut self.buckets[idx],
+            None => return false,
+        };
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.id == id) {
+            entry.addr = addr;
+            entry.last_seen = Instant::now();
+            entry.failed = 0;
+            return true;
+        }
+        if bucket.len() >= K {
+            match bucket.iter().position(|entry| entry.is_questionable()) {
+                Some(idx) => {
+                    bucket.remove(idx);
+                }
+                None => return false,
+            }
+        }
+        bucket.push(NodeEntry {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failed: 0,
+        });
+        true
+    }
+
+    /// A query to `addr` timed out.
+    pub fn mark_failed(&mut self, addr: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            for entry in bucket.iter_mut().filter(|entry| entry.addr == *addr) {
+                entry.failed += 1;
+            }
+            bucket.retain(|entry| entry.failed < MAX_FAILURES);
+        }
+    }
+
+    /// Return up to `count` nodes, closest to `target` first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeEntry> {
+        let mut nodes = self.buckets.iter().flatten().cloned().collect::<Vec<NodeEntry>>();
+        nodes.sort_by_key(|entry| distance(&entry.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Compact node info: 20 bytes id followed by a compact IPv4 address.
+fn encode_nodes(nodes: &[NodeEntry]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(nodes.len() * 26);
+    for node in nodes {
+        if let Some(compact) = compact_v4(&node.addr) {
+            data.extend_from_slice(&node.id);
+            data.extend_from_slice(&compact);
+        }
+    }
+    data
+}
+
+fn parse_nodes(data: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    data.chunks_exact(26)
+        .filter_map(|chunk| {
+            let mut id = [0u8; 20];
+            id.copy_from_slice(&chunk[..20]);
+            parse_compact_v4(&chunk[20..]).pop().map(|addr| (id, addr))
+        })
+        .collect()
+}
+
+/*-----------------------------------KRPC messages----------------------------------------------*/
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueryArgs {
+    id: ByteBuf,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<i64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    implied_port: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ResponseArgs {
+    id: ByteBuf,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KrpcMessage {
+    t: ByteBuf,
+    y: String, //"q" query, "r" response, "e" error
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    a: Option<QueryArgs>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r: Option<ResponseArgs>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+}
+
+fn to_node_id(data: &[u8]) -> Option<NodeId> {
+    if data.len() != 20 {
+        return None;
+    }
+    let mut id = [0u8; 20];
+    id.copy_from_slice(data);
+    Some(id)
+}
+
+/*-----------------------------------DHT node----------------------------------------------*/
+
+struct DhtState {
+    table: RoutingTable,
+    pending: HashMap<Vec<u8>, oneshot::Sender<Result<ResponseArgs>>>, //by transaction id
+    peers: HashMap<NodeId, Vec<(SocketAddr, Instant)>>, //announced to us, by info hash
+    secret: [u8; 20],
+    prev_secret: [u8; 20],
+    secret_time: Instant,
+    next_transaction: u16,
+}
+
+impl DhtState {
+    fn new(id: NodeId) -> Self {
+        Self {
+            table: RoutingTable::new(id),
+            pending: HashMap::new(),
+            peers: HashMap::new(),
+            secret: rand::thread_rng().gen(),
+            prev_secret: rand::thread_rng().gen(),
+            secret_time: Instant::now(),
+            next_transaction: 0,
+        }
+    }
+
+    fn make_token(secret: &[u8; 20], ip: &IpAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        match ip {
+            IpAddr::V4(ip) => hasher.update(&ip.octets()),
+            IpAddr::V6(ip) => hasher.update(&ip.octets()),
+        }
+        hasher.digest().bytes().to_vec()
+    }
+
+    fn rotate_secret(&mut self) {
+        if self.secret_time.elapsed() > TOKEN_ROTATION {
+            self.prev_secret = self.secret;
+            self.secret = rand::thread_rng().gen();
+            self.secret_time = Instant::now();
+        }
+    }
+
+    fn is_valid_token(&self, token: &[u8], ip: &IpAddr) -> bool {
+        token == &Self::make_token(&self.secret, ip)[..] || token == &Self::make_token(&self.prev_secret, ip)[..]
+    }
+
+    /// Remember a peer announced to us, within MAX_TORRENTS and MAX_PEERS.
+    fn add_peer(&mut self, info_hash: NodeId, addr: SocketAddr) {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_TORRENTS {
+            self.expire_peers();
+            if self.peers.len() >= MAX_TORRENTS {
+                return;
+            }
+        }
+        let peers = self.peers.entry(info_hash).or_default();
+        peers.retain(|(peer, announced)| *peer != addr && announced.elapsed() < PEER_TTL);
+        if peers.len() >= MAX_PEERS {
+            peers.remove(0);
+        }
+        peers.push((addr, Instant::now()));
+    }
+
+    /// Forget peers announced more than PEER_TTL ago, and torrents left without any.
+    fn expire_peers(&mut self) {
+        for peers in self.peers.values_mut() {
+            peers.retain(|(_, announced)| announced.elapsed() < PEER_TTL);
+        }
+        self.peers.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+/// Result of an iterative lookup.
+struct Lookup {
+    peers: Vec<SocketAddr>,
+    /// Closest nodes that answered, with the token they gave us.
+    nodes: Vec<(NodeId, SocketAddr, Option<ByteBuf>)>,
+}
+
+/// A DHT node, cheap to clone: clones share the socket and the routing table.
+#[derive(Clone)]
+pub struct Dht {
+    id: NodeId,
+    local_addr: SocketAddr,
+    sender: Arc<tokio::sync::Mutex<SendHalf>>,
+    state: Arc<Mutex<DhtState>>,
+}
+
+impl Dht {
+    /// Bind the UDP socket and start answering queries.
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        let local_addr = socket.local_addr()?;
+        let (mut receiver, sender) = socket.split();
+        let id: NodeId = rand::thread_rng().gen();
+        let dht = Self {
+            id,
+            local_addr,
+            sender: Arc::new(tokio::sync::Mutex::new(sender)),
+            state: Arc::new(Mutex::new(DhtState::new(id))),
+        };
+
+        let server = dht.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            loop {
+                // Bad packets and socket errors (e.g. ICMP unreachable) are ignored.
+                if let Ok((len, from)) = receiver.recv_from(&mut buf).await {
+                    let _ = server.handle_packet(&buf[..len], from).await;
+                }
+            }
+        });
+        let expiring = dht.state.clone();
+        tokio::spawn(async move {
+            let mut timer = interval(PEER_EXPIRE_INTERVAL);
+            loop {
+                timer.tick().await;
+                expiring.lock().unwrap().expire_peers();
+            }
+        });
+        Ok(dht)
+    }
+
+    pub fn get_id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Number of nodes in the routing table.
+    pub fn get_node_count(&self) -> usize {
+        self.state.lock().unwrap().table.len()
+    }
+
+    /// Fill the routing table from a few known nodes, then look for nodes close to our id.
+    pub async fn bootstrap(&self, addrs: &[SocketAddr]) -> usize {
+        let queries = addrs.iter().map(|addr| self.find_node(*addr, self.id));
+        for (id, addr) in join_all(queries).await.into_iter().flatten().flatten() {
+            self.state.lock().unwrap().table.insert(id, addr);
+        }
+        self.lookup(self.id, false).await;
+        self.get_node_count()
+    }
+
+    /// Ping a node we learned about from a Port message, it is added if it answers.
+    pub fn add_node(&self, addr: SocketAddr) {
+        let dht = self.clone();
+        tokio::spawn(async move {
+            let _ = dht.ping(addr).await;
+        });
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId> {
+        let args = QueryArgs {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        let response = self.query(addr, "ping", args).await?;
+        to_node_id(&response.id).ok_or_else(|| Error::DhtError("Invalid node id".to_string()))
+    }
+
+    /// Find peers of a torrent.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.lookup(info_hash, true).await.peers
+    }
+
+    /// Find peers of a torrent and tell the closest nodes we download it too.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddr> {
+        let lookup = self.lookup(info_hash, true).await;
+        let announces = lookup.nodes.into_iter().filter_map(|(_, addr, token)| {
+            let args = QueryArgs {
+                id: ByteBuf::from(self.id.to_vec()),
+                info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                port: Some(port as i64),
+                token: Some(token?),
+                implied_port: Some(0),
+                ..Default::default()
+            };
+            Some(self.query(addr, "announce_peer", args))
+        });
+        join_all(announces).await;
+        lookup.peers
+    }
+
+    async fn find_node(&self, addr: SocketAddr, target: NodeId) -> Result<Vec<(NodeId, SocketAddr)>> {
+        let args = QueryArgs {
+            id: ByteBuf::from(self.id.to_vec()),
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..Default::default()
+        };
+        let response = self.query(addr, "find_node", args).await?;
+        Ok(response.nodes.map(|nodes| parse_nodes(&nodes)).unwrap_or_default())
+    }
+
+    /// Iterative lookup: query the ALPHA closest nodes we have not asked yet, until the K
+    /// closest nodes we know have all been asked.
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        let mut candidates = self
+            .state
+            .lock()
+            .unwrap()
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|entry| (entry.id, entry.addr))
+            .collect::<Vec<(NodeId, SocketAddr)>>();
+        let mut seen = candidates.iter().map(|(_, addr)| *addr).collect::<HashSet<SocketAddr>>();
+        let mut queried = HashSet::new();
+        let mut peers = HashSet::new();
+        let mut nodes = Vec::new();
+
+        loop {
+            candidates.sort_by_key(|(id, _)| distance(id, &target));
+            let batch = candidates
+                .iter()
+                .take(K)
+                .filter(|(_, addr)| !queried.contains(addr))
+                .take(ALPHA)
+                .cloned()
+                .collect::<Vec<(NodeId, SocketAddr)>>();
+            if batch.is_empty() {
+                break;
+            }
+
+            let queries = batch.iter().map(|(_, addr)| {
+                queried.insert(*addr);
+                let mut args = QueryArgs {
+                    id: ByteBuf::from(self.id.to_vec()),
+                    ..Default::default()
+                };
+                let method = if get_peers {
+                    args.info_hash = Some(ByteBuf::from(target.to_vec()));
+                    "get_peers"
+                } else {
+                    args.target = Some(ByteBuf::from(target.to_vec()));
+                    "find_node"
+                };
+                self.query(*addr, method, args)
+            });
+            let responses = join_all(queries.collect::<Vec<_>>()).await;
+
+            for ((_, addr), response) in batch.into_iter().zip(responses) {
+                let response = match response {
+                    Ok(response) => response,
+                    Err(_) => continue,
+                };
+                for (id, node_addr) in response.nodes.as_ref().map(|data| parse_nodes(data)).unwrap_or_default() {
+                    if id != self.id && seen.insert(node_addr) {
+                        candidates.push((id, node_addr));
+                    }
+                }
+                for value in response.values.iter().flatten() {
+                    peers.extend(parse_compact_v4(value));
+                }
+                if let Some(id) = to_node_id(&response.id) {
+                    nodes.push((id, addr, response.token));
+                }
+            }
+        }
+
+        nodes.sort_by_key(|(id, _, _)| distance(id, &target));
+        nodes.truncate(K);
+        Lookup {
+            peers: peers.into_iter().collect(),
+            nodes,
+        }
+    }
+
+    /// Send a query and wait for its response. Nodes that answer go into the routing table.
+    async fn query(&self, addr: SocketAddr, method: &str, args: QueryArgs) -> Result<ResponseArgs> {
+        let (tx, rx) = oneshot::channel();
+        let transaction_id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_transaction = state.next_transaction.wrapping_add(1);
+            let transaction_id = state.next_transaction.to_be_bytes().to_vec();
+            state.pending.insert(transaction_id.clone(), tx);
+            transaction_id
+        };
+        let msg = KrpcMessage {
+            t: ByteBuf::from(transaction_id.clone()),
+            y: "q".to_string(),
+            q: Some(method.to_string()),
+            a: Some(args),
+            ..Default::default()
+        };
+        self.send(&msg, addr).await?;
+
+        match timeout(QUERY_TIMEOUT, rx).await {
+            Ok(Ok(Ok(response))) => {
+                if let Some(id) = to_node_id(&response.id) {
+                    self.state.lock().unwrap().table.insert(id, addr);
+                }
+                Ok(response)
+            }
+            Ok(Ok(Err(err))) => Err(err),
+            _ => {
+                let mut state = self.state.lock().unwrap();
+                state.pending.remove(&transaction_id);
+                state.table.mark_failed(&addr);
+                Err(Error::DhtError(format!("{} did not answer", addr)))
+            }
+        }
+    }
+
+    async fn send(&self, msg: &KrpcMessage, addr: SocketAddr) -> Result<()> {
+        let data = serde_bencode::to_bytes(msg)?;
+        self.sender.lock().await.send_to(&data, &addr).await?;
+        Ok(())
+    }
+
+    async fn handle_packet(&self, data: &[u8], from: SocketAddr) -> Result<()> {
+        let msg = serde_bencode::from_bytes::<KrpcMessage>(data)?;
+        match msg.y.as_str() {
+            "q" => {
+                let reply = self.handle_query(&msg, from);
+                self.send(&reply, from).await?;
+            }
+            "r" | "e" => {
+                let pending = self.state.lock().unwrap().pending.remove(msg.t.as_slice());
+                if let Some(tx) = pending {
+                    let result = match (msg.r, msg.e) {
+                        (Some(response), _) => Ok(response),
+                        (None, Some((code, message))) => Err(Error::DhtError(format!("{} {}", code, message))),
+                        (None, None) => Err(Error::DhtError("Empty response".to_string())),
+                    };
+                    let _ = tx.send(result);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_query(&self, msg: &KrpcMessage, from: SocketAddr) -> KrpcMessage {
+        let mut reply = KrpcMessage {
+            t: msg.t.clone(),
+            y: "r".to_string(),
+            ..Default::default()
+        };
+        let args = match &msg.a {
+            Some(args) => args,
+            None => {
+                reply.y = "e".to_string();
+                reply.e = Some((ERROR_PROTOCOL, "Missing arguments".to_string()));
+                return reply;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(id) = to_node_id(&args.id) {
+            state.table.insert(id, from);
+        }
+        let mut response = ResponseArgs {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        let target = args.target.as_ref().or(args.info_hash.as_ref()).and_then(|target| to_node_id(target));
+        match (msg.q.as_deref(), target) {
+            (Some("ping"), _) => {}
+            (Some("find_node"), Some(target)) => {
+                response.nodes = Some(ByteBuf::from(encode_nodes(&state.table.closest(&target, K))));
+            }
+            (Some("get_peers"), Some(info_hash)) => {
+                state.rotate_secret();
+                response.token = Some(ByteBuf::from(DhtState::make_token(&state.secret, &from.ip())));
+                let values = state
+                    .peers
+                    .get(&info_hash)
+                    .map(|peers| {
+                        peers
+                            .iter()
+                            .filter(|(_, announced)| announced.elapsed() < PEER_TTL)
+                            .filter_map(|(addr, _)| compact_v4(addr))
+                            .map(|compact| ByteBuf::from(compact.to_vec()))
+                            .collect::<Vec<ByteBuf>>()
+                    })
+                    .unwrap_or_default();
+                if values.is_empty() {
+                    response.nodes = Some(ByteBuf::from(encode_nodes(&state.table.closest(&info_hash, K))));
+                } else {
+                    response.values = Some(values);
+                }
+            }
+            (Some("announce_peer"), Some(info_hash)) => {
+                let token_ok = args.token.as_ref().map(|token| state.is_valid_token(token, &from.ip())).unwrap_or(false);
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => Some(from.port()),
+                    (_, Some(port)) if port > 0 && port <= u16::MAX as i64 => Some(port as u16),
+                    _ => None,
+                };
+                match port {
+                    Some(port) if token_ok => {
+                        state.add_peer(info_hash, SocketAddr::new(from.ip(), port));
+                    }
+                    _ => {
+                        reply.y = "e".to_string();
+                        reply.e = Some((ERROR_PROTOCOL, "Bad token or port".to_string()));
+                        return reply;
+                    }
+                }
+            }
+            _ => {
+                reply.y = "e".to_string();
+                reply.e = Some((ERROR_METHOD_UNKNOWN, "Method Unknown".to_string()));
+                return reply;
+            }
+        }
+        reply.r = Some(response);
+        reply
+    }
+}
+
+/// Resolve "host:port" strings, names that cannot be resolved are skipped.
+pub async fn resolve_nodes(hosts: &[String]) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+    for host in hosts {
+        if let Ok(resolved) = tokio::net::lookup_host(host.as_str()).await {
+            addrs.extend(resolved.filter(|addr| addr.is_ipv4()));
+        }
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_id(first: u8) -> NodeId {
+        let mut id = [0u8; 20];
+        id[0] = first;
+        id
+    }
+
+    #[test]
+    fn full_bucket_keeps_good_nodes() {
+        let mut table = RoutingTable::new([0; 20]);
+        // Ids with the first bit set all go into bucket 0.
+        for idx in 0..K as u8 {
+            assert!(table.insert(node_id(0x80 | idx), ([127, 0, 0, 1], 1000 + idx as u16).into()));
+        }
+        assert!(!table.insert(node_id(0xff), ([127, 0, 0, 1], 2000).into()));
+        assert!(table.insert(node_id(0x01), ([127, 0, 0, 1], 2001).into()));
+
+        // A node that does not answer makes room for a new one.
+        table.mark_failed(&([127, 0, 0, 1], 1000).into());
+        assert!(table.insert(node_id(0xff), ([127, 0, 0, 1], 2000).into()));
+        assert_eq!(table.len(), K + 1);
+        assert_eq!(table.closest(&node_id(0x00), 1)[0].id, node_id(0x01));
+    }
+
+    #[test]
+    fn announced_peers_are_capped() {
+        let mut state = DhtState::new([0; 20]);
+        for port in 0..MAX_PEERS as u16 + 10 {
+            state.add_peer(node_id(1), ([127, 0, 0, 1], port).into());
+        }
+        let peers = &state.peers[&node_id(1)];
+        assert_eq!(peers.len(), MAX_PEERS);
+        assert_eq!(peers[0].0, ([127, 0, 0, 1], 10).into());
+
+        for idx in 0..MAX_TORRENTS + 10 {
+            let mut info_hash = [0xff; 20];
+            info_hash[..8].copy_from_slice(&(idx as u64).to_be_bytes());
+            state.add_peer(info_hash, ([127, 0, 0, 1], 6881).into());
+        }
+        assert_eq!(state.peers.len(), MAX_TORRENTS);
+
+        // Expired peers make room for new torrents.
+        let expired = Instant::now() - PEER_TTL - Duration::from_secs(1);
+        state.peers.get_mut(&node_id(1)).unwrap().iter_mut().for_each(|peer| peer.1 = expired);
+        state.add_peer(node_id(2), ([127, 0, 0, 1], 6881).into());
+        assert!(!state.peers.contains_key(&node_id(1)));
+        assert!(state.peers.contains_key(&node_id(2)));
+    }
+
+    #[tokio::test]
+    async fn announce_and_get_peers_on_loopback() {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let router = Dht::bind(localhost).await.unwrap();
+        let mut nodes = Vec::new();
+        for _ in 0..4 {
+            let node = Dht::bind(localhost).await.unwrap();
+            assert!(node.bootstrap(&[router.local_addr()]).await > 0);
+            nodes.push(node);
+        }
+
+        let info_hash = [7u8; 20];
+        assert!(nodes[0].announce(info_hash, 6881).await.is_empty());
+        let peers = nodes[3].get_peers(info_hash).await;
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn announce_needs_a_token() {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = Dht::bind(localhost).await.unwrap();
+        let client = Dht::bind(localhost).await.unwrap();
+        assert_eq!(client.ping(server.local_addr()).await.unwrap(), server.get_id());
+
+        let args = QueryArgs {
+            id: ByteBuf::from(client.get_id().to_vec()),
+            info_hash: Some(ByteBuf::from(vec![7u8; 20])),
+            port: Some(6881),
+            token: Some(ByteBuf::from(b"forged".to_vec())),
+            ..Default::default()
+        };
+        assert!(client.query(server.local_addr(), "announce_peer", args).await.is_err());
+        assert!(client.get_peers([7u8; 20]).await.is_empty());
+    }
+}
@@ -0,0 +1,295 @@
+/*
+ * disk_io.rs
+ * Disk I/O on a pool of worker threads, so peers never block the async runtime on a disk.
+ * Blocks received from peers wait in a bounded write cache until their piece is complete, a
+ * worker then hashes the piece from memory, writes it out with as few writes as possible and
+ * tells the torrent whether the hash matched. A full cache makes peers wait before adding more.
+ */
+use crate::error::{Error, Result};
+use crate::signal::Signal;
+use crate::storage::Storage;
+
+use sha1::Sha1;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{oneshot, Semaphore};
+
+/// Number of worker threads of a torrent.
+pub const DISK_WORKERS: usize = 4;
+/// Blocks the write cache holds before peers have to wait, 8MiB of 16KiB blocks.
+pub const WRITE_CACHE_BLOCKS: usize = 512;
+
+enum DiskJob {
+    /// Check and write a piece whose blocks are all in the cache (or on disk already).
+    FinishPiece {
+        piece_idx: usize,
+        hash: [u8; 20],
+        signal_slot: UnboundedSender<Signal>,
+    },
+    /// Write the blocks of unfinished pieces to free the cache, failures go to `signal_slot`.
+    FlushPartial { signal_slot: UnboundedSender<Signal> },
+    Read {
+        piece_idx: usize,
+        begin: u32,
+        length: usize,
+        reply: oneshot::Sender<Result<Vec<u8>>>,
+    },
+}
+
+/// Blocks not written yet: piece index -> block offset -> data.
+type WriteCache = HashMap<usize, BTreeMap<u32, Vec<u8>>>;
+
+/// Handle on the disk workers of a torrent, cheap to clone.
+#[derive(Clone)]
+pub struct DiskIo {
+    job_slot: Sender<DiskJob>,
+    storage: Arc<Mutex<Box<dyn Storage>>>,
+    cache: Arc<Mutex<WriteCache>>,
+    slots: Arc<Semaphore>, //one permit for every block the cache can still take
+}
+
+impl DiskIo {
+    /// Start `workers` threads doing the I/O of `storage`. They stop when every handle is dropped.
+    pub fn new(storage: Box<dyn Storage>, workers: usize, cache_blocks: usize) -> Self {
+        let (job_slot, job_rx) = mpsc::channel();
+        let disk = Self {
+            job_slot,
+            storage: Arc::new(Mutex::new(storage)),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            slots: Arc::new(Semaphore::new(cache_blocks)),
+        };
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        for _ in 0..workers.max(1) {
+            let worker = Worker {
+                storage: disk.storage.clone(),
+                cache: disk.cache.clone(),
+                slots: disk.slots.clone(),
+            };
+            let job_rx = job_rx.clone();
+            thread::spawn(move || worker.run(job_rx));
+        }
+        disk
+    }
+
+    /// Direct access to the storage, for work that is not worth a trip to the workers.
+    pub fn storage(&self) -> MutexGuard<'_, Box<dyn Storage>> {
+        self.storage.lock().unwrap()
+    }
+
+    /// Put a block in the write cache, wait for room first when the cache is full. A failed
+    /// write of the cache is a Signal::StorageError through `signal_slot`.
+    pub async fn write_block(&self, piece_idx: usize, begin: u32, data: Vec<u8>, signal_slot: &UnboundedSender<Signal>) {
+        match self.slots.try_acquire() {
+            Ok(permit) => permit.forget(),
+            Err(_) => {
+                // Unfinished pieces hold the whole cache, write them out to make room.
+                let _ = self.job_slot.send(DiskJob::FlushPartial { signal_slot: signal_slot.clone() });
+                self.slots.acquire().await.forget();
+            }
+        }
+        let replaced = self.cache.lock().unwrap().entry(piece_idx).or_default().insert(begin, data);
+        if replaced.is_some() {
+            // Same block twice, e.g. in endgame: it only takes one slot.
+            self.slots.add_permits(1);
+        }
+    }
+
+    /// Every block of the piece has been given to write_block(), check it against `hash` and
+    /// write it. The torrent gets a Signal::PieceHashed through `signal_slot`.
+    pub fn finish_piece(&self, piece_idx: usize, hash: [u8; 20], signal_slot: UnboundedSender<Signal>) {
+        let _ = self.job_slot.send(DiskJob::FinishPiece {
+            piece_idx,
+            hash,
+            signal_slot,
+        });
+    }
+
+    /// Read a block on a worker.
+    pub async fn read_block(&self, piece_idx: usize, begin: u32, length: usize) -> Result<Vec<u8>> {
+        let (reply, reply_rx) = oneshot::channel();
+        self.job_slot
+            .send(DiskJob::Read {
+                piece_idx,
+                begin,
+                length,
+                reply,
+            })
+            .map_err(|_| Error::DiskError("disk workers are stopped".to_string()))?;
+        reply_rx.await.map_err(|_| Error::DiskError("disk worker went away".to_string()))?
+    }
+
+    /// Write every cached block and flush the storage, in the calling thread.
+    pub fn flush(&self) -> Result<()> {
+        // Storage first: a worker finishing one of these pieces must wait for its blocks.
+        let mut storage = self.storage();
+        let pieces = std::mem::take(&mut *self.cache.lock().unwrap());
+        let mut count = 0;
+        let mut result = Ok(());
+        for (piece_idx, blocks) in pieces {
+            count += blocks.len();
+            if result.is_ok() {
+                result = write_blocks(&mut **storage, piece_idx, blocks);
+            }
+        }
+        self.slots.add_permits(count);
+        result?;
+        storage.flush()
+    }
+
+    /// Create or leave alone the files of the torrent, one flag for each file, in the calling
+    /// thread.
+    pub fn set_wanted(&self, wanted: &[bool]) -> Result<()> {
+        let mut storage = self.storage();
+        for (file_idx, &wanted) in wanted.iter().enumerate() {
+            storage.set_wanted(file_idx, wanted)?;
+        }
+        Ok(())
+    }
+
+    /// Forget every cached block, e.g. when the files are deleted.
+    pub fn clear_cache(&self) {
+        let count = self.cache.lock().unwrap().drain().map(|(_, blocks)| blocks.len()).sum();
+        self.slots.add_permits(count);
+    }
+}
+
+struct Worker {
+    storage: Arc<Mutex<Box<dyn Storage>>>,
+    cache: Arc<Mutex<WriteCache>>,
+    slots: Arc<Semaphore>,
+}
+
+impl Worker {
+    fn run(self, job_rx: Arc<Mutex<Receiver<DiskJob>>>) {
+        loop {
+            let job = match job_rx.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            match job {
+                DiskJob::FinishPiece {
+                    piece_idx,
+                    hash,
+                    signal_slot,
+                } => {
+                    let matched = match self.finish_piece(piece_idx, hash) {
+                        Ok(matched) => matched,
+                        Err(err) => {
+                            let _ = signal_slot.send(Signal::StorageError(format!("Cannot write piece {}: {}", piece_idx, err)));
+                            false
+                        }
+                    };
+                    let _ = signal_slot.send(Signal::PieceHashed(piece_idx, matched));
+                }
+                DiskJob::FlushPartial { signal_slot } => {
+                    let mut storage = self.storage.lock().unwrap();
+                    let pieces = std::mem::take(&mut *self.cache.lock().unwrap());
+                    for (piece_idx, blocks) in pieces {
+                        let count = blocks.len();
+                        if let Err(err) = write_blocks(&mut **storage, piece_idx, blocks) {
+                            let _ = signal_slot.send(Signal::StorageError(format!("Cannot write piece {}: {}", piece_idx, err)));
+                        }
+                        self.slots.add_permits(count);
+                    }
+                }
+                DiskJob::Read {
+                    piece_idx,
+                    begin,
+                    length,
+                    reply,
+                } => {
+                    let _ = reply.send(self.storage.lock().unwrap().read_block(piece_idx, begin, length));
+                }
+            }
+        }
+    }
+
+    fn finish_piece(&self, piece_idx: usize, hash: [u8; 20]) -> Result<bool> {
+        let blocks = self.cache.lock().unwrap().remove(&piece_idx).unwrap_or_default();
+        let count = blocks.len();
+        let piece_length = self.storage.lock().unwrap().get_layout().get_piece_length(piece_idx) as usize;
+
+        // Hash from memory when the whole piece is cached, no need to read it back.
+        let mut data = Vec::with_capacity(piece_length);
+        for (begin, block) in blocks.iter() {
+            if *begin as usize != data.len() {
+                break;
+            }
+            data.extend_from_slice(block);
+        }
+        let cached = data.len() == piece_length;
+
+        // Only the write and the read hold the storage, hashing runs in parallel.
+        let written = {
+            let mut storage = self.storage.lock().unwrap();
+            match write_blocks(&mut **storage, piece_idx, blocks) {
+                Ok(()) if !cached => storage.read_block(piece_idx, 0, piece_length).map(|read| data = read),
+                written => written,
+            }
+        };
+        self.slots.add_permits(count);
+        written?;
+        Ok(Sha1::from(&data).digest().bytes() == hash)
+    }
+}
+
+/// Write the blocks of a piece, blocks that follow each other go in a single write.
+fn write_blocks(storage: &mut dyn Storage, piece_idx: usize, blocks: BTreeMap<u32, Vec<u8>>) -> Result<()> {
+    let mut run_start = 0;
+    let mut run: Vec<u8> = Vec::new();
+    for (begin, block) in blocks {
+        if !run.is_empty() && run_start + run.len() as u32 != begin {
+            storage.write_block(piece_idx, run_start, &run)?;
+            run.clear();
+        }
+        if run.is_empty() {
+            run_start = begin;
+        }
+        run.extend_from_slice(&block);
+    }
+    if !run.is_empty() {
+        storage.write_block(piece_idx, run_start, &run)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem_storage::MemStorage;
+    use crate::utils::sample_torrent;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn pieces_are_hashed_written_and_reported() {
+        let (torrent_info, data) = sample_torrent(&[("data.bin", 40000)], 32768);
+        // Room for two blocks only.
+        let disk = DiskIo::new(Box::new(MemStorage::new(&torrent_info)), 2, 2);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        disk.write_block(0, 16384, data[16384..32768].to_vec(), &tx).await;
+        disk.write_block(1, 0, data[32768..].to_vec(), &tx).await;
+        // The cache is full, the partial pieces are written out to make room.
+        tokio::time::timeout(Duration::from_secs(5), disk.write_block(0, 0, data[..16384].to_vec(), &tx))
+            .await
+            .unwrap();
+        disk.finish_piece(0, torrent_info.get_piece_hash(0), tx.clone());
+        disk.finish_piece(1, [0; 20], tx);
+
+        let mut results = vec![];
+        for _ in 0..2 {
+            match rx.recv().await {
+                Some(Signal::PieceHashed(piece_idx, matched)) => results.push((piece_idx, matched)),
+                other => panic!("unexpected signal {:?}", other),
+            }
+        }
+        results.sort();
+        assert_eq!(results, vec![(0, true), (1, false)]);
+        assert_eq!(disk.read_block(0, 0, 32768).await.unwrap(), &data[..32768]);
+        assert_eq!(disk.slots.available_permits(), 2);
+    }
+}
@@ -1,16 +1,47 @@
 use crate::meta_info::TorrentInfo;
 use crate::piece_control::PieceControler;
-use crate::error::{Result, Error};
-use sha1::Sha1;
+use crate::picker::{PiecePicker, DEFAULT_PRIORITY, MAX_PRIORITY, SKIP_PRIORITY};
+use crate::disk_io::{DiskIo, DISK_WORKERS, WRITE_CACHE_BLOCKS};
+use crate::storage::{FileEntry, Storage, StorageKind};
+use crate::resume::{PartialPiece, ResumeData};
+use crate::error::Result;
+use crate::utils::to_hex;
 use bit_vec::BitVec;
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::path::Path;
-use std::io::{Read, Write, Seek};
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 pub const BLOCKSIZE:u32 = 16384;
 
-/// At any time the are at most 10 pieces in downloading map.
-const MAX_NO_PIECES: i32 = 10;
+/// What happened to a piece after one of its blocks has been written.
+#[derive(Debug, PartialEq)]
+pub enum PieceProgress {
+    InProgress, //still missing some blocks
+    Verified(usize), //all blocks are here and the hash matches
+    HashFailed(usize), //all blocks are here but the data is corrupted
+}
+
+/// How much a file of the torrent is wanted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilePriority {
+    Skip, //not downloaded, pieces it shares with wanted files still are
+    Low,
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    /// Priority of the pieces of this file, a piece shared by several files gets the highest.
+    pub fn piece_priority(self) -> u8 {
+        match self {
+            FilePriority::Skip => SKIP_PRIORITY,
+            FilePriority::Low => 1,
+            FilePriority::Normal => DEFAULT_PRIORITY,
+            FilePriority::High => MAX_PRIORITY,
+        }
+    }
+}
 
 #[derive(Clone, PartialEq)]
 enum BlockState {
@@ -21,8 +52,10 @@ enum BlockState {
 }
 
 struct DownloadingPiece {
+    #[allow(dead_code)]
     piece_idx: usize,
     blocks: Vec<BlockState>,
+    requesters: Vec<Vec<(String, Instant)>>, //peers asked for each block and when, several of them in endgame.
     remain_blocks: usize, //keep track here so we don't need to recalculate.
 }
 
@@ -30,8 +63,33 @@ pub struct Downloader {
     piece_control: PieceControler, 
     downloading: HashMap<usize, DownloadingPiece>,
     meta_info: TorrentInfo,
-    //should I have a mean to manage file?
-    data_file: File,
+    disk: DiskIo,
+    resume_path: PathBuf,
+    uploaded: u64, //bytes served to peers, kept across restarts by resume data.
+    downloaded: u64,
+    deadlines: HashSet<usize>, //time critical pieces of a stream, not verified yet.
+    peer_rates: HashMap<String, (u64, Instant)>, //bytes received from each peer since its first block.
+    piece_watch: watch::Sender<usize>, //index of the last verified piece.
+    piece_updates: watch::Receiver<usize>,
+    needs_check: bool, //no resume data to trust, the data on disk has to be checked.
+    checking: bool, //a hash check is running, nothing is downloaded meanwhile.
+    resumed: Option<usize>, //pieces restored from resume data, None when it was not used.
+}
+
+/// Resume data taken under the downloader lock, written without it.
+pub struct ResumeSnapshot {
+    disk: DiskIo,
+    resume: ResumeData,
+    path: PathBuf,
+}
+
+impl ResumeSnapshot {
+    /// Flush the disk so every finished block is in the files, then save.
+    pub fn save(mut self) -> Result<()> {
+        self.disk.flush()?;
+        self.resume.files = self.disk.storage().get_file_stamps()?;
+        self.resume.save(&self.path)
+    }
 }
 
 /*Implementation*/
@@ -41,6 +99,7 @@ impl DownloadingPiece {
         Self{
             piece_idx, 
             blocks: vec![BlockState::Open; no_blocks],  
+            requesters: vec![Vec::new(); no_blocks],
             remain_blocks: no_blocks,
         }
     }
@@ -55,11 +114,13 @@ impl DownloadingPiece {
         }) 
     }
 
-    //return requested but incompleted block.
-    fn get_next_incompleted_block(&self) -> Option<usize> {
-        self.blocks.iter().position(|block_state| {
-            *block_state == BlockState::Requested
-        })
+    /// Return the requested block with the fewest requesters that `peer` has not been asked
+    /// for yet, used in endgame.
+    fn get_duplicate_block(&self, peer: &str) -> Option<usize> {
+        (0..self.blocks.len())
+            .filter(|&idx| self.blocks[idx] == BlockState::Requested)
+            .filter(|&idx| !self.requesters[idx].iter().any(|(requester, _)| requester == peer))
+            .min_by_key(|&idx| self.requesters[idx].len())
     }
 
     fn is_finished(&self) -> bool {
@@ -71,106 +132,599 @@ impl DownloadingPiece {
 
 impl Downloader {
     pub fn new(torrent_info: &TorrentInfo) -> Result<Self> {
+        Self::with_directory(torrent_info, Path::new("downloads"))
+    }
+
+    /// Same as new() but files are stored under `base_dir`.
+    pub fn with_directory(torrent_info: &TorrentInfo, base_dir: &Path) -> Result<Self> {
+        Self::with_storage(torrent_info, base_dir, StorageKind::Disk.open(torrent_info, base_dir)?)
+    }
+
+    /// Keep the data in `storage`, resume data (if the storage is persistent) goes to `base_dir`.
+    pub fn with_storage(torrent_info: &TorrentInfo, base_dir: &Path, storage: Box<dyn Storage>) -> Result<Self> {
         let downloading = HashMap::new();
         let piece_control = PieceControler::new(torrent_info.get_number_of_pieces());
-        
-        let path = Path::new("downloads").join(torrent_info.get_torrent_name());
-        let data_file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
-   
+
+        let resume_path = base_dir.join(format!("{}.resume", to_hex(&torrent_info.get_info_hash())));
+        let (piece_watch, piece_updates) = watch::channel(0);
+
         let mut new_instance = Self {
             piece_control,
             downloading,
             meta_info: torrent_info.clone(),
-            data_file,
+            disk: DiskIo::new(storage, DISK_WORKERS, WRITE_CACHE_BLOCKS),
+            resume_path,
+            uploaded: 0,
+            downloaded: 0,
+            deadlines: HashSet::new(),
+            peer_rates: HashMap::new(),
+            piece_watch,
+            piece_updates,
+            needs_check: false,
+            checking: false,
+            resumed: None,
         };
-        new_instance.verify()?;
+        // A storage that starts empty every time has nothing to check.
+        if new_instance.disk.storage().is_persistent() && !new_instance.load_resume()? {
+            new_instance.needs_check = true;
+        }
         Ok(new_instance)
     }
 
+    /// Move the downloaded files and the resume data under `base_dir`.
+    pub fn move_storage(&mut self, base_dir: &Path) -> Result<()> {
+        self.disk.storage().move_to(base_dir)?;
+        let resume_path = base_dir.join(self.resume_path.file_name().unwrap_or_default());
+        if self.resume_path.is_file() {
+            std::fs::rename(&self.resume_path, &resume_path)?;
+        }
+        self.resume_path = resume_path;
+        Ok(())
+    }
+
+    /// Remove the downloaded files and the resume data, every piece is missing again.
+    pub fn delete_files(&mut self) -> Result<()> {
+        self.disk.clear_cache();
+        self.disk.storage().delete()?;
+        if self.resume_path.is_file() {
+            std::fs::remove_file(&self.resume_path)?;
+        }
+        for piece_idx in 0..self.meta_info.get_number_of_pieces() {
+            self.piece_control.reset_piece(piece_idx);
+        }
+        self.downloading.clear();
+        Ok(())
+    }
+
+    /// Write everything still buffered by the disk workers and the storage.
+    pub fn flush(&mut self) -> Result<()> {
+        self.disk.flush()
+    }
+
+    /// Handle on the disk workers, peers write and read blocks through it.
+    pub fn get_disk(&self) -> DiskIo {
+        self.disk.clone()
+    }
+
     pub fn update_priority(&mut self, bit_field: BitVec) {
-        bit_field.iter().enumerate().for_each(|(pie_idx, set)| {
+        bit_field.iter().take(self.meta_info.get_number_of_pieces()).enumerate().for_each(|(pie_idx, set)| {
             if set {
                 self.piece_control.increase_count(pie_idx);
             }
         });
     }
 
-    pub fn write_block(&mut self, piece_idx: usize, block_offset: u32, data: &[u8]) {
-        // Update state to writing first
-        if let Some(piece) = self.downloading.get_mut(&piece_idx)  {
-            let block_idx = (block_offset / BLOCKSIZE) as usize;
-            piece.set_state(block_idx, BlockState::Writing);
-            //seek
-            let piece_offset = self.meta_info.get_piece_length(0) as u64 * piece_idx as u64;
-            let block_offset = piece_offset + block_offset as u64;
-            self.data_file.seek(std::io::SeekFrom::Start(block_offset as u64));
-            //write block to disk
-            self.data_file.write_all(data);
-            //update block state to Finished.
-            piece.set_state(block_idx, BlockState::Finished);
-            if piece.is_finished() {
-                self.piece_control.set_piece_complete(piece_idx);
-                println!("Piece {} has been finished", piece_idx);
+    /// Change how the next piece is chosen, rarest first by default.
+    pub fn set_picker(&mut self, picker: Box<dyn PiecePicker>) {
+        self.piece_control.set_picker(picker);
+    }
+
+    /// Set the priority of every file, in the order of the torrent. Files missing from
+    /// `priorities` keep the normal priority.
+    pub fn set_file_priorities(&mut self, priorities: &[FilePriority]) -> Result<()> {
+        let wanted = self.apply_file_priorities(priorities);
+        self.disk.set_wanted(&wanted)
+    }
+
+    /// Piece side of set_file_priorities(), return which files are wanted so the caller
+    /// can update the storage out of the lock.
+    pub fn apply_file_priorities(&mut self, priorities: &[FilePriority]) -> Vec<bool> {
+        let layout = self.disk.storage().get_layout().clone();
+        let file_priority = |file_idx: usize| priorities.get(file_idx).cloned().unwrap_or(FilePriority::Normal);
+        for piece_idx in 0..self.meta_info.get_number_of_pieces() {
+            let length = self.meta_info.get_piece_length(piece_idx) as usize;
+            let priority = layout
+                .map_range(layout.get_offset(piece_idx, 0), length)
+                .iter()
+                .map(|seg| file_priority(seg.file_idx).piece_priority())
+                .max()
+                .unwrap_or(SKIP_PRIORITY);
+            self.piece_control.set_priority(piece_idx, priority);
+        }
+        (0..layout.get_files().len()).map(|file_idx| file_priority(file_idx) != FilePriority::Skip).collect()
+    }
+
+    /// Priority used by the PriorityPicker, see picker::MAX_PRIORITY.
+    pub fn set_piece_priority(&mut self, piece_idx: usize, priority: u8) {
+        if piece_idx < self.meta_info.get_number_of_pieces() {
+            self.piece_control.set_priority(piece_idx, priority);
+        }
+    }
+
+    /// Ask for a piece before `deadline`, used by the PriorityPicker. Pieces with a deadline
+    /// are time critical: they go to the fastest peers and get duplicate requests early.
+    pub fn set_piece_deadline(&mut self, piece_idx: usize, deadline: Option<Instant>) {
+        if piece_idx >= self.meta_info.get_number_of_pieces() || self.piece_control.has_piece(piece_idx) {
+            return;
+        }
+        self.piece_control.set_deadline(piece_idx, deadline);
+        if deadline.is_some() {
+            self.deadlines.insert(piece_idx);
+        } else {
+            self.deadlines.remove(&piece_idx);
+        }
+    }
+
+    /// Receiver woken up every time a piece has been verified.
+    pub fn subscribe_pieces(&self) -> watch::Receiver<usize> {
+        self.piece_updates.clone()
+    }
+
+    /// Files of the torrent and where they start in the piece stream.
+    pub fn get_files(&self) -> Vec<FileEntry> {
+        self.disk.storage().get_layout().get_files().clone()
+    }
+
+    /// Files the piece belongs to that have all of their pieces now.
+    pub fn get_completed_files(&self, piece_idx: usize) -> Vec<usize> {
+        let piece_size = self.get_piece_size();
+        let start = piece_idx as u64 * piece_size;
+        let end = start + self.meta_info.get_piece_length(piece_idx) as u64;
+        self.get_files()
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.length > 0 && file.offset < end && file.offset + file.length > start)
+            .filter(|(_, file)| {
+                let first = (file.offset / piece_size) as usize;
+                let last = ((file.offset + file.length - 1) / piece_size) as usize;
+                (first..=last).all(|idx| self.piece_control.has_piece(idx))
+            })
+            .map(|(file_idx, _)| file_idx)
+            .collect()
+    }
+
+    pub fn get_piece_size(&self) -> u64 {
+        self.meta_info.get_piece_size()
+    }
+
+    /// A peer is fast when it gives us at least the median rate of the peers that sent
+    /// something. Everybody is fast before the first block arrives.
+    fn is_fast_peer(&self, peer: &str) -> bool {
+        let rate = |(bytes, since): &(u64, Instant)| *bytes as f64 / since.elapsed().as_secs_f64().max(1.0);
+        let mut rates = self.peer_rates.values().map(rate).collect::<Vec<f64>>();
+        if rates.is_empty() {
+            return true;
+        }
+        rates.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        match self.peer_rates.get(peer) {
+            Some(entry) => rate(entry) >= rates[(rates.len() - 1) / 2],
+            None => false,
+        }
+    }
+
+    /// A peer announced a new piece with a Have message.
+    pub fn add_peer_piece(&mut self, piece_idx: usize) {
+        if piece_idx < self.meta_info.get_number_of_pieces() {
+            self.piece_control.increase_count(piece_idx);
+        }
+    }
+
+    /// A peer went away (or replaced its bitfield), its pieces are less available now.
+    pub fn remove_peer_pieces(&mut self, bit_field: &BitVec) {
+        bit_field.iter().take(self.meta_info.get_number_of_pieces()).enumerate().for_each(|(pie_idx, set)| {
+            if set {
+                self.piece_control.decrease_count(pie_idx);
             }
+        });
+    }
+
+    /// Write a block straight to the storage in the calling thread, and check the piece
+    /// when it is complete. Peers go through the disk workers instead, see block_written().
+    pub fn write_block(&mut self, piece_idx: usize, block_offset: u32, data: &[u8]) -> Result<PieceProgress> {
+        if !self.start_write(piece_idx, block_offset, data.len()) {
+            return Ok(PieceProgress::InProgress);
+        }
+        //write block to disk, storage takes care of file boundaries.
+        self.disk.storage().write_block(piece_idx, block_offset, data)?;
+        match self.block_written(piece_idx, block_offset, data.len()) {
+            Some(_) => self.verify_piece(piece_idx),
+            None => Ok(PieceProgress::InProgress),
         }
     }
 
-    //FIXME: It should not return a piece that it all blocks are requested
+    /// A block arrived, mark it as being written so a duplicate of it is dropped.
+    /// return: false if the block is unknown, does not have the offset and length of one of
+    /// our blocks, or is already written or being written.
+    pub fn start_write(&mut self, piece_idx: usize, block_offset: u32, length: usize) -> bool {
+        let block_idx = (block_offset / BLOCKSIZE) as usize;
+        let known = self.downloading.get(&piece_idx).is_some_and(|piece| block_idx < piece.blocks.len());
+        // Anything else would be written over the next block, or the next piece.
+        if !known
+            || !block_offset.is_multiple_of(BLOCKSIZE)
+            || length != self.get_block_size(piece_idx, block_idx) as usize
+        {
+            return false;
+        }
+        let piece = match self.downloading.get_mut(&piece_idx) {
+            Some(piece) => piece,
+            None => return false,
+        };
+        match piece.blocks.get(block_idx) {
+            // Unknown block, or a duplicate from endgame.
+            None | Some(BlockState::Finished) | Some(BlockState::Writing) => return false,
+            // The request had been given up but the data came anyway.
+            Some(BlockState::Open) => piece.remain_blocks -= 1,
+            _ => {}
+        }
+        piece.requesters[block_idx].clear();
+        piece.set_state(block_idx, BlockState::Writing);
+        true
+    }
+
+    /// The data of a block given to start_write() has been handed to the storage or the disk workers.
+    /// return: the hash the piece must have when this was its last missing block.
+    pub fn block_written(&mut self, piece_idx: usize, block_offset: u32, length: usize) -> Option<[u8; 20]> {
+        let piece = self.downloading.get_mut(&piece_idx)?;
+        let block_idx = (block_offset / BLOCKSIZE) as usize;
+        if piece.blocks.get(block_idx)? != &BlockState::Writing {
+            return None;
+        }
+        piece.set_state(block_idx, BlockState::Finished);
+        self.downloaded += length as u64;
+        if piece.is_finished() {
+            Some(self.meta_info.get_piece_hash(piece_idx))
+        } else {
+            None
+        }
+    }
+
+    /// Read a downloaded piece back from disk and check it against the hash in meta info.
+    fn verify_piece(&mut self, piece_idx: usize) -> Result<PieceProgress> {
+        let matched = self.check_piece_hash(piece_idx)?;
+        Ok(self.piece_checked(piece_idx, matched))
+    }
+
+    /// The hash of a complete piece has been checked. A corrupted piece is dropped from the
+    /// downloading map and goes back to NOTYET, so all of its blocks will be requested again.
+    pub fn piece_checked(&mut self, piece_idx: usize, matched: bool) -> PieceProgress {
+        if !self.downloading.contains_key(&piece_idx) {
+            // Files have been deleted while the piece was checked.
+            return PieceProgress::InProgress;
+        }
+        if matched {
+            self.downloading.remove(&piece_idx);
+            self.piece_control.set_piece_complete(piece_idx);
+            self.piece_control.set_deadline(piece_idx, None);
+            self.deadlines.remove(&piece_idx);
+            let _ = self.piece_watch.broadcast(piece_idx);
+            PieceProgress::Verified(piece_idx)
+        } else {
+            self.downloading.remove(&piece_idx);
+            self.piece_control.reset_piece(piece_idx);
+            PieceProgress::HashFailed(piece_idx)
+        }
+    }
+
+    fn check_piece_hash(&mut self, piece_idx: usize) -> Result<bool> {
+        Ok(self.disk.storage().hash_piece(piece_idx)? == self.meta_info.get_piece_hash(piece_idx))
+    }
+
+    /// Return a piece the peer has with at least one Open block.
     pub fn pick_next_piece(&mut self, peer_bitfield: &BitVec) -> Option<usize> {
-        //Check downding list first
-        for idx in self.downloading.keys() {
-            if peer_bitfield.get(*idx) == Some(true) {
-                if let Some(entry) = self.downloading.get(idx) {
-                    //only return if it still has block to request
-                    //a lot of if (shit)  
-                    if entry.remain_blocks > 0 {
-                        return Some(*idx);
-                    }
-                }
+        let has_open_blocks = |idx: &usize, entry: &DownloadingPiece| {
+            peer_bitfield.get(*idx) == Some(true) && entry.remain_blocks > 0 && self.piece_control.is_wanted(*idx)
+        };
+        //Time critical pieces first, then the ones already downloading.
+        if let Some((idx, _)) = self.downloading.iter().find(|(idx, entry)| self.deadlines.contains(idx) && has_open_blocks(idx, entry)) {
+            return Some(*idx);
+        }
+        let critical_waiting = self
+            .deadlines
+            .iter()
+            .any(|idx| peer_bitfield.get(*idx) == Some(true) && !self.downloading.contains_key(idx));
+        if !critical_waiting {
+            if let Some((idx, _)) = self.downloading.iter().find(|(idx, entry)| has_open_blocks(idx, entry)) {
+                return Some(*idx);
             }
         }
         //There is no valid piece in downloading list, so we get a new one.
         //then add it to downloading list.
-        if let Some(piece_idx) = self.piece_control.get_next_piece(&peer_bitfield) {
+        if let Some(piece_idx) = self.piece_control.get_next_piece(peer_bitfield) {
             self.piece_control.set_piece_picked(piece_idx);
 
-            // Calculate the number of blocks here.
-            let number_of_blocks = 
-                ((self.meta_info.get_piece_length(piece_idx) as f32) / BLOCKSIZE as f32) as usize;
+            // Calculate the number of blocks here, the last block may be shorter than BLOCKSIZE.
+            let piece_length = self.meta_info.get_piece_length(piece_idx);
+            let number_of_blocks = piece_length.div_ceil(BLOCKSIZE) as usize;
             let new_piece = DownloadingPiece::new(piece_idx, number_of_blocks);
             self.downloading.insert(piece_idx, new_piece);
             return Some(piece_idx);
         }
-        
-        //Re-check downloading list again and pick the first piece
-        self.downloading.keys().next().map(|x| *x)
-    }
-
-    // What should I return here?
-    // return: (piece index, block index, block size)
-    pub fn pick_next_block(&mut self, peer_bitfield: &BitVec) -> Option<(u32, u32, u32)> {
-        self.pick_next_piece(peer_bitfield).and_then(|piece_idx| {
-            if let Some(mut entry) = self.downloading.get_mut(&piece_idx) {
-                match entry.get_next_free_block() {
-                    Some(block_idx) => {
-                        entry.remain_blocks -= 1;
-                        entry.set_state(block_idx, BlockState::Requested);
-                        Some((piece_idx as u32, block_idx as u32 * BLOCKSIZE, self.get_block_size(piece_idx, block_idx)))
-                    }
-                    _ => {
-                        if let Some(block_idx) = entry.get_next_incompleted_block() {
-                            Some((piece_idx as u32, block_idx as u32 * BLOCKSIZE, self.get_block_size(piece_idx, block_idx)))
-                        } else {
-                            None
-                        }
-                    }
+        None
+    }
+
+    /// Endgame: every missing block has been requested, the last ones are requested from
+    /// several peers so a slow peer does not hold the whole download.
+    pub fn is_endgame(&self) -> bool {
+        !self.is_complete() && self.piece_control.all_picked() && self.downloading.values().all(|piece| piece.remain_blocks == 0)
+    }
+
+    /// Pick a block for `peer` and remember it has been asked for it.
+    /// return: (piece index, block offset, block size)
+    pub fn pick_next_block(&mut self, peer: &str, peer_bitfield: &BitVec) -> Option<(u32, u32, u32)> {
+        if self.checking {
+            return None;
+        }
+        // Time critical pieces are only requested from the fastest peers.
+        let mut peer_bitfield = peer_bitfield.clone();
+        if !self.deadlines.is_empty() && !self.is_fast_peer(peer) {
+            for idx in self.deadlines.iter() {
+                if *idx < peer_bitfield.len() {
+                    peer_bitfield.set(*idx, false);
+                }
+            }
+        }
+        let peer_bitfield = &peer_bitfield;
+        let endgame = self.is_endgame();
+        let (piece_idx, block_idx) = match self.pick_next_piece(peer_bitfield) {
+            Some(piece_idx) => {
+                let entry = self.downloading.get_mut(&piece_idx)?;
+                let block_idx = entry.get_next_free_block()?;
+                entry.remain_blocks -= 1;
+                entry.set_state(block_idx, BlockState::Requested);
+                (piece_idx, block_idx)
+            }
+            // Time critical pieces get duplicate requests without waiting for the endgame.
+            None => self
+                .downloading
+                .iter()
+                .filter(|(idx, _)| endgame || self.deadlines.contains(idx))
+                .filter(|(idx, _)| peer_bitfield.get(**idx) == Some(true))
+                .filter_map(|(idx, entry)| entry.get_duplicate_block(peer).map(|block_idx| (*idx, block_idx)))
+                .min_by_key(|(idx, block_idx)| self.downloading[idx].requesters[*block_idx].len())?,
+        };
+        if let Some(entry) = self.downloading.get_mut(&piece_idx) {
+            entry.requesters[block_idx].push((peer.to_string(), Instant::now()));
+        }
+        Some((piece_idx as u32, block_idx as u32 * BLOCKSIZE, self.get_block_size(piece_idx, block_idx)))
+    }
+
+    /// A block arrived from `peer`, return the other peers that were asked for it so their
+    /// requests can be cancelled.
+    pub fn block_received(&mut self, piece_idx: usize, block_offset: u32, peer: &str) -> Vec<String> {
+        let block_idx = (block_offset / BLOCKSIZE) as usize;
+        if piece_idx < self.meta_info.get_number_of_pieces() && block_offset < self.meta_info.get_piece_length(piece_idx) {
+            let length = self.get_block_size(piece_idx, block_idx) as u64;
+            self.peer_rates.entry(peer.to_string()).or_insert((0, Instant::now())).0 += length;
+        }
+        match self.downloading.get_mut(&piece_idx) {
+            Some(piece) if block_idx < piece.requesters.len() => {
+                std::mem::take(&mut piece.requesters[block_idx])
+                    .into_iter()
+                    .map(|(requester, _)| requester)
+                    .filter(|requester| requester != peer)
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The request of `peer` for this block will not be answered (rejected or lost with a
+    /// choke), when nobody else has been asked the block can be requested again.
+    pub fn reject_block(&mut self, piece_idx: usize, block_offset: u32, peer: &str) {
+        if let Some(piece) = self.downloading.get_mut(&piece_idx) {
+            let block_idx = (block_offset / BLOCKSIZE) as usize;
+            if piece.blocks.get(block_idx) == Some(&BlockState::Requested) {
+                piece.requesters[block_idx].retain(|(requester, _)| requester != peer);
+                if piece.requesters[block_idx].is_empty() {
+                    piece.set_state(block_idx, BlockState::Open);
+                    piece.remain_blocks += 1;
                 }
-            } else {
-                None
             }
+        }
+    }
+
+    /// Forget every request of `peer`, used when it chokes us or goes away.
+    pub fn release_peer(&mut self, peer: &str) {
+        for piece in self.downloading.values_mut() {
+            for block_idx in 0..piece.blocks.len() {
+                if piece.blocks[block_idx] != BlockState::Requested {
+                    continue;
+                }
+                piece.requesters[block_idx].retain(|(requester, _)| requester != peer);
+                if piece.requesters[block_idx].is_empty() {
+                    piece.set_state(block_idx, BlockState::Open);
+                    piece.remain_blocks += 1;
+                }
+            }
+        }
+    }
+
+    /// The peer is gone: its requests are released and its rate is forgotten.
+    pub fn remove_peer(&mut self, peer: &str) {
+        self.release_peer(peer);
+        self.peer_rates.remove(peer);
+    }
+
+    /// Give up requests that have been waiting longer than `timeout`, their blocks can be
+    /// requested again.
+    /// return: the peer and (piece index, block offset, block size) of every expired request.
+    pub fn expire_requests(&mut self, timeout: Duration) -> Vec<(String, (u32, u32, u32))> {
+        let mut expired = Vec::new();
+        for (&piece_idx, piece) in self.downloading.iter_mut() {
+            for block_idx in 0..piece.blocks.len() {
+                if piece.blocks[block_idx] != BlockState::Requested {
+                    continue;
+                }
+                let (late, waiting): (Vec<_>, Vec<_>) = piece.requesters[block_idx]
+                    .drain(..)
+                    .partition(|(_, requested_at)| requested_at.elapsed() >= timeout);
+                piece.requesters[block_idx] = waiting;
+                for (peer, _) in late {
+                    expired.push((peer, (piece_idx, block_idx)));
+                }
+                if piece.requesters[block_idx].is_empty() {
+                    piece.set_state(block_idx, BlockState::Open);
+                    piece.remain_blocks += 1;
+                }
+            }
+        }
+        expired
+            .into_iter()
+            .map(|(peer, (piece_idx, block_idx))| {
+                let block = (piece_idx as u32, block_idx as u32 * BLOCKSIZE, self.get_block_size(piece_idx, block_idx));
+                (peer, block)
+            })
+            .collect()
+    }
+
+    pub fn get_info_hash(&self) -> [u8; 20] {
+        self.meta_info.get_info_hash()
+    }
+
+    pub fn get_torrent_info(&self) -> TorrentInfo {
+        self.meta_info.clone()
+    }
+
+    pub fn get_number_of_pieces(&self) -> usize {
+        self.meta_info.get_number_of_pieces()
+    }
+
+    pub fn is_interesting(&self, peer_bitfield: &BitVec) -> bool {
+        self.piece_control.is_interesting(peer_bitfield)
+    }
+
+    /// Return true when every piece has been downloaded, we are only seeding then.
+    pub fn is_complete(&self) -> bool {
+        self.piece_control.is_complete()
+    }
+
+    pub fn has_piece(&self, piece_idx: usize) -> bool {
+        piece_idx < self.meta_info.get_number_of_pieces() && self.piece_control.has_piece(piece_idx)
+    }
+
+    /// Return a bitfield of pieces we have and can serve.
+    pub fn get_bitfield(&self) -> BitVec {
+        self.piece_control.get_bitfield()
+    }
+
+    /// Check that we can serve a block and count it as uploaded, the data itself is read
+    /// by the disk workers.
+    pub fn start_upload(&mut self, piece_idx: usize, begin: u32, length: u32) -> bool {
+        if self.checking || piece_idx >= self.meta_info.get_number_of_pieces() || !self.piece_control.has_piece(piece_idx) {
+            return false;
+        }
+        if begin as u64 + length as u64 > self.meta_info.get_piece_length(piece_idx) as u64 {
+            return false;
+        }
+        self.uploaded += length as u64;
+        true
+    }
+
+    /// Return (uploaded, downloaded) bytes, including previous runs.
+    pub fn get_transferred(&self) -> (u64, u64) {
+        (self.uploaded, self.downloaded)
+    }
+
+    /// Return the number of bytes of wanted pieces we still miss.
+    pub fn get_left(&self) -> u64 {
+        (0..self.meta_info.get_number_of_pieces())
+            .filter(|&piece_idx| !self.piece_control.has_piece(piece_idx) && self.piece_control.is_wanted(piece_idx))
+            .map(|piece_idx| self.meta_info.get_piece_length(piece_idx) as u64)
+            .sum()
+    }
+
+    /// Save verified pieces and finished blocks of pieces in progress.
+    pub fn save_resume(&mut self) -> Result<()> {
+        match self.resume_snapshot() {
+            Some(snapshot) => snapshot.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// State to save as resume data, None when the storage does not outlive the session.
+    /// Saving it flushes the disk, better done out of the async runtime.
+    pub fn resume_snapshot(&self) -> Option<ResumeSnapshot> {
+        if !self.disk.storage().is_persistent() {
+            return None;
+        }
+        let partial = self
+            .downloading
+            .iter()
+            .map(|(&piece_idx, piece)| {
+                let blocks = piece.blocks.iter().map(|state| *state == BlockState::Finished).collect::<BitVec>();
+                PartialPiece {
+                    piece: piece_idx as i64,
+                    blocks: ByteBuf::from(blocks.to_bytes()),
+                }
+            })
+            .collect();
+        let resume = ResumeData {
+            info_hash: ByteBuf::from(self.meta_info.get_info_hash().to_vec()),
+            have: ByteBuf::from(self.get_bitfield().to_bytes()),
+            partial,
+            files: Vec::new(), //stamped after the flush
+            uploaded: self.uploaded as i64,
+            downloaded: self.downloaded as i64,
+        };
+        Some(ResumeSnapshot {
+            disk: self.disk.clone(),
+            resume,
+            path: self.resume_path.clone(),
         })
     }
 
+    /// Restore state from resume data, return false when it is missing or files have changed
+    /// since it was saved, all pieces must be checked then.
+    fn load_resume(&mut self) -> Result<bool> {
+        let resume = match ResumeData::load(&self.resume_path) {
+            Some(resume) => resume,
+            None => return Ok(false),
+        };
+        if !resume.matches(&self.meta_info.get_info_hash(), &self.disk.storage().get_file_stamps()?) {
+            return Ok(false);
+        }
+
+        let no_pieces = self.meta_info.get_number_of_pieces();
+        let have = BitVec::from_bytes(&resume.have);
+        for piece_idx in (0..no_pieces).filter(|&idx| have.get(idx) == Some(true)) {
+            self.piece_control.set_piece_complete(piece_idx);
+        }
+        for partial in resume.partial {
+            let piece_idx = partial.piece as usize;
+            if piece_idx >= no_pieces || self.piece_control.has_piece(piece_idx) {
+                continue;
+            }
+            let number_of_blocks = self.meta_info.get_piece_length(piece_idx).div_ceil(BLOCKSIZE) as usize;
+            let finished = BitVec::from_bytes(&partial.blocks);
+            let mut piece = DownloadingPiece::new(piece_idx, number_of_blocks);
+            for block_idx in (0..number_of_blocks).filter(|&idx| finished.get(idx) == Some(true)) {
+                piece.set_state(block_idx, BlockState::Finished);
+                piece.remain_blocks -= 1;
+            }
+            let is_finished = piece.is_finished();
+            self.piece_control.set_piece_picked(piece_idx);
+            self.downloading.insert(piece_idx, piece);
+            if is_finished {
+                // Stopped between the last block and the hash check.
+                self.verify_piece(piece_idx)?;
+            }
+        }
+        self.uploaded = resume.uploaded as u64;
+        self.downloaded = resume.downloaded as u64;
+        self.resumed = Some(have.iter().take(no_pieces).filter(|&set| set).count());
+        Ok(true)
+    }
+
     // Private functions
     fn get_block_size(&self, piece_idx: usize, block_idx: usize) -> u32 {
         let block_idx = block_idx as u32;
@@ -182,30 +736,218 @@ impl Downloader {
         }
     }
 
-    pub fn verify(&mut self) -> Result<()> {
+    /// Return true when there was no resume data to trust, every piece on disk must be
+    /// checked before downloading, see checker::HashCheck.
+    pub fn needs_check(&self) -> bool {
+        self.needs_check
+    }
 
-        let mut file = &self.data_file;
-        let no_pieces = self.meta_info.get_number_of_pieces();
-        for piece_idx in 0..no_pieces {
-            let piece_offset = self.meta_info.get_piece_length(0) as u64 * piece_idx as u64;
-            let length = self.meta_info.get_piece_length(piece_idx) as usize;
-            file.seek(std::io::SeekFrom::Start(piece_offset))?;
-            let mut data = Vec::with_capacity(length as usize);
-            let read_byte = file.take(length as u64).read_to_end(&mut data)?;
-            if read_byte < length {
-                //place holder.
-                file.write(&vec![0u8; length])?;
-            } else {
-                //get hash
-                let piece_hash = &self.meta_info.get_piece_hash(piece_idx);
-                let read_hash = Sha1::from(&data);
-                if read_hash.digest().bytes() == *piece_hash {
-                    self.piece_control.set_piece_complete(piece_idx);
-                    println!("Piece {} hash been download correclty", piece_idx);
-                }
-            }
-        } 
-        Ok(()) 
+    /// Number of pieces restored from resume data, None when there was none to trust.
+    pub fn resumed_pieces(&self) -> Option<usize> {
+        self.resumed
+    }
+
+    pub fn is_checking(&self) -> bool {
+        self.checking
+    }
+
+    /// Forget every piece and stop picking new ones, a hash check is starting.
+    pub fn begin_check(&mut self) -> Result<()> {
+        self.downloading.clear();
+        self.disk.flush()?;
+        for piece_idx in 0..self.meta_info.get_number_of_pieces() {
+            self.piece_control.reset_piece(piece_idx);
+        }
+        self.checking = true;
+        Ok(())
+    }
+
+    /// The hash check found a piece on disk.
+    pub fn set_piece_checked(&mut self, piece_idx: usize) {
+        if self.checking {
+            self.piece_control.set_piece_complete(piece_idx);
+            self.deadlines.remove(&piece_idx);
+            let _ = self.piece_watch.broadcast(piece_idx);
+        }
+    }
+
+    /// The hash check is over, pieces it did not find are downloaded again.
+    pub fn end_check(&mut self) {
+        self.checking = false;
+        self.needs_check = false;
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::picker::Sequential;
+    use crate::utils::{sample_torrent, TempDir};
+
+    // Two pieces of two blocks, the last one is short.
+    fn two_pieces() -> (TorrentInfo, Vec<u8>) {
+        sample_torrent(&[("data.bin", 40000)], 32768)
+    }
+
+    // Files "a" (40000 bytes) and "b" (30000 bytes), five pieces of 16KiB, piece 2 is shared.
+    fn two_files() -> (TorrentInfo, Vec<u8>) {
+        sample_torrent(&[("a", 40000), ("b", 30000)], 16384)
+    }
+
+    #[test]
+    fn resume_keeps_pieces_and_blocks() {
+        let (torrent_info, data) = two_pieces();
+        let dir = TempDir::new();
+        let all = BitVec::from_elem(2, true);
+
+        let mut downloader = Downloader::with_directory(&torrent_info, &dir).unwrap();
+        downloader.set_picker(Box::new(Sequential));
+        assert_eq!(downloader.pick_next_block("a", &all), Some((0, 0, 16384)));
+        assert_eq!(downloader.write_block(0, 0, &data[..16384]).unwrap(), PieceProgress::InProgress);
+        // Second block of piece 0 is requested but never arrives.
+        assert_eq!(downloader.pick_next_block("a", &all), Some((0, 16384, 16384)));
+        assert_eq!(downloader.pick_next_block("a", &all), Some((1, 0, 7232)));
+        assert_eq!(downloader.write_block(1, 0, &data[32768..]).unwrap(), PieceProgress::Verified(1));
+        downloader.save_resume().unwrap();
+        drop(downloader);
+
+        let mut resumed = Downloader::with_directory(&torrent_info, &dir).unwrap();
+        assert_eq!(resumed.get_bitfield(), BitVec::from_fn(2, |idx| idx == 1));
+        assert_eq!(resumed.get_transferred(), (0, 16384 + 7232));
+        // Only the second block of the first piece is still missing.
+        assert_eq!(resumed.pick_next_block("a", &all), Some((0, 16384, 16384)));
+        drop(resumed);
+
+        // Files changed behind our back: resume data is not trusted anymore.
+        std::fs::write(dir.join("data.bin"), vec![0u8; 40000]).unwrap();
+        let checked = Downloader::with_directory(&torrent_info, &dir).unwrap();
+        assert!(!checked.get_bitfield().any());
+    }
+
+    #[test]
+    fn corrupted_piece_is_downloaded_again() {
+        let (torrent_info, data) = two_pieces();
+        let dir = TempDir::new();
+        let first = BitVec::from_fn(2, |idx| idx == 0);
+
+        let mut downloader = Downloader::with_directory(&torrent_info, &dir).unwrap();
+        assert_eq!(downloader.pick_next_block("a", &first), Some((0, 0, 16384)));
+        assert_eq!(downloader.pick_next_block("a", &first), Some((0, 16384, 16384)));
+        let mut corrupted = data[16384..32768].to_vec();
+        corrupted[100] ^= 1;
+        assert_eq!(downloader.write_block(0, 0, &data[..16384]).unwrap(), PieceProgress::InProgress);
+        assert_eq!(downloader.write_block(0, 16384, &corrupted).unwrap(), PieceProgress::HashFailed(0));
+
+        // Back to NOTYET: not downloading, and picked again from its first block.
+        assert!(!downloader.downloading.contains_key(&0));
+        assert_eq!(downloader.pick_next_block("b", &first), Some((0, 0, 16384)));
+        assert_eq!(downloader.pick_next_block("b", &first), Some((0, 16384, 16384)));
+        assert_eq!(downloader.write_block(0, 0, &data[..16384]).unwrap(), PieceProgress::InProgress);
+        assert_eq!(downloader.write_block(0, 16384, &data[16384..32768]).unwrap(), PieceProgress::Verified(0));
+    }
+
+    #[test]
+    fn misplaced_blocks_are_dropped() {
+        let (torrent_info, data) = two_pieces();
+        let dir = TempDir::new();
+        let all = BitVec::from_elem(2, true);
+
+        let mut downloader = Downloader::with_directory(&torrent_info, &dir).unwrap();
+        downloader.set_picker(Box::new(Sequential));
+        for _ in 0..3 {
+            assert!(downloader.pick_next_block("a", &all).is_some());
+        }
+        assert_eq!(downloader.write_block(1, 0, &data[32768..]).unwrap(), PieceProgress::Verified(1));
+        // Too long for the block, it would spill over piece 1.
+        assert_eq!(downloader.write_block(0, 16384, &data[..23616]).unwrap(), PieceProgress::InProgress);
+        // Not at a block boundary, and past the end of the piece.
+        assert_eq!(downloader.write_block(0, 100, &data[..16384]).unwrap(), PieceProgress::InProgress);
+        assert_eq!(downloader.write_block(0, 32768, &data[..16384]).unwrap(), PieceProgress::InProgress);
+        assert_eq!(downloader.disk.storage().hash_piece(1).unwrap(), torrent_info.get_piece_hash(1));
+
+        assert_eq!(downloader.write_block(0, 0, &data[..16384]).unwrap(), PieceProgress::InProgress);
+        assert_eq!(downloader.write_block(0, 16384, &data[16384..32768]).unwrap(), PieceProgress::Verified(0));
+    }
+
+    #[test]
+    fn endgame_duplicates_and_cancels() {
+        let (torrent_info, data) = two_pieces();
+        let dir = TempDir::new();
+        let all = BitVec::from_elem(2, true);
+
+        let mut downloader = Downloader::with_directory(&torrent_info, &dir).unwrap();
+        assert!(!downloader.is_endgame());
+        for _ in 0..3 {
+            assert!(downloader.pick_next_block("a", &all).is_some());
+        }
+        assert!(downloader.is_endgame());
+        // Every block is already asked to "a", nothing left for it.
+        assert_eq!(downloader.pick_next_block("a", &all), None);
+
+        let duplicate = downloader.pick_next_block("b", &all).unwrap();
+        let (piece, begin, length) = duplicate;
+        assert_eq!(downloader.block_received(piece as usize, begin, "b"), vec!["a".to_string()]);
+        let start = (piece * 32768 + begin) as usize;
+        downloader.write_block(piece as usize, begin, &data[start..start + length as usize]).unwrap();
+        // The late copy from "a" is ignored.
+        assert!(downloader.block_received(piece as usize, begin, "a").is_empty());
+        downloader.write_block(piece as usize, begin, &data[start..start + length as usize]).unwrap();
+        assert_ne!(downloader.pick_next_block("b", &all), Some(duplicate));
+    }
+
+    #[test]
+    fn skipped_file_is_not_downloaded() {
+        let (torrent_info, data) = two_files();
+        let dir = TempDir::new();
+        let all = BitVec::from_elem(5, true);
+
+        let mut downloader = Downloader::with_directory(&torrent_info, &dir).unwrap();
+        downloader.set_file_priorities(&[FilePriority::Normal, FilePriority::Skip]).unwrap();
+        assert_eq!(downloader.get_left(), 3 * 16384);
+        while let Some((piece, begin, length)) = downloader.pick_next_block("a", &all) {
+            assert!(piece <= 2);
+            let start = (piece * 16384 + begin) as usize;
+            downloader.write_block(piece as usize, begin, &data[start..start + length as usize]).unwrap();
+        }
+        assert!(downloader.is_complete());
+        assert_eq!(downloader.get_left(), 0);
+        assert_eq!(downloader.get_completed_files(2), vec![0]);
+        // Only the part of "b" inside piece 2 has been written.
+        assert_eq!(std::fs::metadata(dir.join("dir").join("b")).unwrap().len(), 3 * 16384 - 40000);
+    }
+
+    #[test]
+    fn high_priority_file_comes_first() {
+        let (torrent_info, _) = two_files();
+        let dir = TempDir::new();
+        let all = BitVec::from_elem(5, true);
+
+        // The default picker, every piece is as rare as the others.
+        let mut downloader = Downloader::with_directory(&torrent_info, &dir).unwrap();
+        downloader.set_file_priorities(&[FilePriority::Normal, FilePriority::High]).unwrap();
+        let mut picked = HashSet::new();
+        // One block per piece, the pieces of "b" are all picked before those of "a".
+        for _ in 0..3 {
+            let (piece, _, _) = downloader.pick_next_block("a", &all).unwrap();
+            picked.insert(piece);
+        }
+        assert_eq!(picked, [2, 3, 4].iter().cloned().collect());
+    }
+
+    #[test]
+    fn expired_and_released_requests_reopen_blocks() {
+        let (torrent_info, _) = two_pieces();
+        let dir = TempDir::new();
+        let all = BitVec::from_elem(2, true);
+
+        let mut downloader = Downloader::with_directory(&torrent_info, &dir).unwrap();
+        let first = downloader.pick_next_block("a", &all).unwrap();
+        assert!(downloader.expire_requests(Duration::from_secs(60)).is_empty());
+        assert_eq!(downloader.expire_requests(Duration::from_secs(0)), vec![("a".to_string(), first)]);
+        // The block is free again, "b" gets it.
+        assert_eq!(downloader.pick_next_block("b", &all), Some(first));
+
+        downloader.release_peer("b");
+        assert_eq!(downloader.pick_next_block("c", &all), Some(first));
+    }
+}
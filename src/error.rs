@@ -1,4 +1,5 @@
 use bincode::Error as BincodeErrorKind;
+use reqwest::Error as HttpError;
 use serde_bencode::Error as BencodeError;
 use std::fmt;
 use std::io::Error as StdIoError;
@@ -15,6 +16,18 @@ pub enum Error {
     NotSupportProtocol(String),
     BincodeError(BincodeErrorKind),
     UrlError(EUrlParser),
+    HttpError(HttpError),
+    TrackerError(String), // Tracker answered with a failure reason.
+    InvalidHandshake(String),
+    InvalidMagnet(String),
+    InvalidMetadata(String), // Metadata from peers is missing, rejected or corrupted.
+    UnsafePath(String), // A file of the torrent would be written outside the download directory.
+    DhtError(String), // A DHT node did not answer or answered with an error.
+    StreamError(String), // A stream read cannot be served.
+    DiskError(String), // Disk workers are gone.
+    ConnectionLimit, // The session has too many peer connections.
+    UnknownTorrent, // No torrent with this info hash in the session.
+    DuplicateTorrent, // The torrent is already in the session.
     Unknown,
 }
 
@@ -48,6 +61,12 @@ impl From<EUrlParser> for Error {
     }
 }
 
+impl From<HttpError> for Error {
+    fn from(err: HttpError) -> Error {
+        Error::HttpError(err)
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -57,6 +76,18 @@ impl fmt::Display for Error {
             Error::AddrParserError(ref err) => err.fmt(f),
             Error::BincodeError(ref err) => err.fmt(f),
             Error::UrlError(ref err) => err.fmt(f),
+            Error::HttpError(ref err) => err.fmt(f),
+            Error::TrackerError(ref s) => write!(f, "Tracker error: {}", s),
+            Error::InvalidHandshake(ref s) => write!(f, "Invalid handshake: {}", s),
+            Error::InvalidMagnet(ref s) => write!(f, "Invalid magnet link: {}", s),
+            Error::InvalidMetadata(ref s) => write!(f, "Invalid metadata: {}", s),
+            Error::UnsafePath(ref s) => write!(f, "Unsafe file path in torrent: {}", s),
+            Error::DhtError(ref s) => write!(f, "DHT error: {}", s),
+            Error::StreamError(ref s) => write!(f, "Stream error: {}", s),
+            Error::DiskError(ref s) => write!(f, "Disk error: {}", s),
+            Error::ConnectionLimit => f.write_str("Too many connections"),
+            Error::UnknownTorrent => f.write_str("Unknown torrent"),
+            Error::DuplicateTorrent => f.write_str("Torrent already added"),
             _ => f.write_str("An unknown Error just happend."),
         }
     }
@@ -0,0 +1,79 @@
+/*
+ * events.rs
+ * Typed events for applications using the library. A torrent (or a session, for all of its
+ * torrents) publishes them on an event bus, every subscriber gets its own copy through a
+ * channel, which is also a Stream.
+ */
+use crate::torrent_instance::TorrentState;
+
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A piece has been downloaded and matched its hash.
+    PieceFinished { info_hash: [u8; 20], piece: usize },
+    /// A downloaded piece did not match its hash, it will be downloaded again.
+    HashFailed { info_hash: [u8; 20], piece: usize },
+    /// Every piece of a file (index in the torrent) has been downloaded.
+    FileCompleted { info_hash: [u8; 20], file: usize },
+    PeerConnected { info_hash: [u8; 20], addr: String },
+    PeerDisconnected { info_hash: [u8; 20], addr: String },
+    /// A peer did not send a requested block in time, its requests go to other peers.
+    PeerSnubbed { info_hash: [u8; 20], addr: String },
+    /// A tracker answered an announce with `peers` peers.
+    TrackerReply { info_hash: [u8; 20], url: String, peers: usize },
+    /// A tracker accepted an announce but sent a warning message with it.
+    TrackerWarning { info_hash: [u8; 20], url: String, message: String },
+    TrackerError { info_hash: [u8; 20], url: String, message: String },
+    StateChanged { info_hash: [u8; 20], state: TorrentState },
+    /// Data or resume data could not be written or read.
+    StorageError { info_hash: [u8; 20], message: String },
+    /// Resume data was trusted, `pieces` pieces are complete without a hash check.
+    Resumed { info_hash: [u8; 20], pieces: usize },
+    /// The torrent could not do its job, e.g. listen for peers, or its task ended with an error.
+    TorrentError { info_hash: [u8; 20], message: String },
+}
+
+/// Where events are published, cheap to clone. Subscribers that went away are dropped.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<UnboundedSender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive every event published from now on.
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        let (event_slot, event_rx) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push(event_slot);
+        event_rx
+    }
+
+    pub fn emit(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn every_subscriber_gets_events() {
+        let bus = EventBus::new();
+        let mut first = bus.subscribe();
+        let second = bus.subscribe();
+        bus.emit(Event::PieceFinished { info_hash: [1; 20], piece: 3 });
+        drop(second);
+        bus.emit(Event::HashFailed { info_hash: [1; 20], piece: 4 });
+
+        assert_eq!(first.next().await, Some(Event::PieceFinished { info_hash: [1; 20], piece: 3 }));
+        assert_eq!(first.next().await, Some(Event::HashFailed { info_hash: [1; 20], piece: 4 }));
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,230 @@
+/*
+ * extension.rs
+ * Extension protocol (BEP 10). Peers that set bit 20 of the reserved bytes can exchange
+ * extended messages (id 20), the first one is a bencoded handshake telling which extensions
+ * are supported and which extended message id each of them uses.
+ * Extensions are plugged into a peer through an ExtensionRegistry: every registered extension
+ * gets a local id (its position + 1) and receives the messages sent with that id.
+ */
+use crate::error::Result;
+use crate::message::{Message, MessagePlayload};
+
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Extended message id of the extension handshake.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+/// Bit 20 (counted from the right) of the reserved bytes: 0x10 in the sixth byte.
+pub const EXTENSION_BYTE: usize = 5;
+pub const EXTENSION_FLAG: u8 = 0x10;
+/// Client name and version sent in the `v` field.
+pub const CLIENT_VERSION: &str = "o_torrent 0.1";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension name -> extended message id, 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: HashMap<String, i64>,
+    /// Listening port of the sender.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<i64>,
+    /// Client name and version.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// Number of outstanding requests the sender accepts.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    /// Our address as seen by the sender, 4 or 16 bytes.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+impl ExtendedHandshake {
+    /// Return the id the remote side wants for extension `name`.
+    pub fn get_id(&self, name: &str) -> Option<u8> {
+        match self.m.get(name) {
+            Some(&id) if id > 0 && id <= u8::MAX as i64 => Some(id as u8),
+            _ => None,
+        }
+    }
+
+    pub fn get_yourip(&self) -> Option<IpAddr> {
+        let ip = self.yourip.as_ref()?;
+        match ip.len() {
+            4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(ip);
+                Some(IpAddr::from(octets))
+            }
+            16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(ip);
+                Some(IpAddr::from(octets))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn supports_extensions(reserved: &[u8; 8]) -> bool {
+    reserved[EXTENSION_BYTE] & EXTENSION_FLAG != 0
+}
+
+/// Build an extended message (id 20).
+pub fn extended_message(ext_id: u8, payload: Vec<u8>) -> Message {
+    Message::new(2 + payload.len(), Some(20), MessagePlayload::Extended(ext_id, payload))
+}
+
+/// An extension running on one peer connection.
+pub trait Extension: Send {
+    /// Name used in the `m` dictionary, e.g. "ut_pex".
+    fn name(&self) -> &'static str;
+
+    /// Add extension specific keys to our handshake.
+    fn fill_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// The remote handshake has been received.
+    fn on_handshake(&mut self, _remote: &ExtendedHandshake) {}
+
+    /// Handle a message sent to this extension, return the payloads to send back.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>>;
+
+    /// Called regularly by the peer, return payloads the extension wants to send on its own.
+    fn tick(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+/// Create the extensions of a new connection, from the address of the remote peer.
+pub type ExtensionFactory = Arc<dyn Fn(&str) -> Vec<Box<dyn Extension>> + Send + Sync>;
+
+/// Extensions of one peer connection and what the remote side told us in its handshake.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    extensions: Vec<Box<dyn Extension>>,
+    remote: Option<ExtendedHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an extension, messages the remote side sends with the returned id go to it.
+    pub fn register(&mut self, extension: Box<dyn Extension>) -> u8 {
+        self.extensions.push(extension);
+        self.extensions.len() as u8
+    }
+
+    /// Our handshake: every registered extension with its local id.
+    pub fn build_handshake(&self, port: u16, reqq: i64, remote_ip: Option<IpAddr>) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            p: Some(port as i64),
+            v: Some(CLIENT_VERSION.to_string()),
+            reqq: Some(reqq),
+            yourip: remote_ip.map(|ip| match ip {
+                IpAddr::V4(ip) => ByteBuf::from(ip.octets().to_vec()),
+                IpAddr::V6(ip) => ByteBuf::from(ip.octets().to_vec()),
+            }),
+            ..Default::default()
+        };
+        for (idx, extension) in self.extensions.iter().enumerate() {
+            handshake.m.insert(extension.name().to_string(), idx as i64 + 1);
+            extension.fill_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    pub fn get_remote(&self) -> Option<&ExtendedHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// Give every extension the remote peer supports a chance to send something.
+    pub fn tick(&mut self) -> Vec<Message> {
+        let remote = match &self.remote {
+            Some(remote) => remote,
+            None => return Vec::new(),
+        };
+        let mut messages = Vec::new();
+        for extension in self.extensions.iter_mut() {
+            if let Some(remote_id) = remote.get_id(extension.name()) {
+                messages.extend(extension.tick().into_iter().map(|payload| extended_message(remote_id, payload)));
+            }
+        }
+        messages
+    }
+
+    /// Handle an extended message, return the messages to send back to the remote peer.
+    pub fn handle_message(&mut self, ext_id: u8, payload: &[u8]) -> Result<Vec<Message>> {
+        if ext_id == EXTENDED_HANDSHAKE_ID {
+            let remote = serde_bencode::from_bytes::<ExtendedHandshake>(payload)?;
+            for extension in self.extensions.iter_mut() {
+                extension.on_handshake(&remote);
+            }
+            self.remote = Some(remote);
+            return Ok(Vec::new());
+        }
+
+        let extension = match self.extensions.get_mut(ext_id as usize - 1) {
+            Some(extension) => extension,
+            None => return Ok(Vec::new()), // not an id we gave out
+        };
+        let replies = extension.on_message(payload)?;
+        // Replies use the id the remote side picked for this extension.
+        let remote_id = match self.remote.as_ref().and_then(|remote| remote.get_id(extension.name())) {
+            Some(remote_id) => remote_id,
+            None => return Ok(Vec::new()),
+        };
+        Ok(replies.into_iter().map(|reply| extended_message(remote_id, reply)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    #[test]
+    fn handshake_and_dispatch() {
+        let mut registry = ExtensionRegistry::new();
+        assert_eq!(registry.register(Box::new(Echo)), 1);
+
+        let ours = registry.build_handshake(6881, 20, Some("10.0.0.2".parse().unwrap()));
+        let decoded = serde_bencode::from_bytes::<ExtendedHandshake>(&serde_bencode::to_bytes(&ours).unwrap()).unwrap();
+        assert_eq!(decoded.get_id("echo"), Some(1));
+        assert_eq!((decoded.p, decoded.reqq), (Some(6881), Some(20)));
+        assert_eq!(decoded.get_yourip(), Some("10.0.0.2".parse().unwrap()));
+
+        // Nothing is sent back before we know the remote id.
+        assert!(registry.handle_message(1, b"ping").unwrap().is_empty());
+
+        registry.handle_message(EXTENDED_HANDSHAKE_ID, b"d1:md4:echoi7eee").unwrap();
+        let replies = registry.handle_message(1, b"ping").unwrap();
+        assert_eq!(replies.len(), 1);
+        match &replies[0].payload {
+            MessagePlayload::Extended(7, payload) => assert_eq!(payload, b"ping"),
+            _ => panic!("reply should be an extended message"),
+        }
+        assert!(registry.handle_message(9, b"ping").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+/*
+ * http_server.rs
+ * A small local HTTP server so a media player can play files of a torrent while it is
+ * downloading. `GET /` lists the files, `GET /<file index>` serves one of them with Range
+ * support. Reads go through a TorrentStream, so they wait until the needed pieces are verified.
+ */
+use crate::error::Result;
+use crate::stream::TorrentStream;
+
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+
+/// Requests with a bigger head are dropped.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+pub struct HttpServer {
+    listener: TcpListener,
+    stream: TorrentStream,
+}
+
+impl HttpServer {
+    pub async fn bind(addr: SocketAddr, stream: TorrentStream) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self { listener, stream })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serve clients forever, each of them in its own task.
+    pub async fn run(mut self) {
+        loop {
+            if let Ok((socket, _)) = self.listener.accept().await {
+                let stream = self.stream.clone();
+                // A client that goes away only ends its own connection.
+                tokio::spawn(async move {
+                    let _ = handle_client(socket, stream).await;
+                });
+            }
+        }
+    }
+}
+
+/// Answer one request, the connection is closed afterwards.
+async fn handle_client(mut socket: TcpStream, mut stream: TorrentStream) -> Result<()> {
+    let head = match read_head(&mut socket).await? {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+    let range = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("range"))
+        .map(|(_, value)| value.trim().to_string());
+
+    if method != "GET" && method != "HEAD" {
+        return write_status(&mut socket, "405 Method Not Allowed").await;
+    }
+    let files = stream.get_files();
+    if path == "/" {
+        let listing = files
+            .iter()
+            .enumerate()
+            .map(|(idx, f)| format!("/{} {} {}\n", idx, f.path.display(), f.length))
+            .collect::<String>();
+        let header = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            listing.len()
+        );
+        socket.write_all(header.as_bytes()).await?;
+        if method == "GET" {
+            socket.write_all(listing.as_bytes()).await?;
+        }
+        return Ok(());
+    }
+
+    // "/<index>" or "/<index>/<name>", so players can see the file name.
+    let file = match path.trim_start_matches('/').split('/').next().and_then(|idx| idx.parse::<usize>().ok()) {
+        Some(idx) if idx < files.len() => files[idx].clone(),
+        _ => return write_status(&mut socket, "404 Not Found").await,
+    };
+
+    let (status, start, end) = match range.as_deref() {
+        // Several ranges are not supported, the whole file is sent instead.
+        Some(value) if !value.contains(',') => match parse_range(value, file.length) {
+            Some((start, end)) => ("206 Partial Content", start, end),
+            None => {
+                let header = format!(
+                    "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    file.length
+                );
+                socket.write_all(header.as_bytes()).await?;
+                return Ok(());
+            }
+        },
+        _ => ("200 OK", 0, file.length.saturating_sub(1)),
+    };
+    let body_length = if file.length == 0 { 0 } else { end - start + 1 };
+    let mut header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/octet-stream\r\nAccept-Ranges: bytes\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status, body_length
+    );
+    if status.starts_with("206") {
+        header.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n", start, end, file.length));
+    }
+    header.push_str("\r\n");
+    socket.write_all(header.as_bytes()).await?;
+    if method == "HEAD" {
+        return Ok(());
+    }
+
+    // One piece at a time, the player gets data as soon as each piece is verified.
+    let piece_size = stream.get_piece_size();
+    let mut pos = file.offset + start;
+    let stop = file.offset + start + body_length;
+    while pos < stop {
+        let chunk = std::cmp::min(piece_size - pos % piece_size, stop - pos) as usize;
+        let data = stream.read(pos, chunk).await?;
+        if data.is_empty() {
+            break;
+        }
+        socket.write_all(&data).await?;
+        pos += data.len() as u64;
+    }
+    Ok(())
+}
+
+/// Read the request line and headers, None if the client went away or sent too much.
+async fn read_head(socket: &mut TcpStream) -> Result<Option<String>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST_HEAD {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(String::from_utf8_lossy(&head).into_owned()))
+}
+
+async fn write_status(socket: &mut TcpStream, status: &str) -> Result<()> {
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+    socket.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Parse a single "bytes=start-end" range of a file of `length` bytes.
+/// return: first and last byte (inclusive), None if the range cannot be satisfied.
+fn parse_range(value: &str, length: u64) -> Option<(u64, u64)> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        // Suffix range: the last `end` bytes.
+        let suffix = end.parse::<u64>().ok()?;
+        if suffix == 0 || length == 0 {
+            return None;
+        }
+        return Some((length - std::cmp::min(suffix, length), length - 1));
+    }
+    let start = start.parse::<u64>().ok()?;
+    let end = if end.is_empty() { length.checked_sub(1)? } else { std::cmp::min(end.parse::<u64>().ok()?, length.checked_sub(1)?) };
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::Downloader;
+    use crate::utils::{sample_torrent, TempDir};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=5-1", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    #[tokio::test]
+    async fn range_request_waits_for_pieces() {
+        let (torrent_info, data) = sample_torrent(&[("data.bin", 40000)], 32768);
+        let dir = TempDir::new();
+        let downloader = Arc::new(Mutex::new(Downloader::with_directory(&torrent_info, &dir).unwrap()));
+
+        let server = HttpServer::bind("127.0.0.1:0".parse().unwrap(), TorrentStream::new(downloader.clone()))
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let client = tokio::spawn(async move {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            socket.write_all(b"GET /0 HTTP/1.1\r\nRange: bytes=32760-32775\r\n\r\n").await.unwrap();
+            let mut response = Vec::new();
+            socket.read_to_end(&mut response).await.unwrap();
+            response
+        });
+
+        // The range covers both pieces, nothing comes before they are written.
+        tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+        let all = bit_vec::BitVec::from_elem(2, true);
+        {
+            let mut downloader = downloader.lock().unwrap();
+            while let Some((piece, begin, length)) = downloader.pick_next_block("a", &all) {
+                let start = (piece * 32768 + begin) as usize;
+                downloader.write_block(piece as usize, begin, &data[start..start + length as usize]).unwrap();
+            }
+        }
+
+        let response = client.await.unwrap();
+        let split = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..split]);
+        assert!(head.starts_with("HTTP/1.1 206"));
+        assert!(head.contains("Content-Range: bytes 32760-32775/40000"));
+        assert_eq!(&response[split + 4..], &data[32760..32776]);
+    }
+}
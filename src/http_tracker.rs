@@ -0,0 +1,252 @@
+/*
+ * http_tracker.rs
+ * Announce to HTTP/HTTPS trackers (BEP 3), peers can be compact (BEP 23) or a list of
+ * dictionaries.
+ */
+use serde_bytes::ByteBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use url::Url;
+
+use crate::error::{Error, Result};
+use crate::tracker::{AnnounceParams, AnnounceReply};
+use crate::utils::{parse_compact_v4, parse_compact_v6};
+
+const HTTP_TIMEOUT: u64 = 15; //seconds
+
+#[derive(Debug, Deserialize)]
+struct PeerDict {
+    ip: String,
+    port: u16,
+}
+
+/// Trackers send peers as a compact string when we ask for compact=1, but some of them ignore
+/// it and still send a list of dictionaries.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Peers {
+    Compact(ByteBuf),
+    Dict(Vec<PeerDict>),
+}
+
+#[derive(Debug, Deserialize)]
+struct HttpAnnounceResponse {
+    #[serde(default)]
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default)]
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    #[serde(default)]
+    interval: Option<u32>,
+    #[serde(default)]
+    #[serde(rename = "min interval")]
+    min_interval: Option<u32>,
+    #[serde(default)]
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<String>,
+    #[serde(default)]
+    complete: Option<u32>,
+    #[serde(default)]
+    incomplete: Option<u32>,
+    #[serde(default)]
+    peers: Option<Peers>,
+    #[serde(default)]
+    peers6: Option<ByteBuf>,
+}
+
+/// Percent-encode every byte but the unreserved characters of RFC 3986. Form encoding would
+/// turn 0x20 into '+', which trackers do not read back as a space.
+fn encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Build the GET url of an announce. info_hash and peer_id are raw bytes so they are
+/// url-encoded by hand instead of going through `Url::query_pairs_mut`.
+pub fn build_announce_url(base_url: &Url, params: &AnnounceParams) -> String {
+    let mut url = base_url.to_string();
+    url.push(if base_url.query().is_some() { '&' } else { '?' });
+    url.push_str(&format!(
+        "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1&key={:08x}",
+        encode(&params.info_hash),
+        encode(&params.peer_id),
+        params.port,
+        params.uploaded,
+        params.downloaded,
+        params.left,
+        params.key,
+    ));
+    if params.num_want >= 0 {
+        url.push_str(&format!("&numwant={}", params.num_want));
+    }
+    if let Some(event) = params.event.name() {
+        url.push_str(&format!("&event={}", event));
+    }
+    if let Some(tracker_id) = &params.tracker_id {
+        url.push_str(&format!("&trackerid={}", encode(tracker_id.as_bytes())));
+    }
+    url
+}
+
+/// Parse the bencoded body of an announce response.
+pub fn parse_response(data: &[u8]) -> Result<AnnounceReply> {
+    let response = serde_bencode::from_bytes::<HttpAnnounceResponse>(data)?;
+    if let Some(reason) = response.failure_reason {
+        return Err(Error::TrackerError(reason));
+    }
+
+    let mut peers = match response.peers {
+        Some(Peers::Compact(data)) => parse_compact_v4(&data),
+        Some(Peers::Dict(list)) => list
+            .iter()
+            .filter_map(|p| {
+                let ip = p.ip.parse::<IpAddr>().ok()?;
+                Some(SocketAddr::new(ip, p.port))
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    if let Some(data) = response.peers6 {
+        peers.extend(parse_compact_v6(&data));
+    }
+
+    Ok(AnnounceReply {
+        interval: response.interval.unwrap_or(0),
+        min_interval: response.min_interval,
+        tracker_id: response.tracker_id,
+        warning: response.warning_message,
+        seeders: response.complete.unwrap_or(0),
+        leechers: response.incomplete.unwrap_or(0),
+        peers,
+    })
+}
+
+/// An HTTP tracker, its client keeps connections open between announces.
+pub struct HttpTracker {
+    url: Url,
+    client: reqwest::Client,
+}
+
+impl HttpTracker {
+    pub fn new(url: Url) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(HTTP_TIMEOUT))
+            .build()?;
+        Ok(Self { url, client })
+    }
+
+    pub async fn announce(&self, params: &AnnounceParams) -> Result<AnnounceReply> {
+        let response = self.client.get(&build_announce_url(&self.url, params)).send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        match parse_response(&body) {
+            Err(Error::SerdeBencode(_)) if !status.is_success() => {
+                Err(Error::TrackerError(format!("HTTP status {}", status)))
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::tracker::AnnounceEvent;
+    use tokio::net::TcpListener;
+    use tokio::prelude::*;
+
+    fn sample_params() -> AnnounceParams {
+        AnnounceParams {
+            info_hash: [0xab; 20],
+            peer_id: *b"-OT0001-abcdefghijkl",
+            port: 6881,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: AnnounceEvent::Started,
+            num_want: -1,
+            key: 0x1234,
+            tracker_id: None,
+        }
+    }
+
+    // Answer one request with `body` and return the request line.
+    async fn serve_once(listener: &mut TcpListener, body: &[u8]) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+        stream.write_all(header.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+        String::from_utf8_lossy(&request).lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn announce_url_contains_encoded_fields() {
+        let url = build_announce_url(&Url::parse("http://t.example/announce?passkey=1").unwrap(), &sample_params());
+        assert!(url.starts_with("http://t.example/announce?passkey=1&info_hash=%AB%AB"));
+        assert!(url.contains("&peer_id=-OT0001-abcdefghijkl&port=6881"));
+        assert!(url.contains("&uploaded=1&downloaded=2&left=3&compact=1"));
+        assert!(url.ends_with("&event=started"));
+    }
+
+    #[test]
+    fn only_unreserved_bytes_are_left_as_is() {
+        assert_eq!(encode(b"aZ09-._~"), "aZ09-._~");
+        assert_eq!(encode(&[0x20, b'+', b'%', 0x00, 0xff]), "%20%2B%25%00%FF");
+    }
+
+    #[test]
+    fn parse_dictionary_peers() {
+        let body = b"d8:intervali900e5:peersld2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eeee";
+        let reply = parse_response(body).unwrap();
+        assert_eq!(reply.interval, 900);
+        assert_eq!(reply.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn parse_failure_reason() {
+        match parse_response(b"d14:failure reason9:not founde") {
+            Err(Error::TrackerError(reason)) => assert_eq!(reason, "not found"),
+            _ => panic!("failure reason should be an error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_to_local_tracker() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/announce", listener.local_addr().unwrap())).unwrap();
+
+        let mut body = b"d8:completei5e10:incompletei3e8:intervali1800e12:min intervali60e5:peers12:".to_vec();
+        body.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 192, 168, 1, 2, 0x1a, 0xe2]);
+        body.extend_from_slice(b"10:tracker id3:xyz15:warning message4:slowe");
+
+        let params = sample_params();
+        let tracker = HttpTracker::new(url).unwrap();
+        let server = async { serve_once(&mut listener, &body).await };
+        let (request_line, reply) = futures::join!(server, tracker.announce(&params));
+        assert!(request_line.starts_with("GET /announce?info_hash=%AB"));
+
+        let reply = reply.unwrap();
+        assert_eq!(reply.interval, 1800);
+        assert_eq!(reply.min_interval, Some(60));
+        assert_eq!(reply.tracker_id.as_deref(), Some("xyz"));
+        assert_eq!(reply.warning.as_deref(), Some("slow"));
+        assert_eq!((reply.seeders, reply.leechers), (5, 3));
+        assert_eq!(
+            reply.peers,
+            vec!["10.0.0.1:6881".parse().unwrap(), "192.168.1.2:6882".parse().unwrap()]
+        );
+    }
+}
@@ -7,13 +7,34 @@ extern crate serde_bytes;
 //#[macro_use]
 //extern crate futures;
 extern crate tokio;
+pub mod checker;
+pub mod choker;
+pub mod dht;
+pub mod disk_io;
 pub mod downloader;
 pub mod error;
+pub mod events;
+pub mod extension;
+pub mod http_server;
+pub mod http_tracker;
+pub mod limits;
+pub mod listener;
+pub mod magnet;
 pub mod message;
+pub mod mem_storage;
 pub mod meta_info; //tracker information
+pub mod metadata;
+pub mod mmap_storage;
 pub mod peer;
+pub mod pex;
+pub mod picker;
+pub mod resume;
+pub mod session;
 pub mod signal;
+pub mod storage;
+pub mod stream;
 pub mod torrent_instance;
 pub mod tracker;
+pub mod udp_tracker;
 mod utils;
 mod piece_control;
@@ -0,0 +1,139 @@
+/*
+ * limits.rs
+ * Connection and bandwidth limits shared by every torrent of a session. Rates are token buckets
+ * holding at most one second of data, a peer that goes over the rate waits until the bucket has
+ * refilled. A limit of 0 means unlimited.
+ */
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+/// Bytes per second, 0 for no limit.
+pub struct RateLimiter {
+    rate: AtomicU64,
+    bucket: Mutex<(f64, Instant)>, //tokens (can go below 0), last refill
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    pub fn get_rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Take `bytes` from the bucket.
+    /// return: how long to wait before the bytes may go through.
+    fn consume(&self, bytes: usize) -> Duration {
+        let rate = self.get_rate() as f64;
+        if rate == 0.0 {
+            return Duration::default();
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let (tokens, last) = *bucket;
+        let tokens = (tokens + now.duration_since(last).as_secs_f64() * rate).min(rate) - bytes as f64;
+        *bucket = (tokens, now);
+        if tokens >= 0.0 {
+            Duration::default()
+        } else {
+            Duration::from_secs_f64(-tokens / rate)
+        }
+    }
+
+    /// Wait until `bytes` can be sent or received without going over the rate.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.consume(bytes);
+        if wait > Duration::default() {
+            time::delay_for(wait).await;
+        }
+    }
+}
+
+/// Limits of a session, every torrent and peer shares them.
+pub struct Limits {
+    max_connections: AtomicUsize, //0 for no limit
+    connections: AtomicUsize,
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl Limits {
+    pub fn new(max_connections: usize, download_rate: u64, upload_rate: u64) -> Self {
+        Self {
+            max_connections: AtomicUsize::new(max_connections),
+            connections: AtomicUsize::new(0),
+            download: RateLimiter::new(download_rate),
+            upload: RateLimiter::new(upload_rate),
+        }
+    }
+
+    /// No limit at all, used by a torrent outside of a session.
+    pub fn unlimited() -> Arc<Self> {
+        Arc::new(Self::new(0, 0, 0))
+    }
+
+    pub fn set_max_connections(&self, max_connections: usize) {
+        self.max_connections.store(max_connections, Ordering::Relaxed);
+    }
+
+    /// Number of peer connections open right now.
+    pub fn get_connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Take a connection slot, None when the session has too many connections already.
+    /// The slot is given back when dropped.
+    pub fn try_connect(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        let max = self.max_connections.load(Ordering::Relaxed);
+        let taken = self.connections.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+            if max == 0 || count < max {
+                Some(count + 1)
+            } else {
+                None
+            }
+        });
+        taken.ok().map(|_| ConnectionSlot { limits: self.clone() })
+    }
+}
+
+/// A connection counted by the session, see Limits::try_connect().
+pub struct ConnectionSlot {
+    limits: Arc<Limits>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limits.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn connections_and_rates_are_limited() {
+        let limits = Arc::new(Limits::new(2, 0, 1000));
+        let first = limits.try_connect().unwrap();
+        let _second = limits.try_connect().unwrap();
+        assert!(limits.try_connect().is_none());
+        drop(first);
+        let _third = limits.try_connect().unwrap();
+        assert_eq!(limits.get_connections(), 2);
+
+        // One second of data is free, the next half second has to wait.
+        assert_eq!(limits.upload.consume(1000), Duration::default());
+        assert!(limits.upload.consume(500) > Duration::from_millis(400));
+        assert_eq!(limits.download.consume(1 << 20), Duration::default());
+    }
+}
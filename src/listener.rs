@@ -0,0 +1,245 @@
+/*
+ * listener.rs
+ * Accept connections from other peers, check their handshake and serve them like any peer we
+ * connected to ourselves. One listener serves every torrent of a session, incoming peers are
+ * routed by the info hash of their handshake.
+ */
+use crate::downloader::Downloader;
+use crate::error::{Error, Result};
+use crate::extension::ExtensionFactory;
+use crate::limits::Limits;
+use crate::peer::{read_handshake, HandshakeMsg, Peer};
+use crate::signal::Signal;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time;
+
+/// Port announced to trackers and where we wait for incoming peers.
+pub const LISTEN_PORT: u16 = 6881;
+/// An incoming peer that does not send its handshake by then is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the peers of a torrent go.
+#[derive(Clone)]
+pub struct Route {
+    pub signal_slot: UnboundedSender<Signal>,
+    pub download_mutex: Arc<Mutex<Downloader>>,
+    pub extensions: Option<ExtensionFactory>, //registered on every incoming peer
+}
+
+/// Torrents served by a listener, keyed by info hash. Shared so torrents can be added and
+/// removed while the listener runs.
+pub type Routes = Arc<Mutex<HashMap<[u8; 20], Route>>>;
+
+pub struct Listener {
+    listener: TcpListener,
+    peer_id: [u8; 20],
+    routes: Routes,
+    limits: Arc<Limits>,
+}
+
+impl Listener {
+    pub async fn bind(port: u16, peer_id: [u8; 20]) -> Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+        Ok(Self {
+            listener,
+            peer_id,
+            routes: Arc::new(Mutex::new(HashMap::new())),
+            limits: Limits::unlimited(),
+        })
+    }
+
+    /// Serve the peers of the torrent with `info_hash`.
+    pub fn add_torrent(&self, info_hash: [u8; 20], route: Route) {
+        self.routes.lock().unwrap().insert(info_hash, route);
+    }
+
+    pub fn get_routes(&self) -> Routes {
+        self.routes.clone()
+    }
+
+    /// Incoming peers count against these connection and bandwidth limits.
+    pub fn set_limits(&mut self, limits: Arc<Limits>) {
+        self.limits = limits;
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept peers forever, each of them runs in its own task.
+    pub async fn run(mut self) {
+        let port = self.local_addr().map(|addr| addr.port()).unwrap_or(LISTEN_PORT);
+        loop {
+            let (stream, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => continue, // e.g. too many open files, the next connection may do better.
+            };
+            let slot = match self.limits.try_connect() {
+                Some(slot) => slot,
+                None => continue, // Too many connections, the peer is dropped.
+            };
+            let peer_id = self.peer_id;
+            let routes = self.routes.clone();
+            let limits = self.limits.clone();
+            tokio::spawn(async move {
+                // A failed handshake only concerns this peer.
+                let _ = handle_incoming(stream, addr, routes, peer_id, limits, port).await;
+                drop(slot);
+            });
+        }
+    }
+}
+
+async fn handle_incoming(mut stream: TcpStream, addr: SocketAddr, routes: Routes, peer_id: [u8; 20], limits: Arc<Limits>, port: u16) -> Result<()> {
+    // The remote peer talks first, we only answer if it wants one of our torrents.
+    let remote = match time::timeout(HANDSHAKE_TIMEOUT, read_handshake(&mut stream)).await {
+        Ok(remote) => remote?,
+        Err(_) => return Err(Error::InvalidHandshake("No handshake received in time".to_string())),
+    };
+    let route = routes.lock().unwrap().get(&remote.info_hash).cloned();
+    let route = match route {
+        Some(route) => route,
+        None => return Err(Error::InvalidHandshake("Info hash does not match".to_string())),
+    };
+    stream.write_all(&HandshakeMsg::new(peer_id, remote.info_hash).to_bytes()?).await?;
+
+    let mut peer = Peer::new(&addr.to_string(), route.signal_slot, route.download_mutex);
+    if let Some(extensions) = &route.extensions {
+        for extension in extensions(&addr.to_string()) {
+            peer.register_extension(extension);
+        }
+    }
+    peer.set_limits(limits);
+    peer.set_listen_port(port);
+    peer.set_remote_reserved(&remote.reserved);
+    peer.handle_connection(&mut stream).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checker::HashCheck;
+    use crate::meta_info::TorrentInfo;
+    use crate::peer::PeerCommand;
+    use crate::utils::{sample_torrent, TempDir};
+    use tokio::sync::mpsc;
+
+    // A single 20 bytes file with 16 bytes pieces, written completely on disk and checked.
+    // The directory goes away when the returned TempDir is dropped.
+    async fn seeded_downloader() -> (TorrentInfo, Arc<Mutex<Downloader>>, TempDir) {
+        let (torrent_info, data) = sample_torrent(&[("seed.bin", 20)], 16);
+        let dir = TempDir::new();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("seed.bin"), &data).unwrap();
+        let downloader = Arc::new(Mutex::new(Downloader::with_directory(&torrent_info, &dir).unwrap()));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        HashCheck::start(downloader.clone(), 1, tx).unwrap();
+        assert!(matches!(rx.recv().await, Some(Signal::CheckFinished)));
+        (torrent_info, downloader, dir)
+    }
+
+    async fn seed_listener(info_hash: [u8; 20], signal_slot: UnboundedSender<Signal>, download_mutex: Arc<Mutex<Downloader>>) -> Listener {
+        let listener = Listener::bind(0, [1; 20]).await.unwrap();
+        listener.add_torrent(
+            info_hash,
+            Route {
+                signal_slot,
+                download_mutex,
+                extensions: None,
+            },
+        );
+        listener
+    }
+
+    #[tokio::test]
+    async fn serve_requests_from_incoming_peer() {
+        let (torrent_info, downloader, _dir) = seeded_downloader().await;
+        let info_hash = torrent_info.get_info_hash();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let listener = seed_listener(info_hash, tx, downloader).await;
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(listener.run());
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        // Without the extension bit, so the bitfield is the only message before unchoke.
+        let mut hsm = HandshakeMsg::new([2; 20], info_hash);
+        hsm.reserved = [0; 8];
+        stream.write_all(&hsm.to_bytes().unwrap()).await.unwrap();
+        let remote = read_handshake(&mut stream).await.unwrap();
+        assert_eq!(remote.peer_id, [1; 20]);
+        assert!(crate::extension::supports_extensions(&remote.reserved));
+
+        // bitfield with both pieces
+        let mut buf = [0u8; 6];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 2, 5, 0b1100_0000]);
+
+        // interested, then the choker unchokes us
+        stream.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+        match rx.recv().await {
+            Some(Signal::PeerConnected(handle)) => handle.command_slot.send(PeerCommand::UnChoke).unwrap(),
+            _ => panic!("peer should be registered first"),
+        }
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 1, 1]);
+
+        // request 2 bytes at offset 2 of the last piece
+        stream.write_all(&[0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2]).await.unwrap();
+        let mut buf = [0u8; 15];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 11, 7, 0, 0, 0, 1, 0, 0, 0, 2, 18, 19]);
+    }
+
+    #[tokio::test]
+    async fn fast_peer_gets_have_all_and_allowed_fast_pieces() {
+        let (torrent_info, downloader, _dir) = seeded_downloader().await;
+        let info_hash = torrent_info.get_info_hash();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let listener = seed_listener(info_hash, tx, downloader).await;
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(listener.run());
+
+        // Only the fast extension bit.
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut hsm = HandshakeMsg::new([2; 20], info_hash);
+        hsm.reserved = [0, 0, 0, 0, 0, 0, 0, 0x04];
+        stream.write_all(&hsm.to_bytes().unwrap()).await.unwrap();
+        read_handshake(&mut stream).await.unwrap();
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 1, 14]);
+        // With two pieces, both are in the allowed fast set.
+        let mut buf = [0u8; 18];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!((buf[4], buf[13]), (17, 17));
+
+        // Still choked, but the request is served.
+        stream.write_all(&[0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2]).await.unwrap();
+        let mut buf = [0u8; 15];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 11, 7, 0, 0, 0, 1, 0, 0, 0, 2, 18, 19]);
+    }
+
+    #[tokio::test]
+    async fn reject_unknown_info_hash() {
+        let (torrent_info, downloader, _dir) = seeded_downloader().await;
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let listener = seed_listener(torrent_info.get_info_hash(), tx, downloader).await;
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(listener.run());
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(&HandshakeMsg::new([2; 20], [3; 20]).to_bytes().unwrap()).await.unwrap();
+        let mut buf = [0u8; 68];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,137 @@
+/*
+ * magnet.rs
+ * Parse magnet links (BEP 9):
+ * magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>&ws=<web seed>&x.pe=<peer address>
+ * The info hash is either 40 hex characters or 32 base32 characters.
+ */
+use std::net::SocketAddr;
+use url::Url;
+
+use crate::error::{Error, Result};
+
+const BTIH_PREFIX: &str = "urn:btih:";
+
+#[derive(Debug)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    pub web_seeds: Vec<String>,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    pub fn parse(link: &str) -> Result<Self> {
+        let url = Url::parse(link)?;
+        if url.scheme() != "magnet" {
+            return Err(Error::InvalidMagnet(format!("Not a magnet link: {}", link)));
+        }
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut web_seeds = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" if value.len() > BTIH_PREFIX.len()
+                    && value.get(..BTIH_PREFIX.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(BTIH_PREFIX)) =>
+                {
+                    let hash = &value[BTIH_PREFIX.len()..];
+                    info_hash = match hash.len() {
+                        40 => decode_hex(hash),
+                        32 => decode_base32(hash),
+                        _ => None,
+                    };
+                }
+                "dn" => display_name = Some(value.to_string()),
+                "tr" => trackers.push(value.to_string()),
+                "ws" => web_seeds.push(value.to_string()),
+                "x.pe" => {
+                    // Only ip:port peers, there is nothing to resolve a hostname here.
+                    if let Ok(addr) = value.parse::<SocketAddr>() {
+                        peers.push(addr);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let info_hash = info_hash.ok_or_else(|| Error::InvalidMagnet("Missing or invalid btih".to_string()))?;
+        Ok(Self {
+            info_hash,
+            display_name,
+            trackers,
+            web_seeds,
+            peers,
+        })
+    }
+}
+
+fn decode_hex(s: &str) -> Option<[u8; 20]> {
+    let mut hash = [0u8; 20];
+    for (idx, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(idx * 2..idx * 2 + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+fn decode_base32(s: &str) -> Option<[u8; 20]> {
+    let mut hash = [0u8; 20];
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+    let mut pos = 0;
+    for c in s.bytes() {
+        let val = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | val as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            hash[pos] = (buffer >> bits) as u8;
+            pos += 1;
+        }
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MagnetLink;
+
+    #[test]
+    fn parse_hex_magnet() {
+        let link = MagnetLink::parse(
+            "magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c&dn=Big+Buck+Bunny\
+             &tr=udp%3A%2F%2Fexplodie.org%3A6969&tr=http%3A%2F%2Ft.example%2Fannounce\
+             &ws=https%3A%2F%2Fwebtorrent.io%2Ftorrents%2F&x.pe=10.0.0.1:6881",
+        )
+        .unwrap();
+        assert_eq!(link.info_hash[..4], [0xdd, 0x82, 0x55, 0xec]);
+        assert_eq!(link.display_name.as_deref(), Some("Big Buck Bunny"));
+        assert_eq!(link.trackers, vec!["udp://explodie.org:6969", "http://t.example/announce"]);
+        assert_eq!(link.web_seeds, vec!["https://webtorrent.io/torrents/"]);
+        assert_eq!(link.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn parse_base32_magnet() {
+        let hex = MagnetLink::parse("magnet:?xt=urn:btih:dd8255ecdc7ca55fb0bbf81323d87062db1f6d1c").unwrap();
+        let base32 = MagnetLink::parse("magnet:?xt=urn:btih:3WBFL3G4PSSV7MF37AJSHWDQMLNR63I4").unwrap();
+        assert_eq!(hex.info_hash, base32.info_hash);
+    }
+
+    #[test]
+    fn reject_missing_info_hash() {
+        assert!(MagnetLink::parse("magnet:?dn=nothing").is_err());
+    }
+
+    #[test]
+    fn reject_multibyte_xt() {
+        // The prefix length falls inside a multibyte character.
+        assert!(MagnetLink::parse("magnet:?xt=urn:bt%C3%A9%C3%A9").is_err());
+    }
+}
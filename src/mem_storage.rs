@@ -0,0 +1,68 @@
+/*
+ * mem_storage.rs
+ * Storage backend keeping the whole torrent in memory, for tests and simulations. Nothing
+ * survives the process, so resume data is never used with it.
+ */
+use crate::error::Result;
+use crate::meta_info::TorrentInfo;
+use crate::resume::FileStamp;
+use crate::storage::{FileLayout, Storage};
+
+use std::path::Path;
+
+pub struct MemStorage {
+    layout: FileLayout,
+    data: Vec<u8>, //the whole piece stream
+}
+
+impl MemStorage {
+    pub fn new(torrent_info: &TorrentInfo) -> Self {
+        let layout = FileLayout::new(torrent_info);
+        let data = vec![0u8; layout.get_total_length() as usize];
+        Self { layout, data }
+    }
+
+    /// Clamp a range to the end of the torrent.
+    fn range(&self, piece_idx: usize, begin: u32, length: usize) -> (usize, usize) {
+        let start = std::cmp::min(self.layout.get_offset(piece_idx, begin) as usize, self.data.len());
+        (start, std::cmp::min(start + length, self.data.len()))
+    }
+}
+
+impl Storage for MemStorage {
+    fn get_layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn read_block(&mut self, piece_idx: usize, begin: u32, length: usize) -> Result<Vec<u8>> {
+        let (start, end) = self.range(piece_idx, begin, length);
+        Ok(self.data[start..end].to_vec())
+    }
+
+    fn write_block(&mut self, piece_idx: usize, begin: u32, data: &[u8]) -> Result<()> {
+        let (start, end) = self.range(piece_idx, begin, data.len());
+        self.data[start..end].copy_from_slice(&data[..end - start]);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn move_to(&mut self, _base_dir: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete(&mut self) -> Result<()> {
+        self.data.iter_mut().for_each(|byte| *byte = 0);
+        Ok(())
+    }
+
+    fn get_file_stamps(&self) -> Result<Vec<FileStamp>> {
+        Ok(Vec::new())
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
@@ -1,14 +1,8 @@
 use bit_vec::BitVec;
-use bytes::{BufMut, BytesMut};
-use tokio::io::AsyncRead;
-use tokio::io::{self, BufReader};
-use tokio::prelude::*;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io;
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::error::{Error, Result};
-
-const PIECE_MSG_PREFIX_LENGTH: usize = 8;
-
 #[derive(Debug)]
 pub enum MessagePlayload {
     Have(u32),                // <piece index>
@@ -17,6 +11,12 @@ pub enum MessagePlayload {
     Piece(u32, u32, Vec<u8>), //<index><begin><data block>
     Cancel(u32, u32, u32),    //<index><begin><length>
     Port(u16),                //<port>
+    Extended(u8, Vec<u8>),    //<extended message id><payload> (BEP 10)
+    SuggestPiece(u32),        //<piece index> (BEP 6)
+    RejectRequest(u32, u32, u32), //<index><begin><length> (BEP 6)
+    AllowedFast(u32),         //<piece index> (BEP 6)
+    HaveAll,
+    HaveNone,
     Choke,
     UnChoke,
     Interest,
@@ -48,6 +48,12 @@ impl MessageCodec {
     }
 }
 
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Encoder for MessageCodec {
     type Item = Message;
     type Error = io::Error;
@@ -83,6 +89,18 @@ impl Encoder for MessageCodec {
                 MessagePlayload::Port(port) => {
                     buf.put_u16(port);
                 }
+                MessagePlayload::Extended(ext_id, payload) => {
+                    buf.put_u8(ext_id);
+                    buf.put(&payload[..]);
+                }
+                MessagePlayload::SuggestPiece(pie_index) | MessagePlayload::AllowedFast(pie_index) => {
+                    buf.put_u32(pie_index);
+                }
+                MessagePlayload::RejectRequest(index, begin, length) => {
+                    buf.put_u32(index);
+                    buf.put_u32(begin);
+                    buf.put_u32(length);
+                }
                 _ => { /*Do nothing*/ } //Choke, Unchoke, Interest and Non-interest don't have payload.
             }
         }
@@ -108,14 +126,14 @@ impl Decoder for MessageCodec {
                 let len = u32::from_be_bytes(msg_len) as usize;
                 match len {
                     0 => {
-                        buf.split_to(4);
+                        buf.advance(4);
                         return Ok(Some(Message::new(0, None, MessagePlayload::Empty)));
                     },
                     _ if buf.len() < 5 => {
                         return Ok(None);
                     },
                     _ => {
-                        buf.split_to(4);
+                        buf.advance(4);
                         let id = buf.split_to(1);
                         (id[0], len)
                     }
@@ -123,7 +141,19 @@ impl Decoder for MessageCodec {
             }
         };
 
-        
+
+        // Fast extension messages have a fixed size, anything else is a broken peer.
+        let expected_len = match id {
+            13 | 17 => Some(5),
+            14 | 15 => Some(1),
+            16 => Some(13),
+            _ => None,
+        };
+        if expected_len.map(|expected| expected != len).unwrap_or(false) {
+            self.id = None;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad length {} for message {}", len, id)));
+        }
+
         //Only process if we had received data fully.
         if len - 1 > buf.len() {
             self.id = Some(id);
@@ -148,12 +178,18 @@ impl Decoder for MessageCodec {
                     MessagePlayload::UnChoke,
                 )))
             }
-            raw_id @ 2..=3 => {
-                //No payload team
+            raw_id @ 2 => {
+                Ok(Some(Message::new(
+                    len,
+                    Some(raw_id),
+                    MessagePlayload::Interest,
+                )))
+            }
+            raw_id @ 3 => {
                 Ok(Some(Message::new(
                     len,
                     Some(raw_id),
-                    MessagePlayload::Empty,
+                    MessagePlayload::NotInterest,
                 )))
             }
             raw_id @ 4 => {
@@ -241,7 +277,125 @@ impl Decoder for MessageCodec {
                     MessagePlayload::Port(port),
                 )))
             }
-            _ => Ok(None),
+
+            raw_id @ 13 => {
+                // Suggest piece
+                let mut temp: [u8; 4] = std::default::Default::default();
+                temp.copy_from_slice(&buf.split_to(len - 1));
+                Ok(Some(Message::new(
+                    len,
+                    Some(raw_id),
+                    MessagePlayload::SuggestPiece(u32::from_be_bytes(temp)),
+                )))
+            }
+            raw_id @ 14 => {
+                Ok(Some(Message::new(
+                    len,
+                    Some(raw_id),
+                    MessagePlayload::HaveAll,
+                )))
+            }
+            raw_id @ 15 => {
+                Ok(Some(Message::new(
+                    len,
+                    Some(raw_id),
+                    MessagePlayload::HaveNone,
+                )))
+            }
+            raw_id @ 16 => {
+                // Reject request - same layout as Request
+                let mut temp: [u8; 4] = std::default::Default::default();
+                temp.copy_from_slice(&buf.split_to(4));
+                let index = u32::from_be_bytes(temp);
+
+                temp.copy_from_slice(&buf.split_to(4));
+                let begin = u32::from_be_bytes(temp);
+
+                temp.copy_from_slice(&buf.split_to(4));
+                let length = u32::from_be_bytes(temp);
+
+                Ok(Some(Message::new(
+                    len,
+                    Some(raw_id),
+                    MessagePlayload::RejectRequest(index, begin, length),
+                )))
+            }
+            raw_id @ 17 => {
+                // Allowed fast
+                let mut temp: [u8; 4] = std::default::Default::default();
+                temp.copy_from_slice(&buf.split_to(len - 1));
+                Ok(Some(Message::new(
+                    len,
+                    Some(raw_id),
+                    MessagePlayload::AllowedFast(u32::from_be_bytes(temp)),
+                )))
+            }
+
+            raw_id @ 20 => {
+                // Extension protocol: first byte is the extended message id.
+                let data = buf.split_to(len - 1);
+                if data.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty extended message"));
+                }
+                Ok(Some(Message::new(
+                    len,
+                    Some(raw_id),
+                    MessagePlayload::Extended(data[0], data[1..].to_vec()),
+                )))
+            }
+            _ => {
+                // Unknown message: drop its payload and go on with the next one.
+                buf.advance(len - 1);
+                self.decode(buf)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_unknown_message() {
+        let mut codec = MessageCodec::new();
+        // Unknown id 42 with 3 bytes of payload, then an extended handshake and an unchoke.
+        let mut buf = BytesMut::from(&[0, 0, 0, 4, 42, 1, 2, 3, 0, 0, 0, 4, 20, 0, b'd', b'e', 0, 0, 0, 1, 1][..]);
+        match codec.decode(&mut buf).unwrap() {
+            Some(Message { payload: MessagePlayload::Extended(0, payload), .. }) => assert_eq!(payload, b"de"),
+            _ => panic!("extended message expected"),
+        }
+        assert!(matches!(codec.decode(&mut buf).unwrap(), Some(Message { payload: MessagePlayload::UnChoke, .. })));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn fast_extension_round_trip() {
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Message::new(13, Some(16), MessagePlayload::RejectRequest(1, 16384, 16384)), &mut buf).unwrap();
+        codec.encode(Message::new(1, Some(14), MessagePlayload::HaveAll), &mut buf).unwrap();
+        codec.encode(Message::new(5, Some(17), MessagePlayload::AllowedFast(7)), &mut buf).unwrap();
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Message { payload: MessagePlayload::RejectRequest(1, 16384, 16384), .. })
+        ));
+        assert!(matches!(codec.decode(&mut buf).unwrap(), Some(Message { payload: MessagePlayload::HaveAll, .. })));
+        assert!(matches!(codec.decode(&mut buf).unwrap(), Some(Message { payload: MessagePlayload::AllowedFast(7), .. })));
+    }
+
+    #[test]
+    fn fast_extension_bad_length_is_rejected() {
+        // Suggest piece with 2 bytes instead of 4.
+        let mut buf = BytesMut::from(&[0, 0, 0, 3, 13, 0, 1][..]);
+        match MessageCodec::new().decode(&mut buf) {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+            Ok(_) => panic!("bad length accepted"),
         }
+        // Reject request one byte short, and have all with a payload.
+        let mut buf = BytesMut::from(&[0, 0, 0, 12, 16, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0][..]);
+        assert!(MessageCodec::new().decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&[0, 0, 0, 2, 14, 0][..]);
+        assert!(MessageCodec::new().decode(&mut buf).is_err());
     }
 }
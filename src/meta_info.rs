@@ -1,10 +1,10 @@
-use bincode::serialize;
 use serde_bencode::de;
+use serde_bencode::value::Value;
 use serde_bytes::ByteBuf;
 use sha1::Sha1;
 use std::fs::File as FsFile;
-use std::io::prelude::*;
-use std::io::{self, Read};
+use std::io::Read;
+use std::path::{Component, Path};
 
 use crate::error::{Error, Result};
 
@@ -62,13 +62,23 @@ pub struct TorrentInfo {
     #[serde(default)]
     #[serde(rename = "created by")]
     created_by: Option<String>,
+    // Hash of the info dictionary as it was received, so keys we do not know about are
+    // part of it too.
+    #[serde(skip)]
+    info_hash: [u8; 20],
+}
+
+/// A path component names one file or directory: no "..", root, drive prefix or separator.
+fn is_safe_component(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(first)), None) if first == name)
 }
 
 pub fn render_torrent(torrent: &TorrentInfo) {
     println!("name:\t\t{}", torrent.info.name);
     println!("announce:\t{:?}", torrent.announce);
     println!("nodes:\t\t{:?}", torrent.nodes);
-    if let &Some(ref al) = &torrent.announce_list {
+    if let Some(al) = &torrent.announce_list {
         for a in al {
             println!("announce list:\t{}", a[0]);
         }
@@ -83,7 +93,7 @@ pub fn render_torrent(torrent: &TorrentInfo) {
     println!("root hash:\t{:?}", torrent.info.root_hash);
     println!("md5sum:\t\t{:?}", torrent.info.md5sum);
     println!("path:\t\t{:?}", torrent.info.path);
-    if let &Some(ref files) = &torrent.info.files {
+    if let Some(files) = &torrent.info.files {
         for f in files {
             println!("file path:\t{:?}", f.path);
             println!("file length:\t{}", f.length);
@@ -99,27 +109,96 @@ impl TorrentInfo {
         //Normal meta info file should have a small size.
         let mut buffer: Vec<u8> = Vec::new();
         file.read_to_end(&mut buffer)?;
-        let res = de::from_bytes::<TorrentInfo>(&buffer)?;
+        Self::from_bytes(&buffer)
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Result<TorrentInfo> {
+        let mut res = de::from_bytes::<TorrentInfo>(buffer)?;
+        let info = match de::from_bytes::<Value>(buffer)? {
+            Value::Dict(mut root) => root.remove(&b"info"[..]),
+            _ => None,
+        };
+        let info = info.ok_or_else(|| Error::NotSupportProtocol("Missing info dictionary".to_string()))?;
+        res.info_hash = Sha1::from(&serde_bencode::to_bytes(&info)?).digest().bytes();
+        res.check_paths()?;
         Ok(res)
     }
 
+    /// Build meta info from a raw info dictionary, e.g. one downloaded from peers (BEP 9),
+    /// and the trackers we know about.
+    pub fn from_info_bytes(info_bytes: &[u8], announce_tiers: Vec<Vec<String>>) -> Result<TorrentInfo> {
+        let info = de::from_bytes::<Info>(info_bytes)?;
+        let res = TorrentInfo {
+            info,
+            announce: announce_tiers.iter().flatten().next().cloned(),
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            announce_list: Some(announce_tiers),
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            info_hash: Sha1::from(info_bytes).digest().bytes(),
+        };
+        res.check_paths()?;
+        Ok(res)
+    }
+
+    /// Every file must stay inside the download directory, whatever the torrent says.
+    fn check_paths(&self) -> Result<()> {
+        for file in self.get_files() {
+            // Files of a multi-file torrent are nested under its name, an empty path is that directory.
+            let empty = self.info.files.is_some() && file.path.len() < 2;
+            if empty || !file.path.iter().all(|name| is_safe_component(name)) {
+                return Err(Error::UnsafePath(file.path.join("/")));
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_announce(&self) -> Vec<&str> {
         match &self.announce_list {
             Some(list) => {
                 list.iter().flat_map(|array| array.iter()).map(|s| s.as_str()).collect()
             },
             _ => match &self.announce {
-                Some(s) => vec![&s],
+                Some(s) => vec![s],
                 _ => Vec::new(),
             }
         }
     }
 
+    /// Return announce urls grouped by tier (BEP 12). A torrent without announce-list has a
+    /// single tier holding its announce url.
+    pub fn get_announce_tiers(&self) -> Vec<Vec<&str>> {
+        match &self.announce_list {
+            Some(list) => list
+                .iter()
+                .map(|tier| tier.iter().map(|s| s.as_str()).collect::<Vec<&str>>())
+                .filter(|tier| !tier.is_empty())
+                .collect(),
+            _ => match &self.announce {
+                Some(s) => vec![vec![s]],
+                _ => Vec::new(),
+            },
+        }
+    }
+
+    /// DHT nodes listed in the torrent, as "host:port" strings.
+    pub fn get_nodes(&self) -> Vec<String> {
+        match &self.nodes {
+            Some(nodes) => nodes.iter().map(|Node(host, port)| format!("{}:{}", host, port)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Private torrents (BEP 27) only get peers from their trackers.
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
     pub fn get_info_hash(&self) -> [u8; 20] {
-        //let encoded_pkt: Vec<u8> = bincode::config().big_endian().serialize(&self.info).unwrap();
-        let encoded_pkt = serde_bencode::to_bytes(&self.info).unwrap();
-        let hash_entity = Sha1::from(&encoded_pkt);
-        hash_entity.digest().bytes()
+        self.info_hash
     }
 
     pub fn get_number_of_pieces(&self) -> usize {
@@ -141,7 +220,7 @@ impl TorrentInfo {
     }
 
     pub fn get_total_length(&self) -> i64 {
-        let total_length = match self.info.length {
+        match self.info.length {
             Some(val) => val,
             None => {
                 //It means we are having multiple files
@@ -150,10 +229,39 @@ impl TorrentInfo {
                     None => 0,
                 }
             }
-        };
-        total_length
+        }
     }
-    
+
+    /// Return the nominal piece length, every piece except the last one has this size.
+    pub fn get_piece_size(&self) -> u64 {
+        self.info.piece_length as u64
+    }
+
+    /// Return all files of this torrent in the order they appear in the piece stream.
+    /// Paths are relative to the download directory, so a multi-file torrent has its
+    /// files nested under a directory named after the torrent.
+    pub fn get_files(&self) -> Vec<File> {
+        match &self.info.files {
+            Some(files) => files
+                .iter()
+                .map(|f| {
+                    let mut path = vec![self.info.name.clone()];
+                    path.extend(f.path.iter().cloned());
+                    File {
+                        path,
+                        length: f.length,
+                        md5sum: f.md5sum.clone(),
+                    }
+                })
+                .collect(),
+            None => vec![File {
+                path: vec![self.info.name.clone()],
+                length: self.get_total_length(),
+                md5sum: self.info.md5sum.clone(),
+            }],
+        }
+    }
+
     /// Return how many bytes a piece is holding
     pub fn get_piece_length(&self, piece_idx: usize) -> u32 {
         (if (piece_idx + 1) != self.get_number_of_pieces() {
@@ -167,8 +275,7 @@ impl TorrentInfo {
     pub fn get_piece_hash(&self, piece_idx: usize) -> [u8; 20] {
         self.info.pieces.chunks(20).skip(piece_idx).take(1).map(|v| {
             let mut array = [0; 20];
-            let bytes = &v[..v.len()];
-            array.copy_from_slice(bytes);
+            array.copy_from_slice(v);
             array
         }).next().unwrap_or([0u8; 20])
     }
@@ -178,3 +285,37 @@ impl TorrentInfo {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A multi-file torrent named `name` with one file at `path`, given as bencoded strings.
+    fn torrent_with_path(name: &str, path: &str) -> Vec<u8> {
+        format!(
+            "d4:infod5:filesld6:lengthi5e4:pathl{}eee4:name{}:{}12:piece lengthi16384e6:pieces20:{}ee",
+            path,
+            name.len(),
+            name,
+            "x".repeat(20)
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn paths_escaping_the_download_directory_are_rejected() {
+        assert!(TorrentInfo::from_bytes(&torrent_with_path("data", "1:a1:b")).is_ok());
+        for (name, path) in [("data", "2:..4:evil"), ("data", "9:/etc/evil"), ("data", "0:"), ("data", "3:a/b"), ("..", "4:evil"), ("/", "4:evil"), ("data", "")] {
+            match TorrentInfo::from_bytes(&torrent_with_path(name, path)) {
+                Err(Error::UnsafePath(_)) => {}
+                other => panic!("{}/{} accepted: {:?}", name, path, other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn metadata_from_peers_is_checked_too() {
+        let info = b"d5:filesld6:lengthi5e4:pathl2:..4:evileee4:name4:data12:piece lengthi16384e6:pieces20:xxxxxxxxxxxxxxxxxxxxe";
+        assert!(matches!(TorrentInfo::from_info_bytes(info, Vec::new()), Err(Error::UnsafePath(_))));
+    }
+}
@@ -0,0 +1,222 @@
+/*
+ * metadata.rs
+ * Download the info dictionary of a torrent from a peer (BEP 9, ut_metadata). Metadata is
+ * split into 16KiB pieces which are requested through the extension protocol, the result is
+ * checked against the info hash before it is used.
+ */
+use crate::error::{Error, Result};
+use crate::extension::{extended_message, supports_extensions, ExtendedHandshake, EXTENDED_HANDSHAKE_ID};
+use crate::message::{MessageCodec, MessagePlayload};
+use crate::peer::{read_handshake, HandshakeMsg};
+
+use futures::stream::{self, StreamExt};
+use futures_util::sink::SinkExt;
+use sha1::Sha1;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::time::timeout;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+pub const METADATA_PIECE_SIZE: usize = 16384;
+/// Extended message id we ask peers to use when they send us ut_metadata messages.
+pub const UT_METADATA_ID: u8 = 1;
+/// Refuse metadata bigger than this, a peer could make us allocate anything otherwise.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+/// Give up on a peer that has not sent the whole metadata after this long.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of peers asked at the same time.
+const FETCH_CONNECTIONS: usize = 8;
+
+const MSG_REQUEST: u8 = 0;
+const MSG_DATA: u8 = 1;
+const MSG_REJECT: u8 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataMsg {
+    msg_type: u8,
+    piece: u32,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+/// Metadata pieces received so far.
+struct MetadataBuffer {
+    data: Vec<u8>,
+    received: Vec<bool>,
+}
+
+impl MetadataBuffer {
+    fn new(size: usize) -> Self {
+        Self {
+            data: vec![0u8; size],
+            received: vec![false; size.div_ceil(METADATA_PIECE_SIZE)],
+        }
+    }
+
+    fn get_piece_size(&self, piece: usize) -> usize {
+        std::cmp::min(METADATA_PIECE_SIZE, self.data.len() - piece * METADATA_PIECE_SIZE)
+    }
+
+    /// Store a data message, the piece itself follows the bencoded dictionary.
+    fn add_piece(&mut self, piece: usize, payload: &[u8]) -> Result<()> {
+        if piece >= self.received.len() {
+            return Err(Error::InvalidMetadata(format!("Unexpected piece {}", piece)));
+        }
+        let size = self.get_piece_size(piece);
+        if payload.len() < size {
+            return Err(Error::InvalidMetadata(format!("Piece {} is too short", piece)));
+        }
+        let start = piece * METADATA_PIECE_SIZE;
+        self.data[start..start + size].copy_from_slice(&payload[payload.len() - size..]);
+        self.received[piece] = true;
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|&received| received)
+    }
+}
+
+/// Connect to `addr` and download the info dictionary of `info_hash`.
+pub async fn fetch_metadata(addr: SocketAddr, peer_id: [u8; 20], info_hash: [u8; 20]) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(&HandshakeMsg::new(peer_id, info_hash).to_bytes()?).await?;
+
+    let remote = read_handshake(&mut stream).await?;
+    if remote.info_hash != info_hash {
+        return Err(Error::InvalidHandshake("Info hash does not match".to_string()));
+    }
+    if !supports_extensions(&remote.reserved) {
+        return Err(Error::InvalidMetadata("Peer does not support extensions".to_string()));
+    }
+
+    let (r, w) = stream.split();
+    let mut reader = FramedRead::new(r, MessageCodec::new());
+    let mut writer = FramedWrite::new(w, MessageCodec::new());
+
+    let mut handshake = ExtendedHandshake::default();
+    handshake.m.insert("ut_metadata".to_string(), UT_METADATA_ID as i64);
+    writer.send(extended_message(EXTENDED_HANDSHAKE_ID, serde_bencode::to_bytes(&handshake)?)).await?;
+
+    let mut buffer: Option<MetadataBuffer> = None;
+    while let Some(received) = reader.next().await {
+        match received?.payload {
+            MessagePlayload::Extended(EXTENDED_HANDSHAKE_ID, payload) => {
+                let remote_handshake = serde_bencode::from_bytes::<ExtendedHandshake>(&payload)?;
+                let remote_id = remote_handshake
+                    .get_id("ut_metadata")
+                    .ok_or_else(|| Error::InvalidMetadata("Peer does not support ut_metadata".to_string()))?;
+                let size = match remote_handshake.metadata_size {
+                    Some(size) if size > 0 && (size as usize) <= MAX_METADATA_SIZE => size as usize,
+                    _ => return Err(Error::InvalidMetadata("Invalid metadata size".to_string())),
+                };
+
+                // Ask for every piece at once, they are small.
+                let new_buffer = MetadataBuffer::new(size);
+                for piece in 0..new_buffer.received.len() {
+                    let request = MetadataMsg {
+                        msg_type: MSG_REQUEST,
+                        piece: piece as u32,
+                        total_size: None,
+                    };
+                    writer.send(extended_message(remote_id, serde_bencode::to_bytes(&request)?)).await?;
+                }
+                buffer = Some(new_buffer);
+            }
+            MessagePlayload::Extended(UT_METADATA_ID, payload) => {
+                let buffer = match &mut buffer {
+                    Some(buffer) => buffer,
+                    None => continue,
+                };
+                let msg = serde_bencode::from_bytes::<MetadataMsg>(&payload)?;
+                match msg.msg_type {
+                    MSG_DATA => buffer.add_piece(msg.piece as usize, &payload)?,
+                    MSG_REJECT => {
+                        return Err(Error::InvalidMetadata(format!("Piece {} has been rejected", msg.piece)));
+                    }
+                    _ => {}
+                }
+
+                if buffer.is_complete() {
+                    if Sha1::from(&buffer.data).digest().bytes() != info_hash {
+                        return Err(Error::InvalidMetadata("Info hash does not match".to_string()));
+                    }
+                    return Ok(std::mem::take(&mut buffer.data));
+                }
+            }
+            _ => {
+                // Normal peer wire messages are not interesting here.
+            }
+        }
+    }
+    Err(Error::InvalidMetadata(format!("{} closed the connection", addr)))
+}
+
+/// Ask peers for the metadata, a few at a time, until one of them sends it.
+pub async fn fetch_from_peers(peers: Vec<SocketAddr>, peer_id: [u8; 20], info_hash: [u8; 20]) -> Result<Vec<u8>> {
+    let mut attempts = stream::iter(peers)
+        .map(|addr| timeout(FETCH_TIMEOUT, fetch_metadata(addr, peer_id, info_hash)))
+        .buffer_unordered(FETCH_CONNECTIONS);
+    while let Some(result) = attempts.next().await {
+        // Peers that fail or time out are skipped, the next one may have it.
+        if let Ok(Ok(metadata)) = result {
+            return Ok(metadata);
+        }
+    }
+    Err(Error::InvalidMetadata("No peer sent the metadata".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn fetch_metadata_from_peer() {
+        // 20000 bytes of metadata: two pieces, the last one is short.
+        let metadata: Vec<u8> = (0..20000u32).map(|i| i as u8).collect();
+        let info_hash = Sha1::from(&metadata).digest().bytes();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stand_in = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_handshake(&mut stream).await.unwrap();
+            stream.write_all(&HandshakeMsg::new([9; 20], info_hash).to_bytes().unwrap()).await.unwrap();
+
+            let (r, w) = stream.split();
+            let mut reader = FramedRead::new(r, MessageCodec::new());
+            let mut writer = FramedWrite::new(w, MessageCodec::new());
+            let mut handshake = ExtendedHandshake::default();
+            handshake.m.insert("ut_metadata".to_string(), 3);
+            handshake.metadata_size = Some(metadata.len() as i64);
+            writer.send(extended_message(0, serde_bencode::to_bytes(&handshake).unwrap())).await.unwrap();
+
+            while let Some(Ok(msg)) = reader.next().await {
+                if let MessagePlayload::Extended(3, payload) = msg.payload {
+                    let request = serde_bencode::from_bytes::<MetadataMsg>(&payload).unwrap();
+                    let start = request.piece as usize * METADATA_PIECE_SIZE;
+                    let end = std::cmp::min(start + METADATA_PIECE_SIZE, metadata.len());
+                    let reply = MetadataMsg {
+                        msg_type: MSG_DATA,
+                        piece: request.piece,
+                        total_size: Some(metadata.len() as i64),
+                    };
+                    let mut data = serde_bencode::to_bytes(&reply).unwrap();
+                    data.extend_from_slice(&metadata[start..end]);
+                    writer.send(extended_message(UT_METADATA_ID, data)).await.unwrap();
+                }
+            }
+        };
+
+        let fetched = tokio::select! {
+            _ = stand_in => panic!("stand-in peer stopped first"),
+            fetched = fetch_metadata(addr, [1; 20], info_hash) => fetched.unwrap(),
+        };
+        assert_eq!(fetched, metadata);
+    }
+}
@@ -0,0 +1,121 @@
+/*
+ * mmap_storage.rs
+ * Storage backend mapping every file of the torrent in memory. Files are created with their
+ * final size when the storage is opened, the kernel writes dirty pages back on its own and
+ * flush() forces it.
+ */
+use crate::error::Result;
+use crate::meta_info::TorrentInfo;
+use crate::resume::FileStamp;
+use crate::storage::{delete_files, move_files, FileLayout, Storage};
+
+use memmap::MmapMut;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+pub struct MmapStorage {
+    layout: FileLayout,
+    base_dir: PathBuf,
+    files: Vec<Option<(File, MmapMut)>>, //None for empty files, they cannot be mapped.
+}
+
+impl MmapStorage {
+    pub fn new(torrent_info: &TorrentInfo, base_dir: &Path) -> Result<Self> {
+        let layout = FileLayout::new(torrent_info);
+        let files = Self::map_files(&layout, base_dir)?;
+        Ok(Self {
+            layout,
+            base_dir: base_dir.to_path_buf(),
+            files,
+        })
+    }
+
+    fn map_files(layout: &FileLayout, base_dir: &Path) -> Result<Vec<Option<(File, MmapMut)>>> {
+        let mut files = Vec::with_capacity(layout.get_files().len());
+        for entry in layout.get_files() {
+            let path = base_dir.join(&entry.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            if file.metadata()?.len() < entry.length {
+                file.set_len(entry.length)?;
+            }
+            if entry.length == 0 {
+                files.push(None);
+                continue;
+            }
+            let map = unsafe { MmapMut::map_mut(&file)? };
+            files.push(Some((file, map)));
+        }
+        Ok(files)
+    }
+}
+
+impl Storage for MmapStorage {
+    fn get_layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    fn read_block(&mut self, piece_idx: usize, begin: u32, length: usize) -> Result<Vec<u8>> {
+        let offset = self.layout.get_offset(piece_idx, begin);
+        let mut data = Vec::with_capacity(length);
+        for seg in self.layout.map_range(offset, length) {
+            if let Some((_, map)) = &self.files[seg.file_idx] {
+                let start = seg.offset as usize;
+                data.extend_from_slice(&map[start..start + seg.length]);
+            }
+        }
+        Ok(data)
+    }
+
+    fn write_block(&mut self, piece_idx: usize, begin: u32, data: &[u8]) -> Result<()> {
+        let offset = self.layout.get_offset(piece_idx, begin);
+        let mut pos = 0;
+        for seg in self.layout.map_range(offset, data.len()) {
+            if let Some((_, map)) = &mut self.files[seg.file_idx] {
+                let start = seg.offset as usize;
+                map[start..start + seg.length].copy_from_slice(&data[pos..pos + seg.length]);
+            }
+            pos += seg.length;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for (_, map) in self.files.iter().flatten() {
+            map.flush()?;
+        }
+        Ok(())
+    }
+
+    fn move_to(&mut self, base_dir: &Path) -> Result<()> {
+        self.flush()?;
+        self.files.clear();
+        move_files(&self.layout, &self.base_dir, base_dir)?;
+        self.base_dir = base_dir.to_path_buf();
+        self.files = Self::map_files(&self.layout, &self.base_dir)?;
+        Ok(())
+    }
+
+    /// The files are gone, but blocks can still be written to the maps.
+    fn delete(&mut self) -> Result<()> {
+        delete_files(&self.layout, &self.base_dir)
+    }
+
+    fn get_file_stamps(&self) -> Result<Vec<FileStamp>> {
+        self.layout
+            .get_files()
+            .iter()
+            .map(|entry| match fs::File::open(self.base_dir.join(&entry.path)) {
+                Ok(file) => FileStamp::from_file(&file),
+                Err(_) => Ok(FileStamp { length: 0, mtime: 0 }),
+            })
+            .collect()
+    }
+}
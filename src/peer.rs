@@ -1,30 +1,155 @@
 use crate::error::{Error, Result};
 use crate::message::{Message, MessageCodec, MessagePlayload};
 use crate::signal::Signal;
+use crate::disk_io::DiskIo;
 use crate::downloader::Downloader;
+use crate::limits::Limits;
+use crate::dht::{DHT_BYTE, DHT_FLAG};
+use crate::extension::{
+    extended_message, supports_extensions, ExtendedHandshake, Extension, ExtensionRegistry, EXTENDED_HANDSHAKE_ID, EXTENSION_BYTE, EXTENSION_FLAG,
+};
+use crate::listener::LISTEN_PORT;
+use crate::utils::big_endian;
 
+use std::collections::{HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use bincode::*;
+use bincode::Options;
 use bit_vec::BitVec;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::UnboundedSender;
+use sha1::Sha1;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use tokio::prelude::*;
 use tokio::net::{TcpStream, tcp::WriteHalf};
+use tokio::time;
 use tokio_util::codec::{FramedRead, FramedWrite};
 use futures_util::sink::SinkExt;
 use futures::stream::StreamExt;
-use priority_queue::PriorityQueue;
 
 /*-----------------------------------Start stuff for this file here ----------------------------------------------*/
 const MAXIMUM_REQUEST:i32 = 20;
+const PROTOCOL_NAME: &str = "BitTorrent protocol";
+const HANDSHAKE_LENGTH: usize = 68;
+/// Requests bigger than this are dropped, nobody should ask for more than 16KiB anyway.
+const MAXIMUM_REQUEST_LENGTH: u32 = 131072;
+/// Fast extension (BEP 6) bit of the reserved bytes.
+const FAST_BYTE: usize = 7;
+const FAST_FLAG: u8 = 0x04;
+/// Number of pieces a choked peer may still request from us.
+const ALLOWED_FAST_COUNT: usize = 10;
+/// Extensions get a chance to send their own messages this often.
+const EXTENSION_TICK: std::time::Duration = std::time::Duration::from_secs(5);
+/// A peer whose requests time out this many times in a row is snubbed.
+const SNUB_TIMEOUTS: u32 = 2;
+/// Outstanding requests allowed to a snubbed peer.
+const SNUBBED_REQUESTS: usize = 1;
+
+/// Commands sent to a running peer task by the torrent.
+#[derive(Debug)]
+pub enum PeerCommand {
+    Choke,
+    UnChoke,
+    Cancel(u32, u32, u32), //<index><begin><length>, the block came from another peer.
+    TimedOut(u32, u32, u32), //<index><begin><length>, the request waited too long and was given up.
+    Disconnect, //the torrent is paused or stopped.
+    ResetRequests, //a hash check forgot every piece, our requests are cancelled.
+}
+
+/// Counters of a peer, shared between the peer task and the torrent.
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    pub downloaded: AtomicU64, //bytes of blocks received from this peer
+    pub uploaded: AtomicU64, //bytes of blocks sent to this peer
+    pub peer_interested: AtomicBool,
+    pub am_interested: AtomicBool,
+    pub snubbed: AtomicBool, //requests to this peer keep timing out
+}
+
+/// What the torrent keeps about a running peer task.
+#[derive(Debug, Clone)]
+pub struct PeerHandle {
+    pub addr: String,
+    pub command_slot: UnboundedSender<PeerCommand>,
+    pub stats: Arc<PeerStats>,
+}
 
 /* Handshake msg */
 #[derive(Debug, Serialize, Deserialize)]
-struct HandshakeMsg {
+pub(crate) struct HandshakeMsg {
     pstr: String,
-    reserved: [u8; 8],
-    info_hash: [u8; 20],
-    peer_id: [u8; 20], //peer id
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20], //peer id
+}
+
+impl HandshakeMsg {
+    pub fn new(peer_id: [u8; 20], info_hash: [u8; 20]) -> Self {
+        // We always speak the extension protocol and run a DHT node.
+        let mut reserved = [0u8; 8];
+        reserved[EXTENSION_BYTE] |= EXTENSION_FLAG;
+        reserved[DHT_BYTE] |= DHT_FLAG;
+        reserved[FAST_BYTE] |= FAST_FLAG;
+        Self {
+            pstr: String::from(PROTOCOL_NAME),
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut encoded: Vec<u8> = big_endian().serialize(self)?;
+        //bincode will use 64bit to encode a string length, but we only need 1 byte,
+        //so remove first seven bytes.
+        encoded.drain(..7);
+        Ok(encoded)
+    }
+
+    fn from_bytes(data: &[u8; HANDSHAKE_LENGTH]) -> Result<Self> {
+        if data[0] as usize != PROTOCOL_NAME.len() || &data[1..20] != PROTOCOL_NAME.as_bytes() {
+            return Err(Error::InvalidHandshake("Unknown protocol".to_string()));
+        }
+        let mut hsm = Self::new([0u8; 20], [0u8; 20]);
+        hsm.reserved.copy_from_slice(&data[20..28]);
+        hsm.info_hash.copy_from_slice(&data[28..48]);
+        hsm.peer_id.copy_from_slice(&data[48..68]);
+        Ok(hsm)
+    }
+}
+
+/// Allowed fast set of a peer (BEP 6): pieces it may request from us while choked.
+/// Only the /24 network of the peer is used, so several connections from the same network
+/// get the same set.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8; 20], no_pieces: usize, count: usize) -> Vec<u32> {
+    let mut allowed = Vec::with_capacity(count);
+    if no_pieces == 0 {
+        return allowed;
+    }
+    let count = std::cmp::min(count, no_pieces);
+    let mut x = ip.octets().to_vec();
+    x[3] = 0;
+    x.extend_from_slice(info_hash);
+    while allowed.len() < count {
+        x = Sha1::from(&x).digest().bytes().to_vec();
+        for chunk in x.chunks_exact(4) {
+            if allowed.len() >= count {
+                break;
+            }
+            let idx = (u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as u64 % no_pieces as u64) as u32;
+            if !allowed.contains(&idx) {
+                allowed.push(idx);
+            }
+        }
+    }
+    allowed
+}
+
+/// Read the handshake of the remote side.
+pub(crate) async fn read_handshake(stream: &mut TcpStream) -> Result<HandshakeMsg> {
+    let mut data = [0u8; HANDSHAKE_LENGTH];
+    stream.read_exact(&mut data).await?;
+    HandshakeMsg::from_bytes(&data)
 }
 
 pub struct Peer {
@@ -32,144 +157,539 @@ pub struct Peer {
     bit_field: BitVec,
     signal_slot: UnboundedSender<Signal>,
     download_mutex: Arc<Mutex<Downloader>>,
-    // FIXME: I think that it is not neccessary to keep a list of requested blocks.
-    number_of_requests: i32, 
+    disk: DiskIo, //blocks are written and read by the disk workers of the torrent.
+    requested: Vec<(u32, u32, u32)>, //our requests that have not been answered yet.
+    max_requests: i32, //lowered when the remote peer tells its reqq.
+    timeouts: u32, //requests timed out in a row, reset by every block received.
+    snubbed: bool, //only SNUBBED_REQUESTS requests are sent to a snubbed peer.
     is_choke: bool,
+    am_choking: bool, // we are choking the remote peer, its requests are dropped.
+    am_interested: bool,
+    peer_interested: bool,
+    upload_queue: VecDeque<(u32, u32, u32)>, //requests of the remote peer that wait to be served.
+    stats: Arc<PeerStats>,
+    command_slot: UnboundedSender<PeerCommand>,
+    command_rx: Option<UnboundedReceiver<PeerCommand>>, //taken when the connection starts.
+    remote_extensions: bool, //remote peer set the extension protocol bit.
+    remote_dht: bool, //remote peer runs a DHT node, it gets our Port message.
+    fast: bool, //both sides support the fast extension.
+    allowed_fast_in: HashSet<u32>, //pieces we may request while choked.
+    allowed_fast_out: HashSet<u32>, //pieces we serve even when we choke the remote peer.
+    extensions: ExtensionRegistry,
+    limits: Arc<Limits>, //connection and bandwidth limits of the session.
+    listen_port: u16,    //where the remote peer can connect to us
 }
 
 impl Peer {
     pub fn new(ip_addr: &str, signal_slot: UnboundedSender<Signal>, download_mutex: Arc<Mutex<Downloader>>) -> Peer {
+        let (command_slot, command_rx) = mpsc::unbounded_channel();
+        let disk = download_mutex.lock().unwrap().get_disk();
         Self {
             ip_addr: ip_addr.to_string(),
             bit_field: BitVec::new(),
             signal_slot,
             download_mutex,
-            number_of_requests: 0,
+            disk,
+            requested: Vec::new(),
+            max_requests: MAXIMUM_REQUEST,
+            timeouts: 0,
+            snubbed: false,
             is_choke: true,
+            am_choking: true,
+            am_interested: false,
+            peer_interested: false,
+            upload_queue: VecDeque::new(),
+            stats: Arc::new(PeerStats::default()),
+            command_slot,
+            command_rx: Some(command_rx),
+            remote_extensions: false,
+            remote_dht: false,
+            fast: false,
+            allowed_fast_in: HashSet::new(),
+            allowed_fast_out: HashSet::new(),
+            extensions: ExtensionRegistry::new(),
+            limits: Limits::unlimited(),
+            listen_port: LISTEN_PORT,
         }
     }
 
+    /// Plug an extension into this peer, must be called before the connection starts.
+    pub fn register_extension(&mut self, extension: Box<dyn Extension>) -> u8 {
+        self.extensions.register(extension)
+    }
+
+    /// Share the limits of a session, must be called before the connection starts.
+    pub fn set_limits(&mut self, limits: Arc<Limits>) {
+        self.limits = limits;
+    }
+
+    /// Port we accept peers on, sent in the extension handshake and the Port message.
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.listen_port = port;
+    }
+
+    /// Extension handshake of the remote peer, once it has been received.
+    pub fn get_remote_extensions(&self) -> Option<&ExtendedHandshake> {
+        self.extensions.get_remote()
+    }
+
+    /// Remember the reserved bytes of the remote handshake.
+    pub(crate) fn set_remote_reserved(&mut self, reserved: &[u8; 8]) {
+        self.remote_extensions = supports_extensions(reserved);
+        self.remote_dht = reserved[DHT_BYTE] & DHT_FLAG != 0;
+        self.fast = reserved[FAST_BYTE] & FAST_FLAG != 0;
+    }
+
     pub fn get_bit_field(&self) -> &BitVec {
         &self.bit_field
     }
 
+    pub fn get_handle(&self) -> PeerHandle {
+        PeerHandle {
+            addr: self.ip_addr.clone(),
+            command_slot: self.command_slot.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
     //[u8; 20] implemented Copy trait
     pub async fn send_handshake(&mut self, peer_id: [u8; 20], info_hash: [u8; 20]) -> Result<()> {
-        let hsm = HandshakeMsg {
-            pstr: String::from("BitTorrent protocol"),
-            reserved: [0u8; 8],
-            info_hash,
-            peer_id,
+        let _slot = match self.limits.try_connect() {
+            Some(slot) => slot,
+            None => return Err(Error::ConnectionLimit),
         };
-        let mut encoded: Vec<u8> = bincode::config().big_endian().serialize(&hsm)?;
-
-        //bincode will use 64bit to encode a string length, but we only need 1 byte,
-        //so remove first seven bytes.
-
+        let encoded = HandshakeMsg::new(peer_id, info_hash).to_bytes()?;
         let mut stream = TcpStream::connect(&self.ip_addr).await?;
-        encoded.drain(..7);
         stream.write_all(encoded.as_ref()).await?;
 
+        let remote = read_handshake(&mut stream).await?;
+        if remote.info_hash != info_hash {
+            return Err(Error::InvalidHandshake("Info hash does not match".to_string()));
+        }
+        self.set_remote_reserved(&remote.reserved);
+
         self.handle_connection(&mut stream).await?;
         Ok(())
     }
 
-    async fn request_more_blocks(&mut self, mut writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) {
-        //send an interest message.
-        writer.send(Message::new(1, Some(2), MessagePlayload::Interest)).await;
+    /// Tell the remote peer whether it has something we want, only when it changes.
+    async fn update_interest(&mut self, writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) -> Result<()> {
+        let interested = self.download_mutex.lock().unwrap().is_interesting(&self.bit_field);
+        if interested != self.am_interested {
+            self.am_interested = interested;
+            self.stats.am_interested.store(interested, Ordering::Relaxed);
+            if interested {
+                writer.send(Message::new(1, Some(2), MessagePlayload::Interest)).await?;
+            } else {
+                writer.send(Message::new(1, Some(3), MessagePlayload::NotInterest)).await?;
+            }
+        }
+        Ok(())
+    }
 
-        while !self.is_choke && self.number_of_requests < MAXIMUM_REQUEST {
+    async fn request_more_blocks(&mut self, writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) -> Result<()> {
+        self.update_interest(writer).await?;
+        if !self.am_interested {
+            return Ok(());
+        }
+
+        // While choked, only pieces of the allowed fast set can be requested.
+        let mut candidates = self.bit_field.clone();
+        if self.is_choke {
+            for idx in 0..candidates.len() {
+                if !self.allowed_fast_in.contains(&(idx as u32)) {
+                    candidates.set(idx, false);
+                }
+            }
+        }
+        if !candidates.any() {
+            return Ok(());
+        }
+
+        let limit = if self.snubbed { SNUBBED_REQUESTS } else { self.max_requests as usize };
+        while self.requested.len() < limit {
             let mut block_attrs : Option<(u32, u32, u32)> = None;
             //Request fore new block right here.
             //I will keep requesting until the request stack is full.
-            //if let Some(block_attrs) = self.download_mutex.lock().unwrap().pick_next_block(&self.bit_field) {
             if let Ok(mut download_instance) = self.download_mutex.lock() {
-                if let Some(block_info) = download_instance.pick_next_block(&self.bit_field) {
+                if let Some(block_info) = download_instance.pick_next_block(&self.ip_addr, &candidates) {
                     block_attrs = Some((block_info.0, block_info.1, block_info.2));
                 } 
             } else {
-                return;
+                return Ok(());
             }
 
             //send request message to partner
-            if let Some(attr_val) = block_attrs {
-                let msg = Message::new(13, Some(6), MessagePlayload::Request(attr_val.0, attr_val.1, attr_val.2));
-                writer.send(msg).await;
-                self.number_of_requests += 1;
+            match block_attrs {
+                Some(attr_val) if !self.requested.contains(&attr_val) => {
+                    let msg = Message::new(13, Some(6), MessagePlayload::Request(attr_val.0, attr_val.1, attr_val.2));
+                    writer.send(msg).await?;
+                    self.requested.push(attr_val);
+                }
+                _ => {
+                    //Nothing left to request from this peer.
+                    break;
+                }
             }
         }
+        Ok(())
+    }
+
+    /// Exchange messages with the remote peer, the handshake has already been done.
+    /// The torrent gets a PeerConnected signal with our handle first and a PeerDisconnected
+    /// signal when the connection is over.
+    pub async fn handle_connection(&mut self, stream: &mut TcpStream) -> Result<()> {
+        let _ = self.signal_slot.send(Signal::PeerConnected(self.get_handle()));
+        let result = self.exchange_messages(stream).await;
+        self.release_requests();
+        // Pieces of this peer do not count for rarity anymore.
+        self.download_mutex.lock().unwrap().remove_peer_pieces(&self.bit_field);
+        let _ = self.signal_slot.send(Signal::PeerDisconnected(self.ip_addr.clone()));
+        result
     }
 
-    pub async fn handle_connection(&mut self, mut stream: &mut TcpStream) -> Result<()> {
-        let mut data = [0u8; 68];
+    async fn exchange_messages(&mut self, stream: &mut TcpStream) -> Result<()> {
+        let mut command_rx = match self.command_rx.take() {
+            Some(command_rx) => command_rx,
+            None => return Ok(()),
+        };
+        let (r, w) = stream.split();
+        let mut reader = FramedRead::new(r, MessageCodec::new());
+        let mut writer = FramedWrite::new(w, MessageCodec::new());
 
-        match stream.read(&mut data).await {
-            Ok(_) => {
-                // Suppose that we received correct handshake message first.
-                //Trying
-                let (r, w) = stream.split();
-                let mut reader = FramedRead::new(r, MessageCodec::new());
-                let mut writer = FramedWrite::new(w, MessageCodec::new());
+        // Let the remote peer know what we can serve.
+        let bit_field = self.download_mutex.lock().unwrap().get_bitfield();
+        if self.fast && bit_field.all() {
+            writer.send(Message::new(1, Some(14), MessagePlayload::HaveAll)).await?;
+        } else if self.fast && bit_field.none() {
+            writer.send(Message::new(1, Some(15), MessagePlayload::HaveNone)).await?;
+        } else if bit_field.any() {
+            let bytes = bit_field.to_bytes();
+            writer.send(Message::new(1 + bytes.len(), Some(5), MessagePlayload::BitField(bit_field))).await?;
+        }
+        if self.remote_extensions {
+            let remote_ip = self.ip_addr.parse::<SocketAddr>().ok().map(|addr| addr.ip());
+            let handshake = self.extensions.build_handshake(self.listen_port, MAXIMUM_REQUEST as i64, remote_ip);
+            let payload = serde_bencode::to_bytes(&handshake)?;
+            writer.send(extended_message(EXTENDED_HANDSHAKE_ID, payload)).await?;
+        }
+        if self.remote_dht {
+            writer.send(Message::new(3, Some(9), MessagePlayload::Port(self.listen_port))).await?;
+        }
+        if self.fast {
+            self.send_allowed_fast(&mut writer).await?;
+        }
 
-                while let Some(Ok(value)) = reader.next().await {
+        let mut extension_timer = time::interval(EXTENSION_TICK);
+        loop {
+            // Requests wait in upload_queue so a Cancel can still remove them.
+            tokio::select! {
+                received = reader.next() => match received {
                     // Don't need to care about keep alive message.
-                    self.handle_message(value, &mut writer).await;
+                    Some(Ok(value)) => self.handle_message(value, &mut writer).await?,
+                    _ => break,
+                },
+                command = command_rx.recv() => match command {
+                    Some(PeerCommand::Choke) => self.set_choking(true, &mut writer).await?,
+                    Some(PeerCommand::UnChoke) => self.set_choking(false, &mut writer).await?,
+                    Some(PeerCommand::Cancel(pie_idx, begin, length)) => self.cancel_request((pie_idx, begin, length), &mut writer).await?,
+                    Some(PeerCommand::TimedOut(pie_idx, begin, length)) => self.request_timed_out((pie_idx, begin, length), &mut writer).await?,
+                    Some(PeerCommand::ResetRequests) => self.reset_requests(&mut writer).await?,
+                    Some(PeerCommand::Disconnect) | None => break,
+                },
+                _ = futures::future::ready(()), if !self.upload_queue.is_empty() => {
+                    self.serve_request(&mut writer).await?;
+                }
+                _ = extension_timer.tick(), if self.remote_extensions => {
+                    for msg in self.extensions.tick() {
+                        writer.send(msg).await?;
+                    }
                 }
             }
-            _ => {
-                println!("Failed");
+        }
+        Ok(())
+    }
+
+    /// Replace the pieces of the remote peer, the availability counts follow.
+    fn set_bit_field(&mut self, bit_field: BitVec) {
+        let mut downloader = self.download_mutex.lock().unwrap();
+        downloader.remove_peer_pieces(&self.bit_field);
+        downloader.update_priority(bit_field.clone());
+        self.bit_field = bit_field;
+    }
+
+    /// A block we requested arrived, hand it to the disk workers unless another peer was faster.
+    async fn block_received(&mut self, pie_idx: u32, begin: u32, data: Vec<u8>) {
+        self.timeouts = 0;
+        if self.snubbed {
+            self.snubbed = false;
+            self.stats.snubbed.store(false, Ordering::Relaxed);
+        }
+        let length = data.len();
+        let (others, wanted) = {
+            let mut downloader = self.download_mutex.lock().unwrap();
+            let others = downloader.block_received(pie_idx as usize, begin, &self.ip_addr);
+            (others, downloader.start_write(pie_idx as usize, begin, length))
+        };
+        if !others.is_empty() {
+            // Endgame: the same block was requested from other peers too.
+            let _ = self.signal_slot.send(Signal::CancelBlock(others, (pie_idx, begin, length as u32)));
+        }
+        if wanted {
+            // The disk workers write and check the piece, the torrent hears about it
+            // through a Signal::PieceHashed.
+            self.disk.write_block(pie_idx as usize, begin, data, &self.signal_slot).await;
+            let hash = self.download_mutex.lock().unwrap().block_written(pie_idx as usize, begin, length);
+            if let Some(hash) = hash {
+                self.disk.finish_piece(pie_idx as usize, hash, self.signal_slot.clone());
             }
+        }
+    }
+
+    /// Blocks we requested and will not get from this peer can be requested from others.
+    fn release_requests(&mut self) {
+        self.download_mutex.lock().unwrap().release_peer(&self.ip_addr);
+        self.requested.clear();
+    }
+
+    /// The downloader gave up one of our requests, the block may already be asked to another
+    /// peer so the request is cancelled. Too many of them in a row and the peer is snubbed.
+    async fn request_timed_out(&mut self, request: (u32, u32, u32), writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) -> Result<()> {
+        self.cancel_request(request, writer).await?;
+        self.timeouts += 1;
+        if self.timeouts >= SNUB_TIMEOUTS && !self.snubbed {
+            let _ = self.signal_slot.send(Signal::PeerSnubbed(self.ip_addr.clone()));
+            self.snubbed = true;
+            self.stats.snubbed.store(true, Ordering::Relaxed);
+        }
+        self.request_more_blocks(writer).await
+    }
+
+    /// Tell the remote peer which pieces it may request even while we choke it.
+    async fn send_allowed_fast(&mut self, writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) -> Result<()> {
+        let ip = match self.ip_addr.parse::<SocketAddr>() {
+            Ok(SocketAddr::V4(addr)) => *addr.ip(),
+            _ => return Ok(()),
+        };
+        let (info_hash, no_pieces, have) = {
+            let downloader = self.download_mutex.lock().unwrap();
+            (downloader.get_info_hash(), downloader.get_number_of_pieces(), downloader.get_bitfield())
         };
-        println!("We did get here for: {}", &self.ip_addr);
+        for pie_idx in allowed_fast_set(ip, &info_hash, no_pieces, ALLOWED_FAST_COUNT) {
+            self.allowed_fast_out.insert(pie_idx);
+            if have.get(pie_idx as usize) == Some(true) {
+                writer.send(Message::new(5, Some(17), MessagePlayload::AllowedFast(pie_idx))).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn cancel_request(&mut self, request: (u32, u32, u32), writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) -> Result<()> {
+        if let Some(pos) = self.requested.iter().position(|requested| *requested == request) {
+            self.requested.remove(pos);
+            let (pie_idx, begin, length) = request;
+            writer.send(Message::new(13, Some(8), MessagePlayload::Cancel(pie_idx, begin, length))).await?;
+        }
+        Ok(())
+    }
+
+    /// The downloader forgot the blocks we asked for, cancel all of them so they no longer
+    /// count against our request limit.
+    async fn reset_requests(&mut self, writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) -> Result<()> {
+        for (pie_idx, begin, length) in std::mem::take(&mut self.requested) {
+            writer.send(Message::new(13, Some(8), MessagePlayload::Cancel(pie_idx, begin, length))).await?;
+        }
+        Ok(())
+    }
+
+    async fn reject(&mut self, request: (u32, u32, u32), writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) -> Result<()> {
+        let (pie_idx, begin, length) = request;
+        writer.send(Message::new(13, Some(16), MessagePlayload::RejectRequest(pie_idx, begin, length))).await?;
+        Ok(())
+    }
+
+    /// Send the first queued block to the remote peer.
+    async fn serve_request(&mut self, writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) -> Result<()> {
+        if let Some((pie_idx, begin, length)) = self.upload_queue.pop_front() {
+            let allowed = self.download_mutex.lock().unwrap().start_upload(pie_idx as usize, begin, length);
+            if allowed {
+                self.limits.upload.acquire(length as usize).await;
+                let data = self.disk.read_block(pie_idx as usize, begin, length as usize).await?;
+                self.stats.uploaded.fetch_add(data.len() as u64, Ordering::Relaxed);
+                let msg = Message::new(9 + data.len(), Some(7), MessagePlayload::Piece(pie_idx, begin, data));
+                writer.send(msg).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_choking(&mut self, choke: bool, writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) -> Result<()> {
+        if self.am_choking == choke {
+            return Ok(());
+        }
+        self.am_choking = choke;
+        if choke {
+            writer.send(Message::new(1, Some(0), MessagePlayload::Choke)).await?;
+            if self.fast {
+                // Choke does not discard requests with the fast extension, each of them is
+                // rejected explicitly, allowed fast pieces are still served.
+                let queued = self.upload_queue.drain(..).collect::<Vec<(u32, u32, u32)>>();
+                for request in queued {
+                    if self.allowed_fast_out.contains(&request.0) {
+                        self.upload_queue.push_back(request);
+                    } else {
+                        self.reject(request, writer).await?;
+                    }
+                }
+            } else {
+                // Pending requests are discarded when a peer is choked.
+                self.upload_queue.clear();
+            }
+        } else {
+            writer.send(Message::new(1, Some(1), MessagePlayload::UnChoke)).await?;
+        }
         Ok(())
     }
 
-    async fn handle_message(&mut self, received_msg: Message, mut writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) {
+    async fn handle_message(&mut self, received_msg: Message, writer: &mut FramedWrite<WriteHalf<'_>, MessageCodec>) -> Result<()> {
         match received_msg.payload {
             MessagePlayload::BitField(new_bit_field) => {
-                self.download_mutex.lock().unwrap().update_priority(new_bit_field.clone());
-                println!("Just updated bitfield : {}", self.ip_addr);
-                self.bit_field = new_bit_field;
+                self.set_bit_field(new_bit_field);
                 //try to request a block here
-                self.request_more_blocks(&mut writer).await;
+                self.request_more_blocks(writer).await?;
             }
             MessagePlayload::Have(pie_idx) => {
-                self.bit_field.set(pie_idx as usize, true);
-                self.signal_slot.send(Signal::Have(pie_idx as usize));
+                let pie_idx = pie_idx as usize;
+                {
+                    let mut downloader = self.download_mutex.lock().unwrap();
+                    if pie_idx >= downloader.get_number_of_pieces() {
+                        return Ok(());
+                    }
+                    // Have without a bitfield first: the peer started with nothing.
+                    if self.bit_field.is_empty() {
+                        self.bit_field = BitVec::from_elem(downloader.get_number_of_pieces(), false);
+                    }
+                    if self.bit_field.get(pie_idx) == Some(false) {
+                        self.bit_field.set(pie_idx, true);
+                        downloader.add_peer_piece(pie_idx);
+                    }
+                }
+                let _ = self.signal_slot.send(Signal::Have(pie_idx));
                 //try to request a block here
-                self.request_more_blocks(&mut writer).await;
+                self.request_more_blocks(writer).await?;
             }
             MessagePlayload::Choke => {
                 self.is_choke = true;
+                if !self.fast {
+                    // Our pending requests are dropped by the remote peer.
+                    self.release_requests();
+                }
             }
             MessagePlayload::UnChoke => {
                 self.is_choke = false;
-                self.request_more_blocks(&mut writer).await;
+                self.request_more_blocks(writer).await?;
+            }
+            MessagePlayload::Interest => {
+                // Choker decides when the peer gets unchoked.
+                self.peer_interested = true;
+                self.stats.peer_interested.store(true, Ordering::Relaxed);
+            }
+            MessagePlayload::NotInterest => {
+                self.peer_interested = false;
+                self.stats.peer_interested.store(false, Ordering::Relaxed);
             }
             MessagePlayload::Empty => {
                 //Mean something, i don't know
             }
             MessagePlayload::Cancel(pie_idx, begin, length) => {
                 // Seeder role: Remove a task from job queue and ignore all related reply.
+                let queued = self.upload_queue.len();
+                self.upload_queue.retain(|request| *request != (pie_idx, begin, length));
+                if self.fast && self.upload_queue.len() < queued {
+                    // Every request gets an answer with the fast extension.
+                    self.reject((pie_idx, begin, length), writer).await?;
+                }
             }
             MessagePlayload::Request(pie_idx, begin, length) => {
                 // Seeder role: reply by a data block: MessagePayload::Piece
+                let allowed = !self.am_choking || self.allowed_fast_out.contains(&pie_idx);
+                if allowed && length <= MAXIMUM_REQUEST_LENGTH {
+                    self.upload_queue.push_back((pie_idx, begin, length));
+                } else if self.fast {
+                    self.reject((pie_idx, begin, length), writer).await?;
+                }
             }
             MessagePlayload::Piece(pie_idx, begin, data) => {
                 // Write to disk, update manager and broadcast a MessagePayload::Have
                 // TODO: How to sync Offline field for all Peer?
                 //try to request a block here
-                self.download_mutex.lock().unwrap().write_block(pie_idx as usize, begin, &data);
-                self.number_of_requests -= 1;
-                self.request_more_blocks(&mut writer).await;
+                self.stats.downloaded.fetch_add(data.len() as u64, Ordering::Relaxed);
+                // Nothing more is read from this peer until the session rate allows it.
+                self.limits.download.acquire(data.len()).await;
+                // Only the blocks we asked this peer for, with the offset and length we asked.
+                let request = (pie_idx, begin, data.len() as u32);
+                if let Some(pos) = self.requested.iter().position(|requested| *requested == request) {
+                    self.requested.remove(pos);
+                    self.block_received(pie_idx, begin, data).await;
+                }
+                self.request_more_blocks(writer).await?;
+            }
+            MessagePlayload::HaveAll | MessagePlayload::HaveNone => {
+                let have_all = matches!(received_msg.payload, MessagePlayload::HaveAll);
+                let no_pieces = self.download_mutex.lock().unwrap().get_number_of_pieces();
+                self.set_bit_field(BitVec::from_elem(no_pieces, have_all));
+                self.request_more_blocks(writer).await?;
+            }
+            MessagePlayload::SuggestPiece(_) => {
+                // Pieces are picked by rarity, suggestions are ignored.
+            }
+            MessagePlayload::AllowedFast(pie_idx) => {
+                self.allowed_fast_in.insert(pie_idx);
+                if self.is_choke {
+                    self.request_more_blocks(writer).await?;
+                }
+            }
+            MessagePlayload::RejectRequest(pie_idx, begin, length) => {
+                // The block goes back to Open so it can be requested again, maybe from others.
+                if let Some(pos) = self.requested.iter().position(|request| *request == (pie_idx, begin, length)) {
+                    self.requested.remove(pos);
+                    self.download_mutex.lock().unwrap().reject_block(pie_idx as usize, begin, &self.ip_addr);
+                }
             }
             MessagePlayload::Port(port) => {
-                //We have nothing to do here. I won't support it.
-                self.signal_slot.send(Signal::Port(port));
+                // The DHT node of this peer listens on its IP address with this port.
+                if let Ok(addr) = self.ip_addr.parse::<SocketAddr>() {
+                    let _ = self.signal_slot.send(Signal::Port(SocketAddr::new(addr.ip(), port)));
+                }
             }
-            _ => {
-                self.signal_slot.send(Signal::Unknown);
+            MessagePlayload::Extended(ext_id, payload) => {
+                for reply in self.extensions.handle_message(ext_id, &payload)? {
+                    writer.send(reply).await?;
+                }
+                if ext_id == EXTENDED_HANDSHAKE_ID {
+                    if let Some(reqq) = self.get_remote_extensions().and_then(|remote| remote.reqq) {
+                        self.max_requests = reqq.clamp(1, MAXIMUM_REQUEST as i64) as i32;
+                    }
+                }
             }
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_fast_set_matches_bep6() {
+        let info_hash = [0xaa; 20];
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        assert_eq!(allowed_fast_set(ip, &info_hash, 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        assert_eq!(allowed_fast_set(ip, &info_hash, 3, 10).len(), 3);
     }
 }
@@ -0,0 +1,232 @@
+/*
+ * pex.rs
+ * Peer exchange (ut_pex, BEP 11). Once a minute every peer gets the peers we connected to or
+ * lost since the last message, and the peers it sends us are handed to the torrent as new
+ * connection candidates. Not used for private torrents.
+ */
+use crate::error::Result;
+use crate::extension::Extension;
+use crate::signal::Signal;
+use crate::utils::{compact_v4, compact_v6, parse_compact_v4, parse_compact_v6};
+
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+pub const PEX_NAME: &str = "ut_pex";
+/// We send at most one message per minute to each peer.
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Messages arriving faster than this are dropped.
+const PEX_MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+/// Maximum number of added (and of dropped) peers in one message.
+const PEX_MAX_PEERS: usize = 50;
+/// Flag of a peer we connected to ourselves, so it accepts incoming connections.
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PexMsg {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(default)]
+    #[serde(rename = "added.f")]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(default)]
+    #[serde(rename = "added6.f")]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+impl PexMsg {
+    fn add(&mut self, addr: &SocketAddr, flags: u8) {
+        if let Some(compact) = compact_v4(addr) {
+            self.added.extend_from_slice(&compact);
+            self.added_flags.push(flags);
+        } else if let Some(compact) = compact_v6(addr) {
+            self.added6.extend_from_slice(&compact);
+            self.added6_flags.push(flags);
+        }
+    }
+
+    fn drop(&mut self, addr: &SocketAddr) {
+        if let Some(compact) = compact_v4(addr) {
+            self.dropped.extend_from_slice(&compact);
+        } else if let Some(compact) = compact_v6(addr) {
+            self.dropped6.extend_from_slice(&compact);
+        }
+    }
+}
+
+/// Peers the torrent is connected to, with their PEX flags. Shared by all connections.
+#[derive(Debug, Clone, Default)]
+pub struct PexSwarm {
+    peers: Arc<Mutex<HashMap<SocketAddr, u8>>>,
+}
+
+impl PexSwarm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, addr: SocketAddr, flags: u8) {
+        self.peers.lock().unwrap().insert(addr, flags);
+    }
+
+    pub fn remove(&self, addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(addr);
+    }
+
+    fn snapshot(&self) -> HashMap<SocketAddr, u8> {
+        self.peers.lock().unwrap().clone()
+    }
+}
+
+/// ut_pex on one connection.
+pub struct PexExtension {
+    swarm: PexSwarm,
+    remote: Option<SocketAddr>, //never sent to the peer itself
+    signal_slot: UnboundedSender<Signal>,
+    sent: HashMap<SocketAddr, u8>, //what the remote peer knows from us
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexExtension {
+    pub fn new(swarm: PexSwarm, remote: &str, signal_slot: UnboundedSender<Signal>) -> Self {
+        Self {
+            swarm,
+            remote: remote.parse().ok(),
+            signal_slot,
+            sent: HashMap::new(),
+            last_sent: None,
+            last_received: None,
+        }
+    }
+}
+
+impl Extension for PexExtension {
+    fn name(&self) -> &'static str {
+        PEX_NAME
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        if let Some(last) = self.last_received {
+            if last.elapsed() < PEX_MIN_RECEIVE_INTERVAL {
+                return Ok(Vec::new());
+            }
+        }
+        self.last_received = Some(Instant::now());
+
+        let msg = serde_bencode::from_bytes::<PexMsg>(payload)?;
+        let mut peers = parse_compact_v4(&msg.added);
+        peers.extend(parse_compact_v6(&msg.added6));
+        peers.truncate(PEX_MAX_PEERS);
+        if !peers.is_empty() {
+            let _ = self.signal_slot.send(Signal::Peers(peers));
+        }
+        Ok(Vec::new())
+    }
+
+    fn tick(&mut self) -> Vec<Vec<u8>> {
+        if let Some(last) = self.last_sent {
+            if last.elapsed() < PEX_INTERVAL {
+                return Vec::new();
+            }
+        }
+
+        let mut current = self.swarm.snapshot();
+        if let Some(remote) = &self.remote {
+            current.remove(remote);
+        }
+        let mut msg = PexMsg::default();
+        let added = current
+            .iter()
+            .filter(|(addr, _)| !self.sent.contains_key(addr))
+            .take(PEX_MAX_PEERS)
+            .map(|(addr, flags)| (*addr, *flags))
+            .collect::<Vec<(SocketAddr, u8)>>();
+        let dropped = self
+            .sent
+            .keys()
+            .filter(|addr| !current.contains_key(addr))
+            .take(PEX_MAX_PEERS)
+            .cloned()
+            .collect::<Vec<SocketAddr>>();
+        if added.is_empty() && dropped.is_empty() {
+            return Vec::new();
+        }
+
+        for (addr, flags) in added {
+            msg.add(&addr, flags);
+            self.sent.insert(addr, flags);
+        }
+        for addr in dropped {
+            msg.drop(&addr);
+            self.sent.remove(&addr);
+        }
+        self.last_sent = Some(Instant::now());
+        match serde_bencode::to_bytes(&msg) {
+            Ok(payload) => vec![payload],
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn send_added_and_dropped_peers() {
+        let swarm = PexSwarm::new();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut pex = PexExtension::new(swarm.clone(), "10.0.0.9:6881", tx);
+        let first: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let second: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+        swarm.add(first, FLAG_REACHABLE);
+        swarm.add(second, 0);
+        swarm.add("10.0.0.9:6881".parse().unwrap(), 0);
+
+        let sent = pex.tick();
+        let msg = serde_bencode::from_bytes::<PexMsg>(&sent[0]).unwrap();
+        assert_eq!(parse_compact_v4(&msg.added), vec![first]);
+        assert_eq!(msg.added_flags.as_slice(), &[FLAG_REACHABLE]);
+        assert_eq!(parse_compact_v6(&msg.added6), vec![second]);
+
+        // Nothing more until a minute has passed.
+        swarm.remove(&first);
+        assert!(pex.tick().is_empty());
+        pex.last_sent = None;
+        let msg = serde_bencode::from_bytes::<PexMsg>(&pex.tick()[0]).unwrap();
+        assert!(msg.added.is_empty() && msg.added6.is_empty());
+        assert_eq!(parse_compact_v4(&msg.dropped), vec![first]);
+        assert!(pex.tick().is_empty());
+    }
+
+    #[test]
+    fn received_peers_become_candidates() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut pex = PexExtension::new(PexSwarm::new(), "10.0.0.9:6881", tx);
+        let mut msg = PexMsg::default();
+        msg.add(&"10.0.0.1:6881".parse().unwrap(), 0);
+        msg.add(&"[2001:db8::1]:51413".parse().unwrap(), 0);
+        let payload = serde_bencode::to_bytes(&msg).unwrap();
+
+        pex.on_message(&payload).unwrap();
+        match rx.try_recv() {
+            Ok(Signal::Peers(peers)) => assert_eq!(peers.len(), 2),
+            _ => panic!("peers should be forwarded"),
+        }
+        // Too soon after the previous one.
+        pex.on_message(&payload).unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * picker.rs
+ * Piece picking strategies. The piece controller gives a picker every piece the remote peer has
+ * and we still need, ordered from the rarest to the most common one, and the picker chooses
+ * which one to download next. A torrent uses rarest first unless another picker is set. Except
+ * for the sequential one, pickers only look at the pieces with the highest priority.
+ */
+use rand::Rng;
+use std::cmp::Reverse;
+use std::time::Instant;
+
+/// Pieces with this priority are never picked, they only belong to skipped files.
+pub const SKIP_PRIORITY: u8 = 0;
+/// Priority of a piece nobody asked anything about.
+pub const DEFAULT_PRIORITY: u8 = 4;
+/// Highest priority a piece can have.
+pub const MAX_PRIORITY: u8 = 7;
+/// Pieces downloaded at random before switching to rarest first.
+pub const RANDOM_FIRST_PIECES: usize = 4;
+
+/// A piece that can be picked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub piece: usize,
+    pub availability: usize, //number of peers that have the piece
+    pub priority: u8,
+    pub deadline: Option<Instant>, //the piece is wanted before this time, e.g. for streaming
+}
+
+pub trait PiecePicker: Send {
+    /// Choose one of `candidates`, which are sorted from the rarest to the most common piece.
+    /// `finished` is the number of pieces we already have.
+    fn pick(&mut self, candidates: &[Candidate], finished: usize) -> Option<usize>;
+}
+
+/// Candidates with the highest priority, still from the rarest to the most common.
+fn top_priority(candidates: &[Candidate]) -> Vec<&Candidate> {
+    let top = candidates.iter().map(|candidate| candidate.priority).max().unwrap_or_default();
+    candidates.iter().filter(|candidate| candidate.priority == top).collect()
+}
+
+/// The rarest piece of the highest priority, ties are broken at random so peers do not all
+/// ask for the same one.
+#[derive(Debug, Default)]
+pub struct RarestFirst;
+
+impl PiecePicker for RarestFirst {
+    fn pick(&mut self, candidates: &[Candidate], _finished: usize) -> Option<usize> {
+        let candidates = top_priority(candidates);
+        let rarest = candidates.first()?.availability;
+        let ties = candidates.iter().take_while(|candidate| candidate.availability == rarest).count();
+        Some(candidates[rand::thread_rng().gen_range(0, ties)].piece)
+    }
+}
+
+/// Pieces in order, so a file can be played while it is downloading.
+#[derive(Debug, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&mut self, candidates: &[Candidate], _finished: usize) -> Option<usize> {
+        candidates.iter().map(|candidate| candidate.piece).min()
+    }
+}
+
+/// Random pieces of the highest priority until the first few are finished, we have something
+/// to share sooner than with the rarest pieces which only a few peers can give us. Rarest first
+/// after that.
+#[derive(Debug)]
+pub struct RandomFirst {
+    count: usize,
+    rarest: RarestFirst,
+}
+
+impl RandomFirst {
+    pub fn new(count: usize) -> Self {
+        Self { count, rarest: RarestFirst }
+    }
+}
+
+impl Default for RandomFirst {
+    fn default() -> Self {
+        Self::new(RANDOM_FIRST_PIECES)
+    }
+}
+
+impl PiecePicker for RandomFirst {
+    fn pick(&mut self, candidates: &[Candidate], finished: usize) -> Option<usize> {
+        if finished >= self.count {
+            return self.rarest.pick(candidates, finished);
+        }
+        let candidates = top_priority(candidates);
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(candidates[rand::thread_rng().gen_range(0, candidates.len())].piece)
+    }
+}
+
+/// Pieces with a deadline first, the earliest one first, then the highest priority, then the
+/// rarest piece.
+#[derive(Debug, Default)]
+pub struct PriorityPicker;
+
+impl PiecePicker for PriorityPicker {
+    fn pick(&mut self, candidates: &[Candidate], _finished: usize) -> Option<usize> {
+        candidates
+            .iter()
+            .enumerate()
+            .min_by_key(|(rank, candidate)| (candidate.deadline.is_none(), candidate.deadline, Reverse(candidate.priority), *rank))
+            .map(|(_, candidate)| candidate.piece)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn candidate(piece: usize, availability: usize) -> Candidate {
+        Candidate {
+            piece,
+            availability,
+            priority: DEFAULT_PRIORITY,
+            deadline: None,
+        }
+    }
+
+    #[test]
+    fn pickers_follow_their_order() {
+        let candidates = vec![candidate(5, 1), candidate(2, 1), candidate(0, 3), candidate(9, 4)];
+        for _ in 0..20 {
+            let piece = RarestFirst.pick(&candidates, 0).unwrap();
+            assert!(piece == 5 || piece == 2);
+            assert!(RandomFirst::default().pick(&candidates, 0).is_some());
+        }
+        assert_eq!(Sequential.pick(&candidates, 0), Some(0));
+        assert_eq!(RarestFirst.pick(&[], 0), None);
+        assert_eq!(RandomFirst::new(1).pick(&candidates[2..], 1), Some(0));
+    }
+
+    #[test]
+    fn deadline_then_priority_then_rarity() {
+        let now = Instant::now();
+        let mut candidates = vec![candidate(5, 1), candidate(2, 2), candidate(0, 3), candidate(9, 4)];
+        assert_eq!(PriorityPicker.pick(&candidates, 0), Some(5));
+        candidates[1].priority = MAX_PRIORITY;
+        assert_eq!(PriorityPicker.pick(&candidates, 0), Some(2));
+        candidates[3].deadline = Some(now + Duration::from_secs(10));
+        candidates[2].deadline = Some(now + Duration::from_secs(5));
+        assert_eq!(PriorityPicker.pick(&candidates, 0), Some(0));
+    }
+
+    #[test]
+    fn default_pickers_prefer_high_priority() {
+        let mut candidates = vec![candidate(5, 1), candidate(2, 1), candidate(0, 3), candidate(9, 4)];
+        candidates[3].priority = MAX_PRIORITY;
+        for _ in 0..20 {
+            assert_eq!(RarestFirst.pick(&candidates, 0), Some(9));
+            assert_eq!(RandomFirst::default().pick(&candidates, 0), Some(9));
+        }
+    }
+}
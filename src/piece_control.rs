@@ -1,6 +1,9 @@
+use crate::picker::{Candidate, PiecePicker, RarestFirst, DEFAULT_PRIORITY, SKIP_PRIORITY};
 use std::collections::HashMap;
+use std::time::Instant;
 use bit_vec::BitVec;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq)]
 enum PieceStatus {
     HAVE,
@@ -14,6 +17,8 @@ struct PiecePos{
    peer_count: usize,
    piece_list_idx: usize,
    pub piece_status: PieceStatus,
+   priority: u8,
+   deadline: Option<Instant>,
 }
 
 impl PiecePos {
@@ -22,6 +27,8 @@ impl PiecePos {
             peer_count: 0,
             piece_list_idx: piece_idx,
             piece_status: PieceStatus::NOTYET,
+            priority: DEFAULT_PRIORITY,
+            deadline: None,
         }
     }
 
@@ -51,6 +58,11 @@ impl PiecePos {
         self.peer_count
     }
 
+    /// Return true unless the piece only belongs to skipped files.
+    fn is_wanted(&self) -> bool {
+        self.priority != SKIP_PRIORITY
+    }
+
 }
 
 /// This structure will contain lists of pieces
@@ -64,15 +76,15 @@ pub struct PieceControler {
     piece_list : Vec<usize>,
     boundaries : HashMap<usize, usize>,
     finished_piece: usize,
+    picker: Box<dyn PiecePicker>,
 }
 
 impl PieceControler {
     pub fn new(no_of_pieces: usize) -> Self{
         //For test
         let mut piece_map = Vec::with_capacity(no_of_pieces);
-        let piece_list = (0..no_of_pieces).map(|idx| {
-            piece_map.push(PiecePos::new(idx as usize));
-            idx as usize
+        let piece_list = (0..no_of_pieces).inspect(|&idx| {
+            piece_map.push(PiecePos::new(idx));
         }).collect::<Vec<usize>>();
 
         let mut boundaries = HashMap::new();
@@ -84,18 +96,31 @@ impl PieceControler {
             piece_list,
             boundaries,
             finished_piece,
+            picker: Box::new(RarestFirst),
         }
     }
     
     #[cfg(test)]
     pub fn check_piece_list_invalid(&self) -> bool {
-        for i in (0..self.piece_list.len() - 1) {
+        for i in 0..self.piece_list.len() - 1 {
            if self.piece_map[self.piece_list[i]].peer_count > 
                self.piece_map[self.piece_list[i+1]].peer_count {
                 return false;
             }
         }
-        true
+        // Every piece knows its position, and every bucket ends at its boundary.
+        for (list_idx, &piece_idx) in self.piece_list.iter().enumerate() {
+            let count = self.piece_map[piece_idx].peer_count;
+            if self.piece_map[piece_idx].get_list_idx() != list_idx {
+                return false;
+            }
+            let is_last = list_idx + 1 == self.piece_list.len()
+                || self.piece_map[self.piece_list[list_idx + 1]].peer_count != count;
+            if is_last != (self.boundaries.get(&count) == Some(&list_idx)) {
+                return false;
+            }
+        }
+        self.boundaries.len() == self.piece_list.iter().map(|&idx| self.piece_map[idx].peer_count).collect::<std::collections::HashSet<_>>().len()
     }
 
     /// Increase peer_count of a piece by one
@@ -113,65 +138,131 @@ impl PieceControler {
         self.piece_map[self.piece_list[*old_bound_idx]].set_list_idx(piece_list_idx);
         self.piece_list.swap(piece_list_idx, *old_bound_idx);
 
-        if *old_bound_idx > 0 && self.piece_map[self.piece_list[*old_bound_idx - 1]].peer_count as usize == old_avail {
+        if *old_bound_idx > 0 && self.piece_map[self.piece_list[*old_bound_idx - 1]].peer_count == old_avail {
             *old_bound_idx -= 1;
         } else {
             self.boundaries.remove(&old_avail);
         }
 
-        if let None = self.boundaries.get(&new_avail) {
+        if !self.boundaries.contains_key(&new_avail) {
             self.boundaries.insert(new_avail, self.piece_map[piece_idx].get_list_idx());
         }
     }
 
     /// Decrease peer_count of piece by one - called when a peer leaves.
     pub fn decrease_count(&mut self, piece_idx: usize) {
-       unimplemented!(); 
-    }
+        if self.piece_map[piece_idx].get_peer_count() == 0 {
+            return;
+        }
+        let old_avail = self.piece_map[piece_idx].get_peer_count();
+        let piece_list_idx = self.piece_map[piece_idx].get_list_idx();
+        // The list is sorted by peer count, so the first piece of the old bucket is right after
+        // every piece with a lower count.
+        let first_idx = self.piece_list.partition_point(|&idx| self.piece_map[idx].peer_count < old_avail);
+        let new_avail = self.piece_map[piece_idx].decrease_count();
+
+        self.piece_map[piece_idx].set_list_idx(first_idx);
+        self.piece_map[self.piece_list[first_idx]].set_list_idx(piece_list_idx);
+        self.piece_list.swap(piece_list_idx, first_idx);
 
-    pub fn get_no_entries(&self) -> usize {
-        self.piece_list.len()
+        // The piece was alone in its bucket.
+        if self.boundaries.get(&old_avail) == Some(&first_idx) {
+            self.boundaries.remove(&old_avail);
+        }
+        // The lower bucket, new or not, now ends with this piece.
+        self.boundaries.insert(new_avail, first_idx);
     }
     
-    pub fn get_next_piece(&self, peer_bitfield: &BitVec) -> Option<usize> {
-        /*self.piece_list.iter().find_map(|&x| {
-            self.piece_map[x].get_peer_count() > 0 && self.piece_map[x].piece_status == PieceStatus::NOTYET
-        })*/
-
-        self.piece_list.iter().find_map(|&x| {
-            if let Some(check) = peer_bitfield.get(x) {
-                if check ==true && self.piece_map[x].piece_status == PieceStatus::NOTYET {
-                    return Some(x);
-                }
-            }
-            None
-        })
+    pub fn set_picker(&mut self, picker: Box<dyn PiecePicker>) {
+        self.picker = picker;
+    }
+
+    pub fn set_priority(&mut self, piece_idx: usize, priority: u8) {
+        self.piece_map[piece_idx].priority = priority;
+    }
+
+    pub fn set_deadline(&mut self, piece_idx: usize, deadline: Option<Instant>) {
+        self.piece_map[piece_idx].deadline = deadline;
+    }
+
+    /// Let the picker choose among the pieces the peer has and we still need.
+    pub fn get_next_piece(&mut self, peer_bitfield: &BitVec) -> Option<usize> {
+        // piece_list is sorted by peer count, so candidates go from the rarest to the most common.
+        let candidates = self
+            .piece_list
+            .iter()
+            .filter(|&&x| peer_bitfield.get(x) == Some(true) && self.piece_map[x].piece_status == PieceStatus::NOTYET)
+            .filter(|&&x| self.piece_map[x].is_wanted())
+            .map(|&x| Candidate {
+                piece: x,
+                availability: self.piece_map[x].peer_count,
+                priority: self.piece_map[x].priority,
+                deadline: self.piece_map[x].deadline,
+            })
+            .collect::<Vec<Candidate>>();
+        self.picker.pick(&candidates, self.finished_piece)
     }
     
-    //Post condition: return true if all piece has been finished.
+    //Post condition: return true if all wanted pieces have been finished.
     pub fn set_piece_complete(&mut self, piece_idx: usize) -> bool {
         if self.piece_map[piece_idx].piece_status != PieceStatus::HAVE {
             self.piece_map[piece_idx].piece_status = PieceStatus::HAVE;
             self.finished_piece += 1;
         }
 
-        if self.finished_piece == self.piece_map.len() {
-            println!("All piece has been downloaded");
-            true
-        } else {
-            false
-        }
+        self.is_complete()
     }
 
     pub fn set_piece_picked(&mut self, piece_idx: usize) {
         self.piece_map[piece_idx].piece_status = PieceStatus::PICKED;
     }
+
+    /// Return true when no wanted piece is waiting to be picked, every missing one is downloading.
+    pub fn all_picked(&self) -> bool {
+        self.piece_map.iter().all(|piece| piece.piece_status != PieceStatus::NOTYET || !piece.is_wanted())
+    }
+
+    pub fn is_wanted(&self, piece_idx: usize) -> bool {
+        self.piece_map[piece_idx].is_wanted()
+    }
+
+    pub fn has_piece(&self, piece_idx: usize) -> bool {
+        self.piece_map[piece_idx].piece_status == PieceStatus::HAVE
+    }
+
+    /// Return true if the peer has at least one piece we still need.
+    pub fn is_interesting(&self, peer_bitfield: &BitVec) -> bool {
+        peer_bitfield.iter().zip(self.piece_map.iter()).any(|(set, piece)| {
+            set && piece.piece_status != PieceStatus::HAVE && piece.is_wanted()
+        })
+    }
+
+    /// Return true when every wanted piece has been downloaded, pieces of skipped files may
+    /// still be missing.
+    pub fn is_complete(&self) -> bool {
+        self.finished_piece == self.piece_map.len()
+            || self.piece_map.iter().all(|piece| piece.piece_status == PieceStatus::HAVE || !piece.is_wanted())
+    }
+
+    /// Return a bitfield where a bit is set for every piece we have.
+    pub fn get_bitfield(&self) -> BitVec {
+        self.piece_map.iter().map(|piece| piece.piece_status == PieceStatus::HAVE).collect()
+    }
+
+    /// Put a piece back to NOTYET so it can be picked again, e.g. after a hash failure.
+    pub fn reset_piece(&mut self, piece_idx: usize) {
+        if self.piece_map[piece_idx].piece_status == PieceStatus::HAVE {
+            self.finished_piece -= 1;
+        }
+        self.piece_map[piece_idx].piece_status = PieceStatus::NOTYET;
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::meta_info;
     use super::PieceControler;
+    use crate::picker::SKIP_PRIORITY;
+    use bit_vec::BitVec;
     use rand::Rng;
     /*#[test]
     fn check_no_entries() {
@@ -200,10 +291,69 @@ mod tests {
         test_gen(100, 200);
     }
 
+    #[test]
+    fn check_mixed10_200() {
+        test_mixed(10, 200);
+    }
+
+    #[test]
+    fn check_mixed100_2000() {
+        test_mixed(100, 2000);
+    }
+
+    #[test]
+    fn skipped_pieces_are_not_picked() {
+        let mut piece_control = PieceControler::new(3);
+        let all = BitVec::from_elem(3, true);
+        piece_control.set_priority(0, SKIP_PRIORITY);
+        piece_control.set_priority(2, SKIP_PRIORITY);
+        assert_eq!(piece_control.get_next_piece(&all), Some(1));
+        assert!(!piece_control.is_interesting(&BitVec::from_fn(3, |idx| idx != 1)));
+        assert!(!piece_control.set_piece_complete(0));
+        assert!(piece_control.set_piece_complete(1));
+        assert_eq!(piece_control.get_next_piece(&all), None);
+    }
+
+    #[test]
+    fn decrease_back_to_zero() {
+        let mut piece_control = PieceControler::new(4);
+        piece_control.increase_count(2);
+        piece_control.increase_count(2);
+        piece_control.increase_count(1);
+        piece_control.decrease_count(2);
+        piece_control.decrease_count(2);
+        piece_control.decrease_count(1);
+        // Nothing left to remove.
+        piece_control.decrease_count(1);
+        assert!(piece_control.check_piece_list_invalid());
+        assert!(piece_control.piece_map.iter().all(|piece| piece.get_peer_count() == 0));
+    }
+
+    // Peers come and go: every check also compares counts with a plain vector.
+    fn test_mixed(no_pieces: usize, times: usize) {
+        let mut piece_control = PieceControler::new(no_pieces);
+        let mut counts = vec![0usize; no_pieces];
+        let mut rng = rand::thread_rng();
+        for _ in 0..times {
+            let idx = rng.gen_range(0, no_pieces);
+            if rng.gen_range(0, 3) == 0 {
+                piece_control.decrease_count(idx);
+                counts[idx] = counts[idx].saturating_sub(1);
+            } else {
+                piece_control.increase_count(idx);
+                counts[idx] += 1;
+            }
+            assert!(piece_control.check_piece_list_invalid());
+        }
+        for (idx, count) in counts.iter().enumerate() {
+            assert_eq!(piece_control.piece_map[idx].get_peer_count(), *count);
+        }
+    }
+
     fn test_gen(no_pieces: usize, inc_times: i64) {
         let mut piece_control = PieceControler::new(no_pieces);
         let mut rng = rand::thread_rng();
-        for i in (0..inc_times) {
+        for _ in 0..inc_times {
             let idx = rng.gen_range(0, no_pieces);
             piece_control.increase_count(idx);
         }
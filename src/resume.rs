@@ -0,0 +1,102 @@
+/*
+ * resume.rs
+ * Fast resume data, saved next to the downloaded files so a restart does not have to hash
+ * everything again. It is only trusted when every file still has the size and modification
+ * time recorded when it was saved, otherwise the downloader falls back to a full check.
+ */
+use crate::error::Result;
+
+use serde_bytes::ByteBuf;
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// A piece that was being downloaded, `blocks` is a bitfield of the finished blocks.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PartialPiece {
+    pub piece: i64,
+    pub blocks: ByteBuf,
+}
+
+/// Size and modification time (nanoseconds since epoch) of a file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub length: i64,
+    pub mtime: i64,
+}
+
+impl FileStamp {
+    pub fn from_file(file: &File) -> Result<Self> {
+        let metadata = file.metadata()?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as i64)
+            .unwrap_or(0);
+        Ok(Self {
+            length: metadata.len() as i64,
+            mtime,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ResumeData {
+    pub info_hash: ByteBuf,
+    /// Bitfield of verified pieces.
+    pub have: ByteBuf,
+    pub partial: Vec<PartialPiece>,
+    pub files: Vec<FileStamp>,
+    pub uploaded: i64,
+    pub downloaded: i64,
+}
+
+impl ResumeData {
+    /// Read resume data, None if there is no file or it cannot be decoded.
+    pub fn load(path: &Path) -> Option<Self> {
+        let data = fs::read(path).ok()?;
+        serde_bencode::from_bytes::<Self>(&data).ok()
+    }
+
+    /// Write to a temporary file first, a crash while saving must not destroy the old data.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("resume.tmp");
+        fs::write(&tmp_path, serde_bencode::to_bytes(self)?)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Check that resume data belongs to this torrent and files did not change since then.
+    pub fn matches(&self, info_hash: &[u8; 20], files: &[FileStamp]) -> bool {
+        self.info_hash.as_slice() == info_hash && self.files == files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn save_and_load() {
+        let resume = ResumeData {
+            info_hash: ByteBuf::from(vec![1; 20]),
+            have: ByteBuf::from(vec![0b1010_0000]),
+            partial: vec![PartialPiece {
+                piece: 1,
+                blocks: ByteBuf::from(vec![0b1000_0000]),
+            }],
+            files: vec![FileStamp { length: 20, mtime: 1234 }],
+            uploaded: 5,
+            downloaded: 16,
+        };
+        let dir = TempDir::new();
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.resume");
+        resume.save(&path).unwrap();
+        let loaded = ResumeData::load(&path).unwrap();
+        assert_eq!(loaded, resume);
+        assert!(loaded.matches(&[1; 20], &[FileStamp { length: 20, mtime: 1234 }]));
+        assert!(!loaded.matches(&[1; 20], &[FileStamp { length: 20, mtime: 1235 }]));
+    }
+}
@@ -0,0 +1,344 @@
+/*
+ * session.rs
+ * A session runs many torrents at once. They share one peer id, one listen socket (incoming
+ * peers are routed by the info hash of their handshake), one DHT node and the connection and
+ * bandwidth limits. Every torrent runs in its own task and is controlled through its handle.
+ */
+use crate::dht::Dht;
+use crate::error::{Error, Result};
+use crate::events::{Event, EventBus};
+use crate::limits::Limits;
+use crate::listener::{Listener, Routes, LISTEN_PORT};
+use crate::magnet::MagnetLink;
+use crate::meta_info::TorrentInfo;
+use crate::storage::StorageKind;
+use crate::torrent_instance::{TorrentHandle, TorrentInstance};
+use crate::tracker::generate_peer_id;
+
+use futures::future::join_all;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// Torrents still running after this long are left behind by shutdown().
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct SessionConfig {
+    pub listen_port: u16, //TCP for peers, UDP for the DHT node
+    pub dht: bool,
+    pub max_connections: usize, //peer connections of all torrents, 0 for no limit
+    pub download_rate: u64, //bytes per second of all torrents, 0 for no limit
+    pub upload_rate: u64,
+    pub download_dir: PathBuf, //files and resume data of every torrent
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: LISTEN_PORT,
+            dht: true,
+            max_connections: 200,
+            download_rate: 0,
+            upload_rate: 0,
+            download_dir: PathBuf::from("downloads"),
+        }
+    }
+}
+
+pub struct Session {
+    peer_id: [u8; 20],
+    local_addr: SocketAddr,
+    routes: Routes,
+    dht: Option<Dht>,
+    limits: Arc<Limits>,
+    events: EventBus,
+    download_dir: PathBuf,
+    torrents: HashMap<[u8; 20], TorrentHandle>,
+    tasks: Vec<JoinHandle<()>>, //of every torrent started, removed ones included
+}
+
+impl Session {
+    /// Bind the listen socket and start the DHT node, fails if the port is taken.
+    pub async fn new(config: SessionConfig) -> Result<Self> {
+        let peer_id = generate_peer_id();
+        let limits = Arc::new(Limits::new(config.max_connections, config.download_rate, config.upload_rate));
+
+        let mut listener = Listener::bind(config.listen_port, peer_id).await?;
+        listener.set_limits(limits.clone());
+        let local_addr = listener.local_addr()?;
+        let routes = listener.get_routes();
+        tokio::spawn(listener.run());
+
+        let dht = if config.dht {
+            TorrentInstance::start_dht(local_addr.port(), &[]).await
+        } else {
+            None
+        };
+        Ok(Self {
+            peer_id,
+            local_addr,
+            routes,
+            dht,
+            limits,
+            events: EventBus::new(),
+            download_dir: config.download_dir,
+            torrents: HashMap::new(),
+            tasks: Vec::new(),
+        })
+    }
+
+    pub fn get_peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Limits of the session, they can be changed while torrents are running.
+    pub fn get_limits(&self) -> Arc<Limits> {
+        self.limits.clone()
+    }
+
+    /// Receive the events of every torrent of the session, see events::Event.
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Start a torrent in the session.
+    /// return: its info hash, the key of every other call.
+    pub fn add_torrent(&mut self, mut instance: TorrentInstance) -> Result<[u8; 20]> {
+        let info_hash = instance.get_info_hash();
+        if self.torrents.contains_key(&info_hash) {
+            // The instance is dropped, so is the hash check it may have started.
+            if let Some(check) = instance.get_handle().get_check() {
+                check.cancel();
+            }
+            return Err(Error::DuplicateTorrent);
+        }
+        instance.join_session(self.peer_id, self.local_addr.port(), self.dht.clone(), self.routes.clone(), self.limits.clone(), self.events.clone());
+        self.torrents.insert(info_hash, instance.get_handle());
+        let events = self.events.clone();
+        self.tasks.push(tokio::spawn(async move {
+            if let Err(err) = instance.run(-1).await {
+                events.emit(Event::TorrentError { info_hash, message: err.to_string() });
+            }
+        }));
+        Ok(info_hash)
+    }
+
+    /// Open a .torrent file and start it, its files go to the download directory of the session.
+    pub async fn add_torrent_file(&mut self, path: &str) -> Result<[u8; 20]> {
+        let torrent_info = TorrentInfo::from_file(path)?;
+        // Before the files are opened and checked.
+        self.check_new(&torrent_info.get_info_hash())?;
+        let instance = TorrentInstance::open(&torrent_info, &self.download_dir, StorageKind::Disk).await?;
+        self.add_torrent(instance)
+    }
+
+    /// Download the metadata of a magnet link, with the DHT node of the session, and start it.
+    pub async fn add_magnet(&mut self, link: &str) -> Result<[u8; 20]> {
+        let magnet = MagnetLink::parse(link)?;
+        self.check_new(&magnet.info_hash)?;
+        let instance = TorrentInstance::from_magnet_with_dht(magnet, self.dht.clone(), self.local_addr.port(), &self.download_dir).await?;
+        self.add_torrent(instance)
+    }
+
+    fn check_new(&self, info_hash: &[u8; 20]) -> Result<()> {
+        if self.torrents.contains_key(info_hash) {
+            return Err(Error::DuplicateTorrent);
+        }
+        Ok(())
+    }
+
+    /// Stop a torrent and forget it, its files are kept.
+    pub fn remove_torrent(&mut self, info_hash: &[u8; 20]) -> Result<()> {
+        let handle = self.torrents.remove(info_hash).ok_or(Error::UnknownTorrent)?;
+        handle.stop();
+        Ok(())
+    }
+
+    pub fn pause_torrent(&self, info_hash: &[u8; 20]) -> Result<()> {
+        self.get_torrent(info_hash).ok_or(Error::UnknownTorrent)?.pause();
+        Ok(())
+    }
+
+    pub fn resume_torrent(&self, info_hash: &[u8; 20]) -> Result<()> {
+        self.get_torrent(info_hash).ok_or(Error::UnknownTorrent)?.resume();
+        Ok(())
+    }
+
+    /// Pause every torrent, e.g. before the computer goes to sleep.
+    pub fn pause_all(&self) {
+        self.torrents.values().for_each(TorrentHandle::pause);
+    }
+
+    pub fn resume_all(&self) {
+        self.torrents.values().for_each(TorrentHandle::resume);
+    }
+
+    /// Stop every torrent and wait until they have closed their peers, written their data
+    /// and told their trackers, or until SHUTDOWN_TIMEOUT. Return false when some did not stop in time.
+    pub async fn shutdown(mut self) -> bool {
+        self.torrents.values().for_each(TorrentHandle::stop);
+        let tasks = self.tasks.drain(..).collect::<Vec<_>>();
+        time::timeout(SHUTDOWN_TIMEOUT, join_all(tasks)).await.is_ok()
+    }
+
+    pub fn get_torrent(&self, info_hash: &[u8; 20]) -> Option<&TorrentHandle> {
+        self.torrents.get(info_hash)
+    }
+
+    pub fn get_torrents(&self) -> Vec<[u8; 20]> {
+        self.torrents.keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageKind;
+    use crate::torrent_instance::TorrentState;
+    use crate::utils::{to_hex, TempDir};
+    use tokio::net::TcpListener;
+    use tokio::prelude::*;
+    use tokio::sync::mpsc;
+
+    /// Answer every announce and report its event ("none" for a regular one) and port.
+    async fn stand_in_tracker() -> (String, UnboundedReceiver<(String, u16)>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (event_slot, event_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 2048];
+                let len = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                let param = |name: &str| request.split(['&', '?', ' ']).find_map(|param| param.strip_prefix(name)).map(str::to_string);
+                let port = param("port=").and_then(|port| port.parse().ok()).unwrap_or(0);
+                let _ = event_slot.send((param("event=").unwrap_or_else(|| "none".to_string()), port));
+                let body = b"d8:intervali900e5:peers0:e";
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(body).await.unwrap();
+            }
+        });
+        (url, event_rx)
+    }
+
+    #[tokio::test]
+    async fn unknown_torrents_are_rejected() {
+        let config = SessionConfig {
+            listen_port: 0,
+            dht: false,
+            ..SessionConfig::default()
+        };
+        let mut session = Session::new(config).await.unwrap();
+        assert_ne!(session.local_addr().port(), 0);
+        assert_eq!(&session.get_peer_id()[..8], b"-OT0001-");
+        assert!(matches!(session.pause_torrent(&[1; 20]), Err(Error::UnknownTorrent)));
+        assert!(matches!(session.remove_torrent(&[1; 20]), Err(Error::UnknownTorrent)));
+        assert!(session.get_torrents().is_empty());
+    }
+
+    /// A session without DHT, and a torrent announcing to `url` in `dir`.
+    async fn session_with_torrent(url: &str, dir: &std::path::Path) -> (Session, TorrentInstance) {
+        std::fs::create_dir_all(dir).unwrap();
+        let path = dir.join("state.torrent");
+        let mut torrent = format!("d8:announce{}:{}4:infod6:lengthi20e4:name9:state.bin12:piece lengthi16e6:pieces40:", url.len(), url).into_bytes();
+        torrent.extend_from_slice(&[0xaa; 40]);
+        torrent.extend_from_slice(b"ee");
+        std::fs::write(&path, &torrent).unwrap();
+
+        let config = SessionConfig {
+            listen_port: 0,
+            dht: false,
+            ..SessionConfig::default()
+        };
+        let session = Session::new(config).await.unwrap();
+        let instance = TorrentInstance::with_storage(path.to_str().unwrap(), StorageKind::Memory).await.unwrap();
+        (session, instance)
+    }
+
+    #[tokio::test]
+    async fn torrents_announce_state_changes_and_shut_down() {
+        let (url, mut announces) = stand_in_tracker().await;
+        let dir = TempDir::new();
+        let (mut session, instance) = session_with_torrent(&url, &dir).await;
+        let info_hash = session.add_torrent(instance).unwrap();
+        let handle = session.get_torrent(&info_hash).unwrap().clone();
+        // The port the session has bound, not the default one.
+        let port = session.local_addr().port();
+        assert_eq!(announces.recv().await.unwrap(), ("started".to_string(), port));
+        assert_eq!(handle.get_state(), TorrentState::Downloading);
+
+        session.pause_torrent(&info_hash).unwrap();
+        assert_eq!(handle.get_state(), TorrentState::Paused);
+        assert_eq!(announces.recv().await.unwrap().0, "stopped");
+        session.resume_torrent(&info_hash).unwrap();
+        assert_eq!(handle.get_state(), TorrentState::Downloading);
+        assert_eq!(announces.recv().await.unwrap().0, "started");
+
+        assert!(session.shutdown().await);
+        assert_eq!(announces.recv().await.unwrap().0, "stopped");
+        assert_eq!(handle.get_state(), TorrentState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn torrent_files_go_to_the_download_dir_once() {
+        let (url, _announces) = stand_in_tracker().await;
+        let dir = TempDir::new();
+        let (_, instance) = session_with_torrent(&url, &dir).await;
+        drop(instance);
+        let config = SessionConfig {
+            listen_port: 0,
+            dht: false,
+            download_dir: dir.join("data"),
+            ..SessionConfig::default()
+        };
+        let mut session = Session::new(config).await.unwrap();
+        let path = dir.join("state.torrent");
+        let info_hash = session.add_torrent_file(path.to_str().unwrap()).await.unwrap();
+        assert!(matches!(session.add_torrent_file(path.to_str().unwrap()).await, Err(Error::DuplicateTorrent)));
+        assert_eq!(session.get_torrents().len(), 1);
+
+        // Resume data is only saved once the first check is over.
+        let handle = session.get_torrent(&info_hash).unwrap().clone();
+        while handle.get_check().is_some() {
+            time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert!(session.shutdown().await);
+        assert!(dir.join("data").join(format!("{}.resume", to_hex(&info_hash))).exists());
+    }
+
+    #[tokio::test]
+    async fn silent_tracker_does_not_hold_shutdown() {
+        // The tracker accepts connections and never answers.
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+        let dir = TempDir::new();
+        let (mut session, instance) = session_with_torrent(&url, &dir).await;
+        let info_hash = session.add_torrent(instance).unwrap();
+        let handle = session.get_torrent(&info_hash).unwrap().clone();
+        let mut events = handle.subscribe();
+        session.pause_torrent(&info_hash).unwrap();
+        session.resume_torrent(&info_hash).unwrap();
+        assert!(matches!(events.recv().await, Some(Event::StateChanged { state: TorrentState::Paused, .. })));
+
+        let started = std::time::Instant::now();
+        assert!(session.shutdown().await);
+        assert!(started.elapsed() < SHUTDOWN_TIMEOUT);
+        assert_eq!(handle.get_state(), TorrentState::Stopped);
+    }
+}
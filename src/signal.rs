@@ -2,12 +2,29 @@
  * Signal.rs
  * This file use for cross-thread communication (Peer -> Manager)
  */
+use crate::peer::PeerHandle;
 use bit_vec::BitVec;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum Signal {
     Bitfield(BitVec),
     Have(usize), // Raise when Peer recieve have message. (piece)
-    Port(u16),
+    Port(SocketAddr), // DHT node of a peer: its IP address with the port of its Port message.
+    Peers(Vec<SocketAddr>), // Peers found by the DHT or peer exchange.
+    Announced(Vec<SocketAddr>, Duration), // Trackers answered: every peer they gave, and when to announce again.
+    CancelBlock(Vec<String>, (u32, u32, u32)), // A block arrived, the other peers asked for it get a Cancel.
+    PieceHashed(usize, bool), // Disk workers checked a complete piece: index and whether the hash matched.
+    StorageError(String), // Disk workers could not write or read.
+    CheckStarted, // A forced hash check forgot every piece, requests to peers are void.
+    CheckFinished, // Hash check of the data on disk is over, or has been cancelled.
+    PeerConnected(PeerHandle), // Handshake is done, the peer is exchanging messages.
+    PeerDisconnected(String), // Connection with this peer (address) is closed.
+    PeerFailed(SocketAddr), // Dialing this peer failed, or its connection ended with an error.
+    PeerSnubbed(String), // This peer (address) did not send a requested block in time.
+    Pause, // Close the peer connections and wait for Resume.
+    Resume,
+    Stop, // Close the peer connections and end the torrent task.
     Unknown,
 }
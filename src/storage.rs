@@ -0,0 +1,427 @@
+/*
+ * storage.rs
+ * A torrent is a single stream of pieces, but on disk it is split into one or more files.
+ * This file maps piece/block ranges onto (file, offset, length) segments and defines the
+ * Storage trait the downloader reads and writes blocks through. DiskStorage keeps one real
+ * file per file of the torrent, see mem_storage.rs and mmap_storage.rs for the other backends.
+ * DiskStorage creates a file when the first block is written to it. Wanted files are extended
+ * to their final size then, skipped files only get the blocks of pieces they share with wanted ones.
+ */
+use crate::error::Result;
+use crate::mem_storage::MemStorage;
+use crate::meta_info::TorrentInfo;
+use crate::mmap_storage::MmapStorage;
+use crate::resume::FileStamp;
+use sha1::Sha1;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A file of the torrent and where it starts in the piece stream.
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub length: u64,
+    pub offset: u64, //offset of the first byte of this file in the whole torrent.
+}
+
+/// Part of a byte range that lives inside one file.
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    pub file_idx: usize,
+    pub offset: u64, //offset inside the file
+    pub length: usize,
+}
+
+/// Layout of files in the torrent, it knows nothing about the real files on disk.
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    files: Vec<FileEntry>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+/// Where the data of a torrent is kept.
+pub trait Storage: Send {
+    fn get_layout(&self) -> &FileLayout;
+
+    /// Read `length` bytes at `begin` of piece `piece_idx`, the range may span several files.
+    fn read_block(&mut self, piece_idx: usize, begin: u32, length: usize) -> Result<Vec<u8>>;
+
+    /// Write a block at `begin` of piece `piece_idx`, the block may span several files.
+    fn write_block(&mut self, piece_idx: usize, begin: u32, data: &[u8]) -> Result<()>;
+
+    /// SHA1 of a whole piece as it is stored now.
+    fn hash_piece(&mut self, piece_idx: usize) -> Result<[u8; 20]> {
+        let length = self.get_layout().get_piece_length(piece_idx);
+        let data = self.read_block(piece_idx, 0, length as usize)?;
+        Ok(Sha1::from(&data).digest().bytes())
+    }
+
+    /// Make sure everything written so far is stored.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Move the files under another directory.
+    fn move_to(&mut self, base_dir: &Path) -> Result<()>;
+
+    /// Remove every file of the torrent.
+    fn delete(&mut self) -> Result<()>;
+
+    /// Mark a file as wanted or skipped, backends may avoid allocating skipped files.
+    fn set_wanted(&mut self, _file_idx: usize, _wanted: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Size and modification time of every file, used to validate resume data.
+    fn get_file_stamps(&self) -> Result<Vec<FileStamp>>;
+
+    /// False when the data does not survive a restart, resume data is useless then.
+    fn is_persistent(&self) -> bool {
+        true
+    }
+}
+
+/// Storage backends a torrent can be created with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageKind {
+    Disk,
+    Memory, //for tests and simulations, nothing is written to disk
+    Mmap,
+}
+
+impl StorageKind {
+    /// Create the storage of `torrent_info` under `base_dir`.
+    pub fn open(self, torrent_info: &TorrentInfo, base_dir: &Path) -> Result<Box<dyn Storage>> {
+        Ok(match self {
+            StorageKind::Disk => Box::new(DiskStorage::new(torrent_info, base_dir)?),
+            StorageKind::Memory => Box::new(MemStorage::new(torrent_info)),
+            StorageKind::Mmap => Box::new(MmapStorage::new(torrent_info, base_dir)?),
+        })
+    }
+}
+
+pub struct DiskStorage {
+    layout: FileLayout,
+    base_dir: PathBuf,
+    handles: Vec<Option<File>>, //None until the file exists on disk.
+    wanted: Vec<bool>, //false for skipped files, they are not preallocated.
+}
+
+/*Implementation*/
+
+impl FileLayout {
+    pub fn new(torrent_info: &TorrentInfo) -> Self {
+        let mut offset = 0;
+        let files = torrent_info
+            .get_files()
+            .into_iter()
+            .map(|f| {
+                let entry = FileEntry {
+                    path: f.path.iter().collect(),
+                    length: f.length as u64,
+                    offset,
+                };
+                offset += f.length as u64;
+                entry
+            })
+            .collect();
+
+        Self {
+            files,
+            piece_length: torrent_info.get_piece_size(),
+            total_length: offset,
+        }
+    }
+
+    pub fn get_files(&self) -> &Vec<FileEntry> {
+        &self.files
+    }
+
+    pub fn get_total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    /// Length of a piece, the last one may be shorter.
+    pub fn get_piece_length(&self, piece_idx: usize) -> u64 {
+        let start = self.piece_length * piece_idx as u64;
+        std::cmp::min(self.piece_length, self.total_length.saturating_sub(start))
+    }
+
+    /// Return the absolute offset of a block in the torrent.
+    pub fn get_offset(&self, piece_idx: usize, begin: u32) -> u64 {
+        self.piece_length * piece_idx as u64 + begin as u64
+    }
+
+    /// Split a range of the torrent into segments, one for each file it touches.
+    /// Part of the range that lies beyond the end of the torrent is dropped.
+    pub fn map_range(&self, offset: u64, length: usize) -> Vec<Segment> {
+        let end = std::cmp::min(offset + length as u64, self.total_length);
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.length > 0 && f.offset < end && f.offset + f.length > offset)
+            .map(|(file_idx, f)| {
+                let seg_start = std::cmp::max(offset, f.offset);
+                let seg_end = std::cmp::min(end, f.offset + f.length);
+                Segment {
+                    file_idx,
+                    offset: seg_start - f.offset,
+                    length: (seg_end - seg_start) as usize,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Move the files of `layout` from `from` to `to`, copying them when a rename is not possible
+/// (e.g. another file system). Files that do not exist are skipped.
+pub(crate) fn move_files(layout: &FileLayout, from: &Path, to: &Path) -> Result<()> {
+    for entry in layout.get_files() {
+        let (old_path, new_path) = (from.join(&entry.path), to.join(&entry.path));
+        if !old_path.is_file() {
+            continue;
+        }
+        if let Some(parent) = new_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::rename(&old_path, &new_path).is_err() {
+            fs::copy(&old_path, &new_path)?;
+            fs::remove_file(&old_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the files of `layout` under `base_dir`, missing files are fine.
+pub(crate) fn delete_files(layout: &FileLayout, base_dir: &Path) -> Result<()> {
+    for entry in layout.get_files() {
+        match fs::remove_file(base_dir.join(&entry.path)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl DiskStorage {
+    /// Open the files of the torrent that already exist under `base_dir`, the others are
+    /// created when something is written to them.
+    pub fn new(torrent_info: &TorrentInfo, base_dir: &Path) -> Result<Self> {
+        let layout = FileLayout::new(torrent_info);
+        fs::create_dir_all(base_dir)?;
+        let mut handles = Vec::with_capacity(layout.files.len());
+        for entry in &layout.files {
+            let path = base_dir.join(&entry.path);
+            let file = if path.is_file() {
+                Some(OpenOptions::new().read(true).write(true).open(path)?)
+            } else {
+                None
+            };
+            handles.push(file);
+        }
+        let wanted = vec![true; layout.files.len()];
+
+        Ok(Self {
+            layout,
+            base_dir: base_dir.to_path_buf(),
+            handles,
+            wanted,
+        })
+    }
+
+    /// Return the file, create it (and its directories) first if needed.
+    fn open(&mut self, file_idx: usize) -> Result<&mut File> {
+        if self.handles[file_idx].is_none() {
+            let entry = &self.layout.files[file_idx];
+            let path = self.base_dir.join(&entry.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            if self.wanted[file_idx] && file.metadata()?.len() < entry.length {
+                file.set_len(entry.length)?;
+            }
+            self.handles[file_idx] = Some(file);
+        }
+        Ok(self.handles[file_idx].as_mut().unwrap())
+    }
+}
+
+impl Storage for DiskStorage {
+    fn get_layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    /// Parts of files that have not been written yet are read as zeros.
+    fn read_block(&mut self, piece_idx: usize, begin: u32, length: usize) -> Result<Vec<u8>> {
+        let offset = self.layout.get_offset(piece_idx, begin);
+        let mut data = vec![0u8; length];
+        let mut pos = 0;
+        for seg in self.layout.map_range(offset, length) {
+            if let Some(file) = &mut self.handles[seg.file_idx] {
+                file.seek(SeekFrom::Start(seg.offset))?;
+                // A skipped file may be shorter than the range, keep the zeros for the rest.
+                let buf = &mut data[pos..pos + seg.length];
+                let mut filled = 0;
+                while filled < buf.len() {
+                    match file.read(&mut buf[filled..])? {
+                        0 => break,
+                        n => filled += n,
+                    }
+                }
+            }
+            pos += seg.length;
+        }
+        data.truncate(pos);
+        Ok(data)
+    }
+
+    fn write_block(&mut self, piece_idx: usize, begin: u32, data: &[u8]) -> Result<()> {
+        let offset = self.layout.get_offset(piece_idx, begin);
+        let mut pos = 0;
+        for seg in self.layout.map_range(offset, data.len()) {
+            let file = self.open(seg.file_idx)?;
+            file.seek(SeekFrom::Start(seg.offset))?;
+            file.write_all(&data[pos..pos + seg.length])?;
+            pos += seg.length;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for file in self.handles.iter_mut().flatten() {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Files are closed, moved and opened again when they are needed.
+    fn move_to(&mut self, base_dir: &Path) -> Result<()> {
+        self.flush()?;
+        let opened = self.handles.iter().map(|handle| handle.is_some()).collect::<Vec<bool>>();
+        self.handles.iter_mut().for_each(|handle| *handle = None);
+        move_files(&self.layout, &self.base_dir, base_dir)?;
+        self.base_dir = base_dir.to_path_buf();
+        for (file_idx, opened) in opened.into_iter().enumerate() {
+            if opened {
+                self.open(file_idx)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self) -> Result<()> {
+        self.handles.iter_mut().for_each(|handle| *handle = None);
+        delete_files(&self.layout, &self.base_dir)
+    }
+
+    /// A wanted file that already exists is extended to its final size.
+    fn set_wanted(&mut self, file_idx: usize, wanted: bool) -> Result<()> {
+        self.wanted[file_idx] = wanted;
+        if let Some(file) = &self.handles[file_idx] {
+            let length = self.layout.files[file_idx].length;
+            if wanted && file.metadata()?.len() < length {
+                file.set_len(length)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A file that does not exist yet has an empty stamp.
+    fn get_file_stamps(&self) -> Result<Vec<FileStamp>> {
+        self.handles
+            .iter()
+            .map(|handle| match handle {
+                Some(file) => FileStamp::from_file(file),
+                None => Ok(FileStamp { length: 0, mtime: 0 }),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileEntry, FileLayout, Segment, StorageKind};
+    use crate::utils::{sample_torrent, TempDir};
+    use sha1::Sha1;
+    use std::path::PathBuf;
+
+    // Three files of 5, 0 and 12 bytes with 8 bytes pieces.
+    fn sample_layout() -> FileLayout {
+        let files = vec![("a", 5u64, 0u64), ("empty", 0, 5), ("b", 12, 5)]
+            .into_iter()
+            .map(|(name, length, offset)| FileEntry {
+                path: PathBuf::from(name),
+                length,
+                offset,
+            })
+            .collect();
+        FileLayout {
+            files,
+            piece_length: 8,
+            total_length: 17,
+        }
+    }
+
+    #[test]
+    fn map_range_inside_one_file() {
+        let layout = sample_layout();
+        assert_eq!(
+            layout.map_range(layout.get_offset(1, 0), 8),
+            vec![Segment { file_idx: 2, offset: 3, length: 8 }]
+        );
+    }
+
+    #[test]
+    fn map_range_across_files() {
+        let layout = sample_layout();
+        assert_eq!(
+            layout.map_range(layout.get_offset(0, 2), 8),
+            vec![
+                Segment { file_idx: 0, offset: 2, length: 3 },
+                Segment { file_idx: 2, offset: 0, length: 5 },
+            ]
+        );
+    }
+
+    #[test]
+    fn backends_read_back_what_was_written() {
+        // Files of 5 and 12 bytes, 8 bytes pieces: piece 0 spans both files.
+        let (torrent_info, data) = sample_torrent(&[("a", 5), ("b", 12)], 8);
+
+        for kind in [StorageKind::Disk, StorageKind::Memory, StorageKind::Mmap].iter() {
+            let dir = TempDir::new();
+            let mut storage = kind.open(&torrent_info, &dir).unwrap();
+            for (piece_idx, chunk) in data.chunks(8).enumerate() {
+                storage.write_block(piece_idx, 0, chunk).unwrap();
+            }
+            storage.flush().unwrap();
+            assert_eq!(storage.read_block(0, 3, 6).unwrap(), &data[3..9], "{:?}", kind);
+            assert_eq!(storage.read_block(2, 0, 8).unwrap(), &data[16..], "{:?}", kind);
+            assert_eq!(storage.hash_piece(1).unwrap(), Sha1::from(&data[8..16]).digest().bytes());
+
+            let moved = dir.join("moved");
+            storage.move_to(&moved).unwrap();
+            assert_eq!(storage.read_block(0, 0, 8).unwrap(), &data[..8], "{:?}", kind);
+            if *kind != StorageKind::Memory {
+                assert_eq!(std::fs::read(moved.join("dir").join("b")).unwrap(), &data[5..]);
+            }
+            storage.delete().unwrap();
+            assert!(!moved.join("dir").join("b").exists());
+        }
+    }
+
+    #[test]
+    fn map_range_last_piece_is_clamped() {
+        let layout = sample_layout();
+        assert_eq!(
+            layout.map_range(layout.get_offset(2, 0), 8),
+            vec![Segment { file_idx: 2, offset: 11, length: 1 }]
+        );
+    }
+}
@@ -0,0 +1,96 @@
+/*
+ * stream.rs
+ * Streaming mode: read the torrent at any byte offset while it is downloading. A read makes
+ * the pieces it covers, and a few after them, time critical with deadlines, then waits until
+ * they have been verified.
+ */
+use crate::disk_io::DiskIo;
+use crate::downloader::Downloader;
+use crate::error::{Error, Result};
+use crate::picker::PriorityPicker;
+use crate::storage::FileEntry;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Pieces after the ones being read that get a deadline too.
+const STREAM_READAHEAD: usize = 4;
+/// Deadline of a piece is this much later than the one of the piece before it.
+const STREAM_DEADLINE_STEP: Duration = Duration::from_millis(500);
+
+#[derive(Clone)]
+pub struct TorrentStream {
+    downloader: Arc<Mutex<Downloader>>,
+    disk: DiskIo,
+    piece_updates: watch::Receiver<usize>,
+    piece_size: u64,
+    total_length: u64,
+}
+
+impl TorrentStream {
+    /// Switch the torrent to streaming mode, pieces with a deadline are picked first.
+    pub fn new(downloader: Arc<Mutex<Downloader>>) -> Self {
+        let (disk, piece_updates, piece_size, files) = {
+            let mut guard = downloader.lock().unwrap();
+            guard.set_picker(Box::new(PriorityPicker));
+            (guard.get_disk(), guard.subscribe_pieces(), guard.get_piece_size(), guard.get_files())
+        };
+        let total_length = files.last().map(|f| f.offset + f.length).unwrap_or(0);
+        Self {
+            downloader,
+            disk,
+            piece_updates,
+            piece_size,
+            total_length,
+        }
+    }
+
+    pub fn get_files(&self) -> Vec<FileEntry> {
+        self.downloader.lock().unwrap().get_files()
+    }
+
+    pub fn get_piece_size(&self) -> u64 {
+        self.piece_size
+    }
+
+    pub fn get_total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    /// Read `length` bytes at `offset` of the torrent, wait until every piece of the range
+    /// has been downloaded and verified. The range is clamped to the end of the torrent.
+    pub async fn read(&mut self, offset: u64, length: usize) -> Result<Vec<u8>> {
+        if offset >= self.total_length || length == 0 {
+            return Ok(Vec::new());
+        }
+        let length = std::cmp::min(length as u64, self.total_length - offset) as usize;
+        let first = (offset / self.piece_size) as usize;
+        let last = ((offset + length as u64 - 1) / self.piece_size) as usize;
+        self.set_deadlines(first, last + STREAM_READAHEAD);
+
+        loop {
+            let ready = {
+                let downloader = self.downloader.lock().unwrap();
+                (first..=last).all(|piece_idx| downloader.has_piece(piece_idx))
+            };
+            if ready {
+                // The disk workers read it, the range may span several pieces.
+                return self.disk.read_block(first, (offset % self.piece_size) as u32, length).await;
+            }
+            if self.piece_updates.recv().await.is_none() {
+                return Err(Error::StreamError("torrent has been stopped".to_string()));
+            }
+        }
+    }
+
+    /// The first piece is needed now, every next one a bit later.
+    fn set_deadlines(&self, first: usize, last: usize) {
+        let mut downloader = self.downloader.lock().unwrap();
+        let last = std::cmp::min(last, downloader.get_number_of_pieces().saturating_sub(1));
+        let now = Instant::now();
+        for (rank, piece_idx) in (first..=last).enumerate() {
+            downloader.set_piece_deadline(piece_idx, Some(now + STREAM_DEADLINE_STEP * rank as u32));
+        }
+    }
+}
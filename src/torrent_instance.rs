@@ -1,59 +1,839 @@
 /*From this crate*/
-use crate::downloader::Downloader;
+use crate::downloader::{Downloader, FilePriority};
 use crate::{
+    checker::{CheckProgress, HashCheck},
+    choker::{Choker, CHOKE_INTERVAL, UNCHOKE_SLOTS},
+    dht::{self, Dht},
+    downloader::PieceProgress,
     error::{Error, Result},
+    events::{Event, EventBus},
+    extension::{Extension, ExtensionFactory},
+    http_server::HttpServer,
+    limits::Limits,
+    listener::{Listener, Route, Routes, LISTEN_PORT},
+    magnet::MagnetLink,
     meta_info,
-    peer::Peer,
+    metadata,
+    peer::{Peer, PeerCommand},
+    pex::{PexExtension, PexSwarm, FLAG_REACHABLE},
+    picker::PiecePicker,
     signal::Signal,
-    tracker::Tracker,
+    storage::StorageKind,
+    stream::TorrentStream,
+    tracker::{AnnounceEvent, Tracker},
 };
-use bit_vec::BitVec;
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+/// Downloaded files and resume data go there.
+const DOWNLOAD_DIR: &str = "downloads";
+/// Resume data is saved this often, and once more when the torrent stops.
+const RESUME_INTERVAL: Duration = Duration::from_secs(60);
+/// We announce ourselves to the DHT this often.
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Requests not answered after this long are given to other peers.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const REQUEST_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Threads of a hash check, 0 is one per CPU core.
+const CHECK_WORKERS: usize = 0;
+/// How long a stopping torrent waits for its peers to close.
+const PEER_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a stopping torrent waits for trackers to hear it.
+const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bytes left told to trackers while the metadata of a magnet link is fetched, anything
+/// but 0 which would make us a seeder.
+const UNKNOWN_LEFT: u64 = 16384;
+
+/// What the announcer task tells the trackers.
+struct AnnounceRequest {
+    event: AnnounceEvent,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+}
+
+/// What a torrent is doing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentState {
+    Checking(CheckProgress), // Data on disk is checked against the piece hashes.
+    Downloading,
+    Seeding,
+    Paused, // No peer connections until the torrent is resumed.
+    Stopped, // The torrent task has ended, it cannot be resumed.
+    Error(String), // Data could not be written, peers are closed until the torrent is resumed.
+}
+
+/// Control a torrent while it is running, cheap to clone.
+#[derive(Clone)]
+pub struct TorrentHandle {
+    downloader: Arc<Mutex<Downloader>>,
+    check: Arc<Mutex<Option<HashCheck>>>,
+    signal_slot: UnboundedSender<Signal>,
+    state: Arc<Mutex<TorrentState>>,
+    info_hash: [u8; 20],
+    events: EventBus,
+}
+
+impl TorrentHandle {
+    pub fn get_state(&self) -> TorrentState {
+        let state = self.state.lock().unwrap().clone();
+        match (state, self.check.lock().unwrap().as_ref()) {
+            (TorrentState::Checking(_), Some(check)) => TorrentState::Checking(check.get_progress()),
+            (state, _) => state,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), TorrentState::Paused)
+    }
+
+    /// Receive the events of this torrent, see events::Event.
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Checking, downloading or seeding, what the torrent does when nothing stops it.
+    fn active_state(&self) -> TorrentState {
+        if let Some(check) = self.get_check() {
+            return TorrentState::Checking(check.get_progress());
+        }
+        if self.downloader.lock().unwrap().is_complete() {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        }
+    }
+
+    /// Move to `state` if `can_leave` accepts the current one, and tell the subscribers.
+    /// return: true if the state changed.
+    fn set_state_if(&self, can_leave: impl Fn(&TorrentState) -> bool, state: TorrentState) -> bool {
+        {
+            let mut current = self.state.lock().unwrap();
+            if !can_leave(&current) || *current == state {
+                return false;
+            }
+            *current = state;
+        }
+        self.events.emit(Event::StateChanged {
+            info_hash: self.info_hash,
+            state: self.get_state(),
+        });
+        true
+    }
+
+    fn set_state(&self, state: TorrentState) -> bool {
+        self.set_state_if(|_| true, state)
+    }
+
+    /// Close every peer connection and accept no new one until resume() is called.
+    /// Trackers are told we stopped and a running hash check waits too.
+    pub fn pause(&self) {
+        let can_pause = |state: &TorrentState| !matches!(state, TorrentState::Paused | TorrentState::Stopped);
+        if self.set_state_if(can_pause, TorrentState::Paused) {
+            if let Some(check) = self.get_check() {
+                check.pause();
+            }
+            let _ = self.signal_slot.send(Signal::Pause);
+        }
+    }
+
+    /// Go on after pause() or a storage error.
+    pub fn resume(&self) {
+        let can_resume = |state: &TorrentState| matches!(state, TorrentState::Paused | TorrentState::Error(_));
+        if self.set_state_if(can_resume, self.active_state()) {
+            if let Some(check) = self.get_check() {
+                check.resume();
+            }
+            let _ = self.signal_slot.send(Signal::Resume);
+        }
+    }
+
+    /// Close every peer connection, write the data to disk, tell trackers we stopped and end
+    /// the torrent task. The state is Stopped once it is done.
+    pub fn stop(&self) {
+        let _ = self.signal_slot.send(Signal::Stop);
+    }
+
+    /// The hash check in progress, to follow, pause, resume or cancel it.
+    pub fn get_check(&self) -> Option<HashCheck> {
+        self.check.lock().unwrap().clone().filter(|check| !check.is_finished())
+    }
+
+    /// Check every piece on disk again, downloading stops until the check is over.
+    /// Nothing happens if a check is already running, a paused torrent checks once resumed.
+    pub fn force_recheck(&self) -> Result<()> {
+        let mut check = self.check.lock().unwrap();
+        if check.as_ref().map(|check| !check.is_finished()).unwrap_or(false) {
+            return Ok(());
+        }
+        let new_check = HashCheck::start(self.downloader.clone(), CHECK_WORKERS, self.signal_slot.clone())?;
+        if self.is_paused() {
+            new_check.pause();
+        }
+        let progress = new_check.get_progress();
+        *check = Some(new_check);
+        drop(check);
+        let _ = self.signal_slot.send(Signal::CheckStarted);
+        let running = |state: &TorrentState| matches!(state, TorrentState::Downloading | TorrentState::Seeding);
+        self.set_state_if(running, TorrentState::Checking(progress));
+        Ok(())
+    }
+}
 
 pub struct TorrentInstance {
-    tracker: Tracker,
-    peers: HashMap<String, Peer>,
+    tracker: Option<Tracker>, //taken by the announcer task when the torrent starts.
+    announcer: Option<UnboundedSender<AnnounceRequest>>,
+    peer_id: [u8; 20],
+    choker: Choker,
     downloader: Arc<Mutex<Downloader>>,
+    dht: Option<Dht>,
+    dht_nodes: Vec<String>, //bootstrap nodes listed in the torrent
+    private: bool, //no DHT and no peer exchange
+    pex: PexSwarm,
+    extensions: Option<ExtensionFactory>,
+    handle: TorrentHandle,
+    signal_rx: Option<UnboundedReceiver<Signal>>, //taken when the torrent starts.
+    routes: Option<Routes>, //listener shared by the torrents of a session.
+    limits: Arc<Limits>,
+    port: u16, //where we accept peers, announced to trackers, the DHT and peers.
 }
 
 impl TorrentInstance {
     pub async fn new(input: &str) -> Result<Self> {
+        Self::with_storage(input, StorageKind::Disk).await
+    }
+
+    /// Open a .torrent file and keep its data in the given kind of storage.
+    pub async fn with_storage(input: &str, storage: StorageKind) -> Result<Self> {
         let torrent_content = meta_info::TorrentInfo::from_file(input)?;
-        let downloader = Arc::new(Mutex::new(Downloader::new(&torrent_content)?));
-        let tracker = Tracker::from_metainfo(&torrent_content).await?;
+        Self::open(&torrent_content, Path::new(DOWNLOAD_DIR), storage).await
+    }
+
+    /// Start from a parsed torrent, its files go under `base_dir`.
+    pub(crate) async fn open(torrent_content: &meta_info::TorrentInfo, base_dir: &Path, storage: StorageKind) -> Result<Self> {
+        let storage = storage.open(torrent_content, base_dir)?;
+        let downloader = Arc::new(Mutex::new(Downloader::with_storage(torrent_content, base_dir, storage)?));
+        let tracker = Tracker::from_metainfo(torrent_content).await?;
+        let (handle, signal_rx) = Self::make_handle(downloader.clone())?;
         Ok(Self {
-            tracker,
-            peers: HashMap::new(),
+            peer_id: tracker.get_peer_id(),
+            tracker: Some(tracker),
+            announcer: None,
+            choker: Choker::new(UNCHOKE_SLOTS),
             downloader,
+            dht: None,
+            dht_nodes: torrent_content.get_nodes(),
+            private: torrent_content.is_private(),
+            pex: PexSwarm::new(),
+            extensions: None,
+            handle,
+            signal_rx: Some(signal_rx),
+            routes: None,
+            limits: Limits::unlimited(),
+            port: LISTEN_PORT,
         })
     }
 
-    pub async fn update_announce(&mut self, num_want: i32, event: u32) -> Result<()> {
-        self.tracker.connect().await?;
-        self.tracker.announce_request(num_want, event).await?;
+    /// Start from a magnet link: its trackers and peers are used to download the info
+    /// dictionary, then the torrent goes on as if it had been opened from a .torrent file.
+    pub async fn from_magnet(link: &str) -> Result<Self> {
+        // Without trackers or peers in the link, the DHT is the only way to find someone.
+        let magnet = MagnetLink::parse(link)?;
+        let dht = Self::start_dht(LISTEN_PORT, &[]).await;
+        Self::from_magnet_with_dht(magnet, dht, LISTEN_PORT, Path::new(DOWNLOAD_DIR)).await
+    }
+
+    /// Same as from_magnet() but peers are also looked for with the DHT node `dht`, we
+    /// accept peers on `port` and files go under `base_dir`.
+    pub(crate) async fn from_magnet_with_dht(magnet: MagnetLink, dht: Option<Dht>, port: u16, base_dir: &Path) -> Result<Self> {
+        // Every tracker of a magnet link is its own tier.
+        let announce_tiers = magnet.trackers.iter().map(|tr| vec![tr.clone()]).collect::<Vec<Vec<String>>>();
+        let tier_refs = announce_tiers
+            .iter()
+            .map(|tier| tier.iter().map(|s| s.as_str()).collect())
+            .collect::<Vec<Vec<&str>>>();
+        let mut tracker = Tracker::new(magnet.info_hash, &tier_refs).await?;
+        tracker.set_port(port);
 
-        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut candidates = magnet.peers.clone();
+        if !tier_refs.is_empty() {
+            // Only asking for peers: run() sends Started once the size of the torrent is known.
+            // Without trackers the DHT and the peers of the link may be enough.
+            tracker.set_transferred(0, 0, UNKNOWN_LEFT);
+            if tracker.announce_request(-1, AnnounceEvent::None).await.is_ok() {
+                candidates.extend(tracker.get_peers().iter().cloned());
+            }
+        }
+        if let Some(dht) = &dht {
+            candidates.extend(dht.get_peers(magnet.info_hash).await);
+        }
 
-        
-        for peer_addr in self.tracker.get_peers() {
-            let ip_addr = peer_addr.to_string();
-            //let ip_addr = "88.245.98.157:30788".to_string();
-            let peer_id = self.tracker.get_peer_id();
-            let hash_info = self.tracker.get_hash_info();
-            let peer_tx = tx.clone();
-            let cloned_downloader = self.downloader.clone();
+        let info_bytes = metadata::fetch_from_peers(candidates, tracker.get_peer_id(), magnet.info_hash).await?;
+        let torrent_content = meta_info::TorrentInfo::from_info_bytes(&info_bytes, announce_tiers)?;
+        let downloader = Arc::new(Mutex::new(Downloader::with_directory(&torrent_content, base_dir)?));
+        let (handle, signal_rx) = Self::make_handle(downloader.clone())?;
+        Ok(Self {
+            peer_id: tracker.get_peer_id(),
+            tracker: Some(tracker),
+            announcer: None,
+            choker: Choker::new(UNCHOKE_SLOTS),
+            downloader,
+            dht,
+            dht_nodes: Vec::new(),
+            private: torrent_content.is_private(),
+            pex: PexSwarm::new(),
+            extensions: None,
+            handle,
+            signal_rx: Some(signal_rx),
+            routes: None,
+            limits: Limits::unlimited(),
+            port,
+        })
+    }
+
+    /// Bind a DHT node on `port` and bootstrap it from `nodes` and the well known routers.
+    pub(crate) async fn start_dht(port: u16, nodes: &[String]) -> Option<Dht> {
+        let dht = Dht::bind(SocketAddr::from(([0, 0, 0, 0], port))).await.ok()?;
+        let mut hosts = nodes.to_vec();
+        hosts.extend(dht::BOOTSTRAP_NODES.iter().map(|host| host.to_string()));
+        dht.bootstrap(&dht::resolve_nodes(&hosts).await).await;
+        Some(dht)
+    }
 
+    /// Share the downloader with a handle, and check the data on disk when there is no
+    /// resume data to trust. The check runs in the background.
+    fn make_handle(downloader: Arc<Mutex<Downloader>>) -> Result<(TorrentHandle, UnboundedReceiver<Signal>)> {
+        let (signal_slot, signal_rx) = mpsc::unbounded_channel();
+        let (info_hash, complete) = {
+            let downloader = downloader.lock().unwrap();
+            (downloader.get_info_hash(), downloader.is_complete())
+        };
+        let state = if complete { TorrentState::Seeding } else { TorrentState::Downloading };
+        let handle = TorrentHandle {
+            downloader,
+            check: Arc::new(Mutex::new(None)),
+            signal_slot,
+            state: Arc::new(Mutex::new(state)),
+            info_hash,
+            events: EventBus::new(),
+        };
+        let needs_check = handle.downloader.lock().unwrap().needs_check();
+        if needs_check {
+            handle.force_recheck()?;
+        }
+        Ok((handle, signal_rx))
+    }
+
+    /// Run as part of a session: announce with its peer id, take incoming peers from its
+    /// listener, use its DHT node (unless the torrent is private), its limits and its events.
+    pub(crate) fn join_session(&mut self, peer_id: [u8; 20], port: u16, dht: Option<Dht>, routes: Routes, limits: Arc<Limits>, events: EventBus) {
+        self.handle.events = events;
+        if let Some(tracker) = &mut self.tracker {
+            tracker.set_peer_id(peer_id);
+            tracker.set_port(port);
+        }
+        self.peer_id = peer_id;
+        self.port = port;
+        if !self.private {
+            self.dht = dht;
+        }
+        self.routes = Some(routes);
+        self.limits = limits;
+    }
+
+    pub fn get_info_hash(&self) -> [u8; 20] {
+        self.handle.info_hash
+    }
+
+    pub fn get_handle(&self) -> TorrentHandle {
+        self.handle.clone()
+    }
+
+    pub fn get_state(&self) -> TorrentState {
+        self.handle.get_state()
+    }
+
+    /// Receive the events of this torrent, see events::Event.
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        self.handle.subscribe()
+    }
+
+    fn emit(&self, event: Event) {
+        self.handle.events.emit(event);
+    }
+
+    /// Flush the disk and save resume data on a blocking thread, the runtime goes on meanwhile.
+    async fn write_resume(&self) -> Result<()> {
+        let snapshot = match self.downloader.lock().unwrap().resume_snapshot() {
+            Some(snapshot) => snapshot,
+            None => return Ok(()),
+        };
+        task::spawn_blocking(move || snapshot.save())
+            .await
+            .map_err(|err| Error::DiskError(err.to_string()))?
+    }
+
+    async fn save_resume(&self) {
+        if let Err(err) = self.write_resume().await {
+            self.emit(Event::StorageError {
+                info_hash: self.get_info_hash(),
+                message: format!("Cannot save resume data: {}", err),
+            });
+        }
+    }
+
+    /// A complete piece has been checked by the disk workers.
+    /// return: true if it was the last piece we wanted.
+    fn piece_hashed(&self, piece_idx: usize, matched: bool) -> bool {
+        let info_hash = self.get_info_hash();
+        let (progress, files, complete) = {
+            let mut downloader = self.downloader.lock().unwrap();
+            let was_complete = downloader.is_complete();
+            let progress = downloader.piece_checked(piece_idx, matched);
+            let files = match progress {
+                PieceProgress::Verified(_) => downloader.get_completed_files(piece_idx),
+                _ => Vec::new(),
+            };
+            (progress, files, !was_complete && downloader.is_complete())
+        };
+        match progress {
+            PieceProgress::Verified(piece) => self.emit(Event::PieceFinished { info_hash, piece }),
+            // The piece goes back to the picker and is downloaded again.
+            PieceProgress::HashFailed(piece) => self.emit(Event::HashFailed { info_hash, piece }),
+            PieceProgress::InProgress => {}
+        }
+        for file in files {
+            self.emit(Event::FileCompleted { info_hash, file });
+        }
+        if complete {
+            self.handle.set_state_if(|state| *state == TorrentState::Downloading, TorrentState::Seeding);
+        }
+        complete
+    }
+
+    /// Choose how pieces of this torrent are picked, e.g. picker::Sequential for streaming.
+    pub fn set_piece_picker(&self, picker: Box<dyn PiecePicker>) {
+        self.downloader.lock().unwrap().set_picker(picker);
+    }
+
+    /// Choose which files are downloaded and which ones come first, in the order of the torrent.
+    pub async fn set_file_priorities(&self, priorities: &[FilePriority]) -> Result<()> {
+        let (wanted, disk) = {
+            let mut downloader = self.downloader.lock().unwrap();
+            (downloader.apply_file_priorities(priorities), downloader.get_disk())
+        };
+        // Creating files that became wanted is disk work.
+        task::spawn_blocking(move || disk.set_wanted(&wanted))
+            .await
+            .map_err(|err| Error::DiskError(err.to_string()))?
+    }
+
+    /// Switch to streaming mode and return a reader of the torrent data.
+    pub fn stream(&self) -> TorrentStream {
+        TorrentStream::new(self.downloader.clone())
+    }
+
+    /// Serve the files of the torrent over HTTP on `addr` while downloading, return the
+    /// address the server listens on.
+    pub async fn serve_http(&self, addr: SocketAddr) -> Result<SocketAddr> {
+        let server = HttpServer::bind(addr, self.stream()).await?;
+        let local_addr = server.local_addr()?;
+        tokio::spawn(server.run());
+        Ok(local_addr)
+    }
+
+    fn spawn_peer(&self, peer_addr: SocketAddr, peer_tx: UnboundedSender<Signal>) {
+        let ip_addr = peer_addr.to_string();
+        let peer_id = self.peer_id;
+        let hash_info = self.get_info_hash();
+        let cloned_downloader = self.downloader.clone();
+        let mut peer = Peer::new(&ip_addr, peer_tx.clone(), cloned_downloader);
+        if let Some(extensions) = &self.extensions {
+            for extension in extensions(&ip_addr) {
+                peer.register_extension(extension);
+            }
+        }
+        peer.set_limits(self.limits.clone());
+        peer.set_listen_port(self.port);
+
+        tokio::spawn(async move {
+            if peer.send_handshake(peer_id, hash_info).await.is_err() {
+                let _ = peer_tx.send(Signal::PeerFailed(peer_addr));
+            }
+        });
+    }
+
+    /// Let the listener hand us the peers that want this torrent.
+    fn add_route(&self) {
+        if let Some(routes) = &self.routes {
+            let route = Route {
+                signal_slot: self.handle.signal_slot.clone(),
+                download_mutex: self.downloader.clone(),
+                extensions: self.extensions.clone(),
+            };
+            routes.lock().unwrap().insert(self.get_info_hash(), route);
+        }
+    }
+
+    fn remove_route(&self) {
+        if let Some(routes) = &self.routes {
+            routes.lock().unwrap().remove(&self.get_info_hash());
+        }
+    }
+
+    /// Close every peer connection, their tasks send PeerDisconnected when they are done.
+    fn disconnect_peers(&self) {
+        for handle in self.choker.get_handles() {
+            let _ = handle.command_slot.send(PeerCommand::Disconnect);
+        }
+    }
+
+    /// Stop serving peers and write everything to disk, when the torrent pauses or fails.
+    async fn suspend(&self) {
+        self.remove_route();
+        self.disconnect_peers();
+        self.save_resume().await;
+    }
+
+    fn peer_disconnected(&mut self, addr: String) {
+        if let Ok(peer_addr) = addr.parse::<SocketAddr>() {
+            self.pex.remove(&peer_addr);
+        }
+        self.choker.remove_peer(&addr);
+        // Normally done by the peer itself, but the task may have died.
+        self.downloader.lock().unwrap().remove_peer(&addr);
+        self.emit(Event::PeerDisconnected {
+            info_hash: self.get_info_hash(),
+            addr,
+        });
+    }
+
+    /// Give disconnected peers a moment to finish, so that the blocks they were writing
+    /// are on disk before we flush.
+    async fn wait_peers_closed(&mut self, rx: &mut UnboundedReceiver<Signal>) {
+        let deadline = time::Instant::now() + PEER_CLOSE_TIMEOUT;
+        while self.choker.get_handles().next().is_some() {
+            match time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(Signal::PeerDisconnected(addr))) => self.peer_disconnected(addr),
+                Ok(Some(Signal::PeerConnected(handle))) => {
+                    let _ = handle.command_slot.send(PeerCommand::Disconnect);
+                }
+                Ok(Some(Signal::PieceHashed(pie_idx, matched))) => {
+                    self.piece_hashed(pie_idx, matched);
+                }
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => break,
+            }
+        }
+    }
+
+    async fn check_finished(&self) {
+        self.save_resume().await;
+        let checking = |state: &TorrentState| matches!(state, TorrentState::Checking(_));
+        self.handle.set_state_if(checking, self.handle.active_state());
+    }
+
+    /// Announce from a task of its own, one request after the other, so that signals are
+    /// still handled while trackers are slow to answer. Replies come back as Signal::Announced.
+    fn spawn_announcer(&mut self, mut tracker: Tracker, num_want: i32) -> JoinHandle<()> {
+        let (announce_slot, mut announce_rx) = mpsc::unbounded_channel::<AnnounceRequest>();
+        self.announcer = Some(announce_slot);
+        let signal_slot = self.handle.signal_slot.clone();
+        let events = self.handle.events.clone();
+        let info_hash = self.get_info_hash();
+        tokio::spawn(async move {
+            while let Some(request) = announce_rx.recv().await {
+                tracker.set_transferred(request.uploaded, request.downloaded, request.left);
+                // Failures are published as events, peers can still come from the DHT.
+                let since = Instant::now();
+                let _ = tracker.announce_request(num_want, request.event).await;
+                emit_tracker_events(&tracker, &events, info_hash, since);
+                let _ = signal_slot.send(Signal::Announced(tracker.get_peers().clone(), tracker.get_interval()));
+            }
+        })
+    }
+
+    /// Tell the trackers about us and what we transferred so far, see spawn_announcer().
+    fn announce(&self, event: AnnounceEvent) {
+        let request = {
+            let downloader = self.downloader.lock().unwrap();
+            let (uploaded, downloaded) = downloader.get_transferred();
+            AnnounceRequest {
+                event,
+                uploaded,
+                downloaded,
+                left: downloader.get_left(),
+            }
+        };
+        if let Some(announcer) = &self.announcer {
+            let _ = announcer.send(request);
+        }
+    }
+
+    /// Run the torrent until TorrentHandle::stop() is called: wait for the hash check,
+    /// announce to trackers, exchange pieces with peers, then shut down cleanly.
+    pub async fn run(&mut self, num_want: i32) -> Result<()> {
+        let tx = self.handle.signal_slot.clone();
+        let (mut rx, tracker) = match (self.signal_rx.take(), self.tracker.take()) {
+            (Some(rx), Some(tracker)) => (rx, tracker),
+            _ => return Ok(()), // Already started.
+        };
+        if let Some(pieces) = self.downloader.lock().unwrap().resumed_pieces() {
+            self.emit(Event::Resumed { info_hash: self.get_info_hash(), pieces });
+        }
+        // Trackers and peers need to know which pieces we have.
+        while self.handle.get_check().is_some() {
+            match rx.recv().await {
+                Some(Signal::CheckFinished) => self.check_finished().await,
+                Some(Signal::Stop) => {
+                    // No resume data, the check starts over next time.
+                    if let Some(check) = self.handle.get_check() {
+                        check.cancel();
+                    }
+                    self.handle.set_state(TorrentState::Stopped);
+                    return Ok(());
+                }
+                // The handle has paused or resumed the check already, nothing else runs yet.
+                Some(_) => {}
+                None => return Ok(()),
+            }
+        }
+        let mut paused = self.handle.is_paused();
+        let mut announce_timer = announce_interval(tracker.get_interval());
+        let announcer = self.spawn_announcer(tracker, num_want);
+        if !paused {
+            self.announce(AnnounceEvent::Started);
+        }
+
+        if !self.private {
+            let swarm = self.pex.clone();
+            let pex_tx = tx.clone();
+            let extensions: ExtensionFactory = Arc::new(move |addr: &str| {
+                vec![Box::new(PexExtension::new(swarm.clone(), addr, pex_tx.clone())) as Box<dyn Extension>]
+            });
+            self.extensions = Some(extensions);
+        }
+
+        // Serve peers that connect to us, keep downloading even if the port is taken.
+        let in_session = self.routes.is_some();
+        if !in_session {
+            match Listener::bind(self.port, self.peer_id).await {
+                Ok(listener) => {
+                    self.routes = Some(listener.get_routes());
+                    tokio::spawn(listener.run());
+                }
+                Err(err) => self.emit(Event::TorrentError {
+                    info_hash: self.get_info_hash(),
+                    message: format!("Cannot listen on port {}: {}", self.port, err),
+                }),
+            }
+        }
+        if !paused {
+            self.add_route();
+        }
+
+        let mut known_peers = HashSet::new();
+
+        // A session shares its own DHT node.
+        if self.dht.is_none() && !self.private && !in_session {
+            self.dht = Self::start_dht(self.port, &self.dht_nodes).await;
+        }
+        if let Some(dht) = self.dht.clone() {
+            let info_hash = self.get_info_hash();
+            let dht_tx = tx.clone();
+            let port = self.port;
             tokio::spawn(async move {
-                let mut peer = Peer::new(&ip_addr, peer_tx, cloned_downloader);
-                peer.send_handshake(peer_id, hash_info).await;
+                loop {
+                    let peers = dht.announce(info_hash, port).await;
+                    if dht_tx.send(Signal::Peers(peers)).is_err() {
+                        break;
+                    }
+                    time::delay_for(DHT_ANNOUNCE_INTERVAL).await;
+                }
             });
         }
-        while let Some(msg) = rx.recv().await {
-            println!("{:?}", msg);
+        let mut choke_timer = time::interval(CHOKE_INTERVAL);
+        let mut resume_timer = time::interval(RESUME_INTERVAL);
+        let mut request_timer = time::interval(REQUEST_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = rx.recv() => match msg {
+                    Some(Signal::PeerConnected(handle)) => {
+                        if let Ok(addr) = handle.addr.parse::<SocketAddr>() {
+                            // Peers we dialed accept connections, others may not.
+                            let flags = if known_peers.contains(&addr) { FLAG_REACHABLE } else { 0 };
+                            self.pex.add(addr, flags);
+                        }
+                        self.emit(Event::PeerConnected {
+                            info_hash: self.get_info_hash(),
+                            addr: handle.addr.clone(),
+                        });
+                        self.choker.add_peer(handle);
+                    }
+                    Some(Signal::PeerDisconnected(addr)) => {
+                        // Dialed again when a tracker, the DHT or a peer gives it back. Peers
+                        // closed by a pause are kept for Resume.
+                        if !paused {
+                            if let Ok(peer_addr) = addr.parse::<SocketAddr>() {
+                                known_peers.remove(&peer_addr);
+                            }
+                        }
+                        self.peer_disconnected(addr);
+                    }
+                    Some(Signal::PeerFailed(peer_addr)) => {
+                        if !paused {
+                            known_peers.remove(&peer_addr);
+                        }
+                    }
+                    Some(Signal::PeerSnubbed(addr)) => self.emit(Event::PeerSnubbed {
+                        info_hash: self.get_info_hash(),
+                        addr,
+                    }),
+                    Some(Signal::Port(addr)) => {
+                        if let Some(dht) = &self.dht {
+                            dht.add_node(addr);
+                        }
+                    }
+                    Some(Signal::CancelBlock(peers, (pie_idx, begin, length))) => {
+                        for addr in peers {
+                            if let Some(handle) = self.choker.get_handle(&addr) {
+                                let _ = handle.command_slot.send(PeerCommand::Cancel(pie_idx, begin, length));
+                            }
+                        }
+                    }
+                    Some(Signal::PieceHashed(pie_idx, matched)) => {
+                        if self.piece_hashed(pie_idx, matched) && !paused {
+                            self.announce(AnnounceEvent::Completed);
+                        }
+                    }
+                    Some(Signal::StorageError(message)) => {
+                        self.emit(Event::StorageError {
+                            info_hash: self.get_info_hash(),
+                            message: message.clone(),
+                        });
+                        // Nothing more is downloaded until the torrent is resumed.
+                        if !paused {
+                            paused = true;
+                            self.suspend().await;
+                            self.announce(AnnounceEvent::Stopped);
+                        }
+                        self.handle.set_state(TorrentState::Error(message));
+                    }
+                    Some(Signal::CheckStarted) => {
+                        for handle in self.choker.get_handles() {
+                            let _ = handle.command_slot.send(PeerCommand::ResetRequests);
+                        }
+                    }
+                    Some(Signal::CheckFinished) => self.check_finished().await,
+                    Some(Signal::Announced(peers, interval)) => {
+                        announce_timer = announce_interval(interval);
+                        let _ = tx.send(Signal::Peers(peers));
+                    }
+                    Some(Signal::Peers(peers)) => {
+                        // From trackers, the DHT or peer exchange, kept for later while paused.
+                        for peer_addr in peers {
+                            if known_peers.insert(peer_addr) && !paused {
+                                self.spawn_peer(peer_addr, tx.clone());
+                            }
+                        }
+                    }
+                    Some(Signal::Pause) if !paused => {
+                        paused = true;
+                        self.suspend().await;
+                        self.announce(AnnounceEvent::Stopped);
+                    }
+                    Some(Signal::Resume) if paused => {
+                        paused = false;
+                        self.add_route();
+                        for peer_addr in known_peers.iter() {
+                            self.spawn_peer(*peer_addr, tx.clone());
+                        }
+                        self.announce(AnnounceEvent::Started);
+                    }
+                    Some(Signal::Stop) | None => break,
+                    Some(_) => {}
+                },
+                _ = choke_timer.tick(), if !paused => {
+                    let seeding = self.downloader.lock().unwrap().is_complete();
+                    self.choker.run_round(seeding);
+                }
+                _ = request_timer.tick(), if !paused => {
+                    let expired = self.downloader.lock().unwrap().expire_requests(REQUEST_TIMEOUT);
+                    for (addr, (pie_idx, begin, length)) in expired {
+                        if let Some(handle) = self.choker.get_handle(&addr) {
+                            let _ = handle.command_slot.send(PeerCommand::TimedOut(pie_idx, begin, length));
+                        }
+                    }
+                }
+                _ = announce_timer.tick(), if !paused => {
+                    self.announce(AnnounceEvent::None);
+                }
+                _ = resume_timer.tick() => {
+                    self.save_resume().await;
+                }
+            }
         }
 
-        Ok(())
+        // Peers are closed and data is on disk before trackers hear that we stopped.
+        self.remove_route();
+        self.disconnect_peers();
+        self.wait_peers_closed(&mut rx).await;
+        let saved = match self.handle.get_check() {
+            Some(check) => {
+                // No resume data, the check starts over next time.
+                check.cancel();
+                Ok(())
+            }
+            None => self.write_resume().await,
+        };
+        if !paused {
+            self.announce(AnnounceEvent::Stopped);
+        }
+        // The announcer ends once its queue is empty, a dead tracker does not hold us longer.
+        self.announcer = None;
+        let _ = time::timeout(STOPPED_ANNOUNCE_TIMEOUT, announcer).await;
+        self.handle.set_state(TorrentState::Stopped);
+        saved
+    }
+}
+
+/// Ticks when trackers expect the next regular announce.
+fn announce_interval(interval: Duration) -> time::Interval {
+    time::interval_at(time::Instant::now() + interval, interval)
+}
+
+/// Publish what every tracker tried since `since` answered.
+fn emit_tracker_events(tracker: &Tracker, events: &EventBus, info_hash: [u8; 20], since: Instant) {
+    for status in tracker.get_status() {
+        if status.last_attempt.map(|attempt| attempt < since).unwrap_or(true) {
+            continue;
+        }
+        match status.last_error {
+            Some(message) => events.emit(Event::TrackerError {
+                info_hash,
+                url: status.url,
+                message,
+            }),
+            None => {
+                if let Some(message) = status.last_warning {
+                    events.emit(Event::TrackerWarning {
+                        info_hash,
+                        url: status.url.clone(),
+                        message,
+                    });
+                }
+                events.emit(Event::TrackerReply {
+                    info_hash,
+                    url: status.url,
+                    peers: status.peers_returned,
+                });
+            }
+        }
     }
 }
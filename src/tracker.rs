@@ -1,242 +1,379 @@
-use bincode::*;
-use bit_vec::BitVec;
+use futures::future::join_all;
 use rand::prelude::*;
-use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
-use tokio::net::UdpSocket;
-use tokio::prelude::*;
+use std::time::{Duration, Instant};
+use tokio::time;
 use url::Url;
 
 //modules in the same crate
 use crate::error::{Error, Result};
+use crate::http_tracker::HttpTracker;
+use crate::listener::LISTEN_PORT;
 use crate::meta_info::TorrentInfo;
-use crate::peer::Peer;
+use crate::udp_tracker::UdpTracker;
 use crate::utils::random_string;
 
 /*
  * This file contains all tracker related code.
+ * Trackers are grouped in tiers (BEP 12): tiers are tried in order, trackers inside a tier are
+ * shuffled once and the one that answers is moved to the front of its tier.
  */
 
-//static BIND_ADDR: &'static str = "127.0.0.1:11993";
-static BIND_ADDR: &'static str = "0.0.0.0:0";
-static CONSTANT_CLIENT_ID: &'static str = "-OT0001-";
+static CONSTANT_CLIENT_ID: &str = "-OT0001-";
+/// A tracker that has not answered by then is given up for the next one, UDP trackers
+/// would otherwise retransmit for hours.
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(60);
+/// Announce interval used until a tracker tells us its own.
+const DEFAULT_INTERVAL: u32 = 30 * 60;
 
-#[derive(Serialize, Debug)]
-struct ConnectRequest {
-    connection_id: u64,
-    action: u32,
-    transaction_id: u32,
+/// Why we announce, trackers use it for their statistics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnounceEvent {
+    None, // Regular announce.
+    Completed,
+    Started,
+    Stopped,
 }
 
-#[derive(Deserialize, Debug)]
-struct ConnectResponse {
-    action: u32,
-    transaction_id: u32,
-    connection_id: u64,
+impl AnnounceEvent {
+    /// Number of the event in UDP announces.
+    pub fn id(self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+
+    /// Value of the `event` parameter in HTTP announces, None for a regular one.
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
 }
 
-#[derive(Serialize, Debug)]
-struct AnnounceRequest {
-    connection_id: u64, //connection id: generated by tracker and it will send this field to the client in connection response.
-    action: u32,        //action id: 1 for announce
-    transaction_id: u32, //transaction id: randomaly
-    info_hash: [u8; 20], //this is sha-1 hash of info section in torrent file.
-    peer_id: [u8; 20],  //peer id
-    downloaded: u64,
-    left: u64,
-    uploaded: u64,
-    event: u32,
-    ip_address: u32,
-    key: u32,
-    num_want: i32,
-    port: u32, //we will listen on 11993
+/// Everything a tracker needs to know about us in an announce, whatever the protocol is.
+#[derive(Clone)]
+pub struct AnnounceParams {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub num_want: i32,
+    pub key: u32,
+    pub tracker_id: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
-struct IpAddr {
-    ip: u32,
-    port: u16,
+/// What a tracker answered to an announce.
+#[derive(Debug, Default)]
+pub struct AnnounceReply {
+    pub interval: u32,
+    pub min_interval: Option<u32>,
+    pub tracker_id: Option<String>, // HTTP only, must be sent back in next announces.
+    pub warning: Option<String>,
+    pub seeders: u32,
+    pub leechers: u32,
+    pub peers: Vec<SocketAddr>,
 }
 
-#[derive(Deserialize, Debug)]
-struct AnnounceResponse {
-    action: u32,
-    transaction_id: u32,
-    interval: u32,
-    leechers: u32,
-    seeders: u32,
+enum TrackerClient {
+    Udp(UdpTracker),
+    Http(HttpTracker),
+}
+
+/// What we know about one tracker from the last announces.
+#[derive(Debug, Clone)]
+pub struct TrackerStatus {
+    pub url: String,
+    pub tier: usize,
+    pub last_attempt: Option<Instant>, //last announce, successful or not
+    pub last_announce: Option<Instant>, //last successful announce
+    pub last_error: Option<String>,     //error of the last announce, None if it succeeded
+    pub last_warning: Option<String>,   //warning message of the last successful announce
+    pub peers_returned: usize,          //number of peers in the last reply
+    pub seeders: u32,                   //swarm size given in the last reply
+    pub leechers: u32,
+}
+
+struct TrackerEntry {
+    client: TrackerClient,
+    status: TrackerStatus,
+    tracker_id: Option<String>, //given by this tracker, sent back in the next announces
 }
 
 pub struct Tracker {
-    socket: UdpSocket,
-    connection_id: Option<u64>, //It is not always here
-    announce_list: Vec<SocketAddr>,
+    tiers: Vec<Vec<TrackerEntry>>,
+    announce_to_all_tiers: bool,
     peer_id: [u8; 20],
     hash_info: [u8; 20],
     downloaded: u64,
     uploaded: u64,
     left: u64,
-    seeder: u32,
-    leecher: u32,
     interval: u32,
-    peers: Vec<String>, //    downloaded: u64,
-                        //    left: u64,
-                        //    peer_info: BitVec, //My information
+    min_interval: Option<u32>,
+    peers: Vec<SocketAddr>, //from the last announce, every tier that answered
+    tracker_timeout: Duration, //longest wait for one tracker
+    port: u16,                 //where peers can connect to us
 }
 
-impl Tracker {
-    ///@param: meta_info MetaInfo struct of this torrent.
-    pub async fn from_metainfo(meta_info: &TorrentInfo) -> Result<Self> {
-        let mut announce_list = Vec::new();
-        let announce_urls = meta_info.get_announce();
+impl TrackerEntry {
+    async fn new(url: &str, tier: usize) -> Option<Self> {
+        let base_url = Url::parse(url).ok()?;
+        let client = match base_url.scheme() {
+            "udp" => {
+                // e.g. an IPv6 tracker when we only have IPv4, so try every address.
+                let mut udp_tracker = None;
+                for addr in base_url.socket_addrs(|| None).ok()? {
+                    if let Ok(client) = UdpTracker::new(addr).await {
+                        udp_tracker = Some(client);
+                        break;
+                    }
+                }
+                TrackerClient::Udp(udp_tracker?)
+            }
+            "http" | "https" => TrackerClient::Http(HttpTracker::new(base_url).ok()?),
+            _ => return None,
+        };
+
+        Some(Self {
+            client,
+            status: TrackerStatus {
+                url: url.to_string(),
+                tier,
+                last_attempt: None,
+                last_announce: None,
+                last_error: None,
+                last_warning: None,
+                peers_returned: 0,
+                seeders: 0,
+                leechers: 0,
+            },
+            tracker_id: None,
+        })
+    }
 
-        for aurl in announce_urls {
-            if &aurl[..3] != "udp" {
-                continue;
+    async fn announce(&mut self, params: &AnnounceParams, max_wait: Duration) -> Result<AnnounceReply> {
+        self.status.last_attempt = Some(Instant::now());
+        let params = AnnounceParams {
+            tracker_id: self.tracker_id.clone(),
+            ..params.clone()
+        };
+        let reply = async {
+            match &mut self.client {
+                TrackerClient::Udp(udp_tracker) => udp_tracker.announce(&params).await,
+                TrackerClient::Http(http_tracker) => http_tracker.announce(&params).await,
             }
-            if let Ok(base_url) = Url::parse(aurl) {
-                let sock_addrs = base_url.socket_addrs(|| None);
-                if sock_addrs.is_ok() {
-                    announce_list.extend(sock_addrs.unwrap().iter().clone());
+        };
+        let result = match time::timeout(max_wait, reply).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::TrackerError("Tracker timed out".to_string())),
+        };
+        match &result {
+            Ok(reply) => {
+                self.status.last_announce = Some(Instant::now());
+                self.status.last_error = None;
+                self.status.last_warning = reply.warning.clone();
+                self.status.peers_returned = reply.peers.len();
+                self.status.seeders = reply.seeders;
+                self.status.leechers = reply.leechers;
+                if reply.tracker_id.is_some() {
+                    self.tracker_id = reply.tracker_id.clone();
                 }
             }
+            Err(err) => {
+                self.status.last_error = Some(err.to_string());
+            }
+        }
+        result
+    }
+}
+
+/// Try trackers of a tier in order until one answers, then move it to the front of the tier.
+async fn announce_tier(tier: &mut Vec<TrackerEntry>, params: &AnnounceParams, max_wait: Duration) -> Result<AnnounceReply> {
+    let mut last_error = Error::TrackerError("No tracker available".to_string());
+    for idx in 0..tier.len() {
+        match tier[idx].announce(params, max_wait).await {
+            Ok(reply) => {
+                let entry = tier.remove(idx);
+                tier.insert(0, entry);
+                return Ok(reply);
+            }
+            Err(err) => last_error = err,
         }
+    }
+    Err(last_error)
+}
 
-        let socket = UdpSocket::bind(&BIND_ADDR.parse::<SocketAddr>()?).await?;
+//Create a peer_id:
+//Firstly I generate 20 random byte, then replace first 8 bytes with -OT0001- (Oni torrent
+//version 0.01)
+pub(crate) fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id: [u8; 20] = Default::default();
+    let mut random_id = random_string(12);
+    random_id.insert_str(0, CONSTANT_CLIENT_ID);
+    let random_id = random_id.as_bytes();
+    peer_id.copy_from_slice(&random_id[0..]);
+    peer_id
+}
+
+impl Tracker {
+    ///@param: meta_info MetaInfo struct of this torrent.
+    pub async fn from_metainfo(meta_info: &TorrentInfo) -> Result<Self> {
+        Self::new(meta_info.get_info_hash(), &meta_info.get_announce_tiers()).await
+    }
 
-        //Create a peer_id:
-        //Firstly I generate 20 random byte, then replace first 8 bytes with -OT0001- (Oni torrent
-        //version 0.01)
-        let mut peer_id: [u8; 20] = Default::default();
-        let mut random_id = random_string(12);
-        random_id.insert_str(0, CONSTANT_CLIENT_ID);
-        let random_id = random_id.as_bytes();
-        peer_id.copy_from_slice(&random_id[0..]);
+    /// Create a tracker from announce urls grouped by tier. Urls that cannot be used (bad
+    /// scheme, cannot be resolved) are dropped.
+    pub async fn new(hash_info: [u8; 20], announce_tiers: &[Vec<&str>]) -> Result<Self> {
+        let mut tiers = Vec::new();
+        for urls in announce_tiers {
+            let mut tier = Vec::new();
+            for url in urls {
+                if let Some(entry) = TrackerEntry::new(url, tiers.len()).await {
+                    tier.push(entry);
+                }
+            }
+            if !tier.is_empty() {
+                tier.shuffle(&mut rand::thread_rng());
+                tiers.push(tier);
+            }
+        }
 
         Ok(Self {
-            socket,
-            connection_id: None,
-            announce_list,
-            peer_id,
-            hash_info: meta_info.get_info_hash(),
+            tiers,
+            announce_to_all_tiers: false,
+            peer_id: generate_peer_id(),
+            hash_info,
             downloaded: 0,
             uploaded: 0,
             left: 0,
-            leecher: 0,
-            seeder: 0,
             interval: 0,
+            min_interval: None,
             peers: Vec::new(),
+            tracker_timeout: TRACKER_TIMEOUT,
+            port: LISTEN_PORT,
         })
     }
 
-    pub async fn connect(&mut self) -> Result<()> {
-        //create a connect message and send it to tracker
-        let mut rng = rand::thread_rng();
-        let request_pkt = ConnectRequest {
-            transaction_id: rng.gen(),
-            connection_id: 0x41727101980,
-            action: 0x0, //connect
-        };
-
-        let encoded_pkt: Vec<u8> = bincode::config().big_endian().serialize(&request_pkt)?;
-        println!("Sending request");
-        for sock_addr in &self.announce_list {
-            if let Ok(written_bytes) = self.socket.send_to(&encoded_pkt, sock_addr).await {
-                if written_bytes == encoded_pkt.len() {
-                    self.socket.connect(sock_addr).await?;
-                    println!("Connected to addr: {}", &sock_addr);
-                    break;
-                }
-            }
-        }
-        println!("Waiting for response");
-
-        let data_size = std::mem::size_of::<ConnectResponse>();
-        let mut data = vec![0u8; data_size];
-        let len = self.socket.recv(&mut data).await?;
-        let decoded_pkt: ConnectResponse = bincode::config().big_endian().deserialize(&data)?;
-        println!("Connect response: {:?}", &decoded_pkt);
-        //Finish connect, save connection_id for later using
-        self.connection_id = Some(decoded_pkt.connection_id);
-        Ok(())
+    /// When set, every tier is announced to at the same time instead of stopping at the first
+    /// tier that answers.
+    pub fn set_announce_to_all_tiers(&mut self, value: bool) {
+        self.announce_to_all_tiers = value;
     }
 
     /// Function send request to tracker to get a list of swarms.
     /// @param num_want: Number of peers that client want to receive from tracker (use -1 for
     /// default)
-    /// @event: Can leave empty (0) or it need to be started, stopped, or completed
-    pub async fn announce_request(&mut self, num_want: i32, event: u32) -> Result<()> {
-        let mut rng = rand::thread_rng();
-        let announce_request = AnnounceRequest {
-            connection_id: self.connection_id.unwrap(),
-            action: 1,
-            transaction_id: rng.gen(),
+    /// @event: Why we announce, see AnnounceEvent
+    pub async fn announce_request(&mut self, num_want: i32, event: AnnounceEvent) -> Result<()> {
+        let params = AnnounceParams {
             info_hash: self.hash_info,
             peer_id: self.peer_id,
-            downloaded: self.downloaded,
+            port: self.port,
             uploaded: self.uploaded,
+            downloaded: self.downloaded,
             left: self.left,
             event,
-            ip_address: 0,
-            key: rng.gen(),
             num_want,
-            port: 6881,
+            key: rand::thread_rng().gen(),
+            tracker_id: None, //each tracker sends its own
         };
+        self.peers.clear();
 
-        let encoded_pkt: Vec<u8> = bincode::config()
-            .big_endian()
-            .serialize(&announce_request)?;
-        println!("Sending announce request");
-        self.socket.send(&encoded_pkt).await?;
-
-        let mut data = vec![0u8; 2048];
-        let len = self.socket.recv(&mut data).await?;
-        let decoded_pkt: AnnounceResponse =
-            bincode::config().big_endian().deserialize(&data[..20])?;
-        println!("The interval: {}", decoded_pkt.interval);
-        let mut pos = 20;
-
-        //Separate
-        loop {
-            if (pos + 4) >= len {
-                break;
+        let max_wait = self.tracker_timeout;
+        let results = if self.announce_to_all_tiers {
+            join_all(self.tiers.iter_mut().map(|tier| announce_tier(tier, &params, max_wait))).await
+        } else {
+            let mut results = Vec::new();
+            for tier in self.tiers.iter_mut() {
+                let result = announce_tier(tier, &params, max_wait).await;
+                let answered = result.is_ok();
+                results.push(result);
+                if answered {
+                    break;
+                }
             }
+            results
+        };
 
-            let mut ip_addr = [0u8; 4];
-            let mut port_addr = [0u8; 2];
-            ip_addr.copy_from_slice(&data[pos..pos + 4]);
-            pos += 4;
-            port_addr.copy_from_slice(&data[pos..pos + 2]);
-            pos += 2;
-            let ip_str = ip_addr
-                .to_vec()
-                .iter()
-                .enumerate()
-                .map(|(idx, val)| {
-                    if idx < 3 {
-                        format!("{}.", val)
-                    } else {
-                        format!("{}", val)
-                    }
-                })
-                .collect::<String>();
-            /*
-            tokio::spawn(async move {
-                let ip_addr = format!("{}:{}", ip_str, u16::from_be_bytes(port_addr));
-                let mut peer = Peer::new(&ip_addr);
-                peer.send_handshake(peer_id, hash_info).await;
-            });
-            */
-            self.peers
-                .push(format!("{}:{}", ip_str, u16::from_be_bytes(port_addr)));
+        let mut last_error = Error::TrackerError("No tracker available".to_string());
+        let mut answered = false;
+        for result in results {
+            match result {
+                Ok(reply) => {
+                    self.apply_reply(reply, !answered);
+                    answered = true;
+                }
+                Err(err) => last_error = err,
+            }
+        }
+        if answered {
+            Ok(())
+        } else {
+            Err(last_error)
         }
-        Ok(())
     }
 
-    pub fn get_peers(&self) -> &Vec<String> {
+    // Interval comes from the first tier that answered, peers from every tier.
+    fn apply_reply(&mut self, reply: AnnounceReply, first: bool) {
+        if first {
+            self.interval = reply.interval;
+            self.min_interval = reply.min_interval;
+        }
+        for peer in reply.peers {
+            if !self.peers.contains(&peer) {
+                self.peers.push(peer);
+            }
+        }
+    }
+
+    /// Status of every tracker, in tier order.
+    pub fn get_status(&self) -> Vec<TrackerStatus> {
+        self.tiers
+            .iter()
+            .flat_map(|tier| tier.iter().map(|entry| entry.status.clone()))
+            .collect()
+    }
+
+    /// How long to wait before the next regular announce.
+    pub fn get_interval(&self) -> Duration {
+        let interval = match self.interval {
+            0 => DEFAULT_INTERVAL,
+            interval => interval.max(self.min_interval.unwrap_or(0)),
+        };
+        Duration::from_secs(interval as u64)
+    }
+
+    /// Peers given by the last announce.
+    pub fn get_peers(&self) -> &Vec<SocketAddr> {
         self.peers.as_ref()
     }
 
+    /// Update the totals reported in the next announce.
+    pub fn set_transferred(&mut self, uploaded: u64, downloaded: u64, left: u64) {
+        self.uploaded = uploaded;
+        self.downloaded = downloaded;
+        self.left = left;
+    }
+
+    /// Announce ourselves with another peer id, e.g. the one of a session.
+    pub fn set_peer_id(&mut self, peer_id: [u8; 20]) {
+        self.peer_id = peer_id;
+    }
+
+    /// Announce another listen port, e.g. the one a session has bound.
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
     pub fn get_peer_id(&self) -> [u8; 20] {
         self.peer_id
     }
@@ -245,3 +382,113 @@ impl Tracker {
         self.hash_info
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AnnounceEvent, Tracker};
+    use std::time::Duration;
+    use tokio::net::{TcpListener, UdpSocket};
+    use tokio::prelude::*;
+
+    const REPLY: &[u8] = b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+
+    /// Answer one announce with `body`, return the request line.
+    async fn stand_in_http(listener: &mut TcpListener, body: &[u8]) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        let len = stream.read(&mut buf).await.unwrap();
+        let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+        stream.write_all(header.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+        String::from_utf8_lossy(&buf[..len]).lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn unresponsive_tracker_is_given_up() {
+        // The UDP tracker never answers, the next one of the tier gets its turn in time.
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_url = format!("udp://{}", silent.local_addr().unwrap());
+        let alive_url = format!("http://{}/announce", listener.local_addr().unwrap());
+
+        let mut tracker = Tracker::new([0; 20], &[vec![&silent_url, &alive_url]]).await.unwrap();
+        if tracker.tiers[0][0].status.url != silent_url {
+            tracker.tiers[0].swap(0, 1);
+        }
+        tracker.tracker_timeout = Duration::from_millis(200);
+        let (_, result) = futures::join!(stand_in_http(&mut listener, REPLY), tracker.announce_request(-1, AnnounceEvent::Started));
+        assert!(result.is_ok());
+
+        let status = tracker.get_status();
+        assert_eq!(status[0].url, alive_url);
+        assert_eq!(status[1].last_error.as_deref(), Some("Tracker error: Tracker timed out"));
+    }
+
+    #[tokio::test]
+    async fn responding_tracker_is_promoted() {
+        // A closed port in the first tier and a working tracker after it in the same tier.
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_url = format!("http://{}/announce", dead);
+        let alive_url = format!("http://{}/announce", listener.local_addr().unwrap());
+
+        let mut tracker = Tracker::new([0; 20], &[vec![&dead_url, &alive_url], vec![&dead_url]]).await.unwrap();
+        // Make sure the dead tracker is tried first whatever the shuffle did.
+        if tracker.tiers[0][0].status.url != dead_url {
+            tracker.tiers[0].swap(0, 1);
+        }
+
+        let (_, result) = futures::join!(stand_in_http(&mut listener, REPLY), tracker.announce_request(-1, AnnounceEvent::Started));
+        assert!(result.is_ok());
+
+        let status = tracker.get_status();
+        assert_eq!(status[0].url, alive_url);
+        assert_eq!(status[0].peers_returned, 1);
+        assert!(status[0].last_announce.is_some());
+        assert!(status[1].last_error.is_some());
+        // Second tier is not used because the first one answered.
+        assert!(status[2].last_announce.is_none() && status[2].last_error.is_none());
+        assert_eq!(tracker.get_peers(), &vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(tracker.get_interval(), Duration::from_secs(900));
+        assert!(status[0].last_warning.is_none());
+    }
+
+    #[tokio::test]
+    async fn tracker_warning_is_kept_in_status() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let mut tracker = Tracker::new([0; 20], &[vec![&url]]).await.unwrap();
+
+        let body = b"d8:intervali900e5:peers0:15:warning message4:slowe";
+        let (_, result) = futures::join!(stand_in_http(&mut listener, body), tracker.announce_request(-1, AnnounceEvent::Started));
+        assert!(result.is_ok());
+        assert_eq!(tracker.get_status()[0].last_warning.as_deref(), Some("slow"));
+    }
+
+    #[tokio::test]
+    async fn tracker_id_goes_back_to_its_own_tracker() {
+        let mut first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let first_url = format!("http://{}/announce", first.local_addr().unwrap());
+        let second_url = format!("http://{}/announce", second.local_addr().unwrap());
+        let mut tracker = Tracker::new([0; 20], &[vec![&first_url], vec![&second_url]]).await.unwrap();
+        tracker.set_announce_to_all_tiers(true);
+
+        let with_id = b"d8:intervali900e5:peers0:10:tracker id3:xyze";
+        let (_, _, result) = futures::join!(
+            stand_in_http(&mut first, with_id),
+            stand_in_http(&mut second, REPLY),
+            tracker.announce_request(-1, AnnounceEvent::Started)
+        );
+        assert!(result.is_ok());
+        let (first_request, second_request, _) = futures::join!(
+            stand_in_http(&mut first, with_id),
+            stand_in_http(&mut second, REPLY),
+            tracker.announce_request(-1, AnnounceEvent::None)
+        );
+        assert!(first_request.contains("&trackerid=xyz"));
+        assert!(!second_request.contains("trackerid"));
+        // Peers of every tier that answered the last announce, once.
+        assert_eq!(tracker.get_peers(), &vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+}
@@ -0,0 +1,303 @@
+/*
+ * udp_tracker.rs
+ * UDP tracker protocol (BEP 15): connect/announce with retransmission, transaction id
+ * checking and connection id expiry.
+ */
+use bincode::Options;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::error::{Error, Result};
+use crate::tracker::{AnnounceParams, AnnounceReply};
+use crate::utils::{big_endian, parse_compact_v4};
+
+static BIND_ADDR: &str = "0.0.0.0:0";
+const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+/// Request is retransmitted after 15 * 2 ^ n seconds, n goes from 0 to 8.
+const BASE_TIMEOUT: u64 = 15;
+const MAX_RETRANSMIT: u32 = 8;
+/// A connection id can be used for one minute after it has been received.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Debug)]
+struct ConnectRequest {
+    connection_id: u64,
+    action: u32,
+    transaction_id: u32,
+}
+
+/// Connect response after its action and transaction id, checked by recv_response().
+#[derive(Deserialize, Debug)]
+struct ConnectResponse {
+    connection_id: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct AnnounceRequest {
+    connection_id: u64, //connection id: generated by tracker and it will send this field to the client in connection response.
+    action: u32,        //action id: 1 for announce
+    transaction_id: u32, //transaction id: randomaly
+    info_hash: [u8; 20], //this is sha-1 hash of info section in torrent file.
+    peer_id: [u8; 20],  //peer id
+    downloaded: u64,
+    left: u64,
+    uploaded: u64,
+    event: u32,
+    ip_address: u32,
+    key: u32,
+    num_want: i32,
+    port: u16,
+}
+
+/// Announce response after its action and transaction id, the peers follow.
+#[derive(Deserialize, Debug)]
+struct AnnounceResponse {
+    interval: u32,
+    leechers: u32,
+    seeders: u32,
+}
+
+pub struct UdpTracker {
+    addr: SocketAddr,
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>, //connection id and when we received it.
+    base_timeout: Duration,
+}
+
+impl UdpTracker {
+    pub async fn new(addr: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(&BIND_ADDR.parse::<SocketAddr>()?).await?;
+        socket.connect(addr).await?;
+        Ok(Self {
+            addr,
+            socket,
+            connection: None,
+            base_timeout: Duration::from_secs(BASE_TIMEOUT),
+        })
+    }
+
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Send a connect request and wait for the connection id, retransmitting on timeout.
+    pub async fn connect(&mut self) -> Result<u64> {
+        let transaction_id = rand::thread_rng().gen();
+        for n in 0..=MAX_RETRANSMIT {
+            if let Some(connection_id) = self.try_connect(transaction_id, n).await? {
+                return Ok(connection_id);
+            }
+        }
+        Err(self.timed_out())
+    }
+
+    /// Announce to this tracker. A new connection id is requested whenever the current one is
+    /// older than one minute, including between retransmissions. Connect and announce share
+    /// the retransmission count, each keeps its transaction id so a late reply still counts.
+    pub async fn announce(&mut self, params: &AnnounceParams) -> Result<AnnounceReply> {
+        let connect_transaction_id = rand::thread_rng().gen();
+        let transaction_id = rand::thread_rng().gen();
+        for n in 0..=MAX_RETRANSMIT {
+            let connection_id = match self.connection {
+                Some((connection_id, received)) if received.elapsed() < CONNECTION_ID_TTL => connection_id,
+                _ => match self.try_connect(connect_transaction_id, n).await? {
+                    Some(connection_id) => connection_id,
+                    None => continue,
+                },
+            };
+            let announce_request = AnnounceRequest {
+                connection_id,
+                action: ACTION_ANNOUNCE,
+                transaction_id,
+                info_hash: params.info_hash,
+                peer_id: params.peer_id,
+                downloaded: params.downloaded,
+                uploaded: params.uploaded,
+                left: params.left,
+                event: params.event.id(),
+                ip_address: 0,
+                key: params.key,
+                num_want: params.num_want,
+                port: params.port,
+            };
+            let encoded_pkt: Vec<u8> = big_endian().serialize(&announce_request)?;
+            self.socket.send(&encoded_pkt).await?;
+
+            if let Some(data) = self.recv_response(transaction_id, ACTION_ANNOUNCE, 20, n).await? {
+                let decoded_pkt: AnnounceResponse = big_endian().deserialize(&data[8..20])?;
+                return Ok(AnnounceReply {
+                    interval: decoded_pkt.interval,
+                    seeders: decoded_pkt.seeders,
+                    leechers: decoded_pkt.leechers,
+                    peers: parse_compact_v4(&data[20..]),
+                    ..Default::default()
+                });
+            }
+        }
+        Err(self.timed_out())
+    }
+
+    /// Send one connect request and wait for its reply during the n-th timeout window.
+    async fn try_connect(&mut self, transaction_id: u32, n: u32) -> Result<Option<u64>> {
+        let request_pkt = ConnectRequest {
+            connection_id: PROTOCOL_ID,
+            action: ACTION_CONNECT,
+            transaction_id,
+        };
+        let encoded_pkt: Vec<u8> = big_endian().serialize(&request_pkt)?;
+        self.socket.send(&encoded_pkt).await?;
+
+        match self.recv_response(transaction_id, ACTION_CONNECT, 16, n).await? {
+            Some(data) => {
+                let decoded_pkt: ConnectResponse = big_endian().deserialize(&data[8..16])?;
+                self.connection = Some((decoded_pkt.connection_id, Instant::now()));
+                Ok(Some(decoded_pkt.connection_id))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Wait for the response of transaction `transaction_id` during the n-th timeout window.
+    /// Packets of other transactions are dropped, an error packet is turned into an Error.
+    /// Return None on timeout so the caller can retransmit.
+    async fn recv_response(&mut self, transaction_id: u32, action: u32, min_len: usize, n: u32) -> Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + self.base_timeout * 2u32.pow(n);
+        let mut data = vec![0u8; 2048];
+        loop {
+            let remain = deadline.saturating_duration_since(Instant::now());
+            let len = match timeout(remain, self.socket.recv(&mut data)).await {
+                Ok(len) => len?,
+                Err(_) => return Ok(None),
+            };
+            if len < 8 {
+                continue;
+            }
+
+            let mut header = [0u8; 4];
+            header.copy_from_slice(&data[4..8]);
+            if u32::from_be_bytes(header) != transaction_id {
+                continue;
+            }
+            header.copy_from_slice(&data[..4]);
+            match u32::from_be_bytes(header) {
+                ACTION_ERROR => {
+                    let message = String::from_utf8_lossy(&data[8..len]).to_string();
+                    return Err(Error::TrackerError(message));
+                }
+                received if received == action && len >= min_len => {
+                    data.truncate(len);
+                    return Ok(Some(data));
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn timed_out(&self) -> Error {
+        Error::TrackerError(format!("{} did not answer", self.addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::AnnounceEvent;
+
+    fn sample_params() -> AnnounceParams {
+        AnnounceParams {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: AnnounceEvent::Started,
+            num_want: -1,
+            key: 7,
+            tracker_id: None,
+        }
+    }
+
+    fn header(action: u32, transaction_id: &[u8]) -> Vec<u8> {
+        let mut pkt = action.to_be_bytes().to_vec();
+        pkt.extend_from_slice(transaction_id);
+        pkt
+    }
+
+    async fn client_for(server: &UdpSocket) -> UdpTracker {
+        let mut tracker = UdpTracker::new(server.local_addr().unwrap()).await.unwrap();
+        tracker.base_timeout = Duration::from_millis(50);
+        tracker
+    }
+
+    #[tokio::test]
+    async fn retransmit_and_ignore_other_transactions() {
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = client_for(&server).await;
+        let params = sample_params();
+
+        let stand_in = async {
+            let mut buf = [0u8; 256];
+            // Drop the first connect request, the client must send it again, same transaction.
+            server.recv_from(&mut buf).await.unwrap();
+            let first = buf[12..16].to_vec();
+            let (_, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[12..16], &first[..]);
+            let mut reply = header(ACTION_CONNECT, &[0xff; 4]);
+            reply.extend_from_slice(&42u64.to_be_bytes());
+            server.send_to(&reply, &from).await.unwrap();
+            let mut reply = header(ACTION_CONNECT, &buf[12..16]);
+            reply.extend_from_slice(&42u64.to_be_bytes());
+            server.send_to(&reply, &from).await.unwrap();
+
+            // Drop the first announce too: it comes again without a new connect, and the reply
+            // to the first one is still accepted.
+            server.recv_from(&mut buf).await.unwrap();
+            let first = buf[12..16].to_vec();
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 98);
+            assert_eq!(&buf[..8], &42u64.to_be_bytes());
+            assert_eq!(&buf[8..12], &ACTION_ANNOUNCE.to_be_bytes());
+            assert_eq!(&buf[12..16], &first[..]);
+            let mut reply = header(ACTION_ANNOUNCE, &first);
+            for val in &[1800u32, 3, 5] {
+                reply.extend_from_slice(&val.to_be_bytes());
+            }
+            reply.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+            server.send_to(&reply, &from).await.unwrap();
+        };
+
+        let (_, reply) = futures::join!(stand_in, tracker.announce(&params));
+        let reply = reply.unwrap();
+        assert_eq!(reply.interval, 1800);
+        assert_eq!((reply.leechers, reply.seeders), (3, 5));
+        assert_eq!(reply.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn error_action_is_reported() {
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = client_for(&server).await;
+
+        let stand_in = async {
+            let mut buf = [0u8; 256];
+            let (_, from) = server.recv_from(&mut buf).await.unwrap();
+            let mut reply = header(ACTION_ERROR, &buf[12..16]);
+            reply.extend_from_slice(b"unregistered torrent");
+            server.send_to(&reply, &from).await.unwrap();
+        };
+
+        let (_, result) = futures::join!(stand_in, tracker.connect());
+        match result {
+            Err(Error::TrackerError(message)) => assert_eq!(message, "unregistered torrent"),
+            _ => panic!("error packet should be an error"),
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use bincode::Options;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 pub fn random_string(len: usize) -> String {
     thread_rng()
@@ -7,3 +9,125 @@ pub fn random_string(len: usize) -> String {
         .take(len)
         .collect::<String>()
 }
+
+/// Lower case hex string of `data`.
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Bincode options for the binary wire formats: big endian and fixed size integers.
+pub fn big_endian() -> impl bincode::Options {
+    bincode::DefaultOptions::new()
+        .with_big_endian()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+/// Parse compact IPv4 peers: 4 bytes address followed by 2 bytes port, both big endian.
+pub fn parse_compact_v4(data: &[u8]) -> Vec<SocketAddr> {
+    data.chunks_exact(6)
+        .map(|chunk| {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            SocketAddr::new(ip.into(), port)
+        })
+        .collect()
+}
+
+/// Encode an IPv4 address in compact form, None for IPv6 addresses.
+pub fn compact_v4(addr: &SocketAddr) -> Option<[u8; 6]> {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let mut compact = [0u8; 6];
+            compact[..4].copy_from_slice(&addr.ip().octets());
+            compact[4..].copy_from_slice(&addr.port().to_be_bytes());
+            Some(compact)
+        }
+        SocketAddr::V6(_) => None,
+    }
+}
+
+/// Encode an IPv6 address in compact form, None for IPv4 addresses.
+pub fn compact_v6(addr: &SocketAddr) -> Option<[u8; 18]> {
+    match addr {
+        SocketAddr::V6(addr) => {
+            let mut compact = [0u8; 18];
+            compact[..16].copy_from_slice(&addr.ip().octets());
+            compact[16..].copy_from_slice(&addr.port().to_be_bytes());
+            Some(compact)
+        }
+        SocketAddr::V4(_) => None,
+    }
+}
+
+/// Parse compact IPv6 peers: 16 bytes address followed by 2 bytes port, both big endian.
+pub fn parse_compact_v6(data: &[u8]) -> Vec<SocketAddr> {
+    data.chunks_exact(18)
+        .map(|chunk| {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&chunk[..16]);
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            SocketAddr::new(Ipv6Addr::from(octets).into(), port)
+        })
+        .collect()
+}
+
+/// Directory under the system temp dir for a test, removed with everything in it when dropped.
+#[cfg(test)]
+pub struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new() -> Self {
+        Self(std::env::temp_dir().join(format!("o_torrent_{}", random_string(8))))
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TempDir {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Torrent of the files `(path, length)` cut in `piece_length` pieces, with the data the piece
+/// hashes are made of. One file is a single file torrent, several go in the directory "dir".
+#[cfg(test)]
+pub fn sample_torrent(files: &[(&str, usize)], piece_length: usize) -> (crate::meta_info::TorrentInfo, Vec<u8>) {
+    let total = files.iter().map(|(_, length)| length).sum::<usize>();
+    let data: Vec<u8> = (0..total).map(|i| (i % 251) as u8).collect();
+    let mut torrent = b"d4:infod".to_vec();
+    match files {
+        [(name, length)] => torrent.extend(format!("6:lengthi{}e4:name{}:{}", length, name.len(), name).bytes()),
+        _ => {
+            torrent.extend_from_slice(b"5:filesl");
+            for (path, length) in files {
+                torrent.extend(format!("d6:lengthi{}e4:pathl{}:{}ee", length, path.len(), path).bytes());
+            }
+            torrent.extend_from_slice(b"e4:name3:dir");
+        }
+    }
+    let pieces = data.chunks(piece_length).collect::<Vec<&[u8]>>();
+    torrent.extend(format!("12:piece lengthi{}e6:pieces{}:", piece_length, pieces.len() * 20).bytes());
+    for piece in pieces {
+        torrent.extend_from_slice(&sha1::Sha1::from(piece).digest().bytes());
+    }
+    torrent.extend_from_slice(b"ee");
+    (crate::meta_info::TorrentInfo::from_bytes(&torrent).unwrap(), data)
+}